@@ -0,0 +1,110 @@
+// 200個のvmファイルからなる合成プロジェクトを、スレッド数を変えて変換し
+// かかった時間を比べるベンチマーク
+//
+// cargo run --release --example parallel_bench
+
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use vmtranslator::{translate, Input, Output, TranslateOptions, Diagnostics};
+
+const FILES: usize = 200;
+const FUNCTIONS: usize = 20; // ファイルあたりの関数の数
+const ROUNDS: usize = 5;
+
+/// 算術、比較、分岐、呼び出しを含む合成vmファイルを作る
+fn synthetic_file(n: usize) -> Input {
+    let name = format!("File{}", n);
+    let mut vm = String::new();
+
+    for f in 0..FUNCTIONS {
+        vm += &format!("function {}.f{} 2\n", name, f);
+        vm += "label LOOP\n";
+        for i in 0..10 {
+            vm += &format!("push argument {}\n", i % 3);
+            vm += &format!("push constant {}\n", i);
+            vm += ["add", "sub", "eq", "gt", "lt"][i % 5];
+            vm += "\n";
+            vm += &format!("pop local {}\n", i % 2);
+        }
+        vm += "push local 0\n";
+        vm += "if-goto LOOP\n";
+        vm += &format!("call {}.f{} 1\n", name, (f + 1) % FUNCTIONS);
+        vm += "return\n";
+    }
+
+    Input::new(&name, &vm)
+}
+
+/// files個のvmファイルからなる合成プロジェクトを作る
+fn synthetic_project(files: usize) -> Vec<Input> {
+    (0..files).map(synthetic_file).collect()
+}
+
+/// 合成プロジェクトはROMに入らないので、ROMの大きさは調べない
+fn options(jobs: usize) -> TranslateOptions {
+    TranslateOptions::new().rom_limit(false).jobs(jobs)
+}
+
+/// １スレッドとjobs個のスレッドで変換し、結果が同じことを確かめる
+fn translate_both(inputs: &[Input], jobs: usize) -> Result<(Output, Output), Diagnostics> {
+    let serial = translate(inputs, &options(1))?;
+    let parallel = translate(inputs, &options(jobs))?;
+    Ok((serial, parallel))
+}
+
+/// ROUNDS回変換して最も速かった時間を返す
+fn measure(inputs: &[Input], jobs: usize) -> Result<Duration, Diagnostics> {
+    let mut best = Duration::MAX;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        translate(inputs, &options(jobs))?;
+        best = best.min(start.elapsed());
+    }
+    Ok(best)
+}
+
+fn run() -> Result<(), Diagnostics> {
+    let inputs = synthetic_project(FILES);
+    let cpus = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+
+    // 並列に変換しても結果は変わらない
+    let (serial, parallel) = translate_both(&inputs, cpus)?;
+    assert_eq!(serial, parallel);
+
+    let mut jobs_list = vec![1, 2, 4, cpus];
+    jobs_list.sort_unstable();
+    jobs_list.dedup();
+
+    println!("{} files, {} instructions, {} cpus", FILES,
+             serial.instruction_count(), cpus);
+    let base = measure(&inputs, 1)?;
+    for jobs in jobs_list.iter() {
+        let t = if *jobs == 1 { base } else { measure(&inputs, *jobs)? };
+        println!("-j {:<3} {:>8.2} ms  x{:.2}", jobs, t.as_secs_f64() * 1000.0,
+                 base.as_secs_f64() / t.as_secs_f64());
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_synthetic_project() {
+        let inputs = synthetic_project(3);
+        let (serial, parallel) = translate_both(&inputs, 4).unwrap();
+        assert_eq!(serial, parallel);
+        assert!(measure(&inputs, 2).is_ok());
+    }
+}
@@ -0,0 +1,169 @@
+//! ブートストラップコードの設定
+//!
+//! ブートストラップコードが書き込むメモリは次の通り
+//! * RAM[0] (SP) スタックのベースアドレス。デフォルトは256
+//! * RAM[1] (LCL), RAM[2] (ARG), RAM[3] (THIS), RAM[4] (THAT)
+//!   値が設定されている場合のみ書き込む
+//! * エントリー関数を呼ぶ場合は、callコマンドと同じようにスタックの先頭から
+//!   5ワード（return address, LCL, ARG, THIS, THAT）を書き込み、
+//!   ARGとLCLを設定し直す
+//!
+//! 設定ファイルは１行にひとつ`key = value`の形で書く。`#`から行末まではコメント
+//! ```text
+//! sp = 256
+//! lcl = 300
+//! entry = Main.main
+//! halt = true
+//! ```
+//! `entry`の値を空にするとエントリー関数を呼ばない
+
+use std::fs;
+use crate::identifier;
+
+/// Hackの`@value`命令で扱える値の最大値
+const MAX_VALUE: usize = 32767;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bootstrap {
+    /// スタックのベースアドレス
+    pub sp: u16,
+    pub lcl: Option<u16>,
+    pub arg: Option<u16>,
+    pub this: Option<u16>,
+    pub that: Option<u16>,
+    /// 最初に呼ぶ関数。`None`の場合は呼ばない
+    pub entry: Option<String>,
+    /// `true`の場合はエントリー関数から戻った後に無限ループで停止する
+    pub halt: bool,
+}
+
+/// アドレスとして使う値を読む
+fn parse_address(key: &str, value: &str) -> Result<u16, String> {
+    match value.parse::<usize>() {
+        Ok(n) if n <= MAX_VALUE => Ok(n as u16),
+        _ => Err(format!("{} には0から{}までの数値が必要です", key, MAX_VALUE))
+    }
+}
+
+impl Bootstrap {
+    /// デフォルトの設定。SPを256にしてSys.initを呼ぶ
+    pub fn new() -> Bootstrap {
+        Bootstrap {
+            sp: 256,
+            lcl: None,
+            arg: None,
+            this: None,
+            that: None,
+            entry: Some("Sys.init".to_string()),
+            halt: false,
+        }
+    }
+
+    /// keyの設定をvalueにする
+    pub fn apply(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "sp" => self.sp = parse_address(key, value)?,
+            "lcl" => self.lcl = Some(parse_address(key, value)?),
+            "arg" => self.arg = Some(parse_address(key, value)?),
+            "this" => self.this = Some(parse_address(key, value)?),
+            "that" => self.that = Some(parse_address(key, value)?),
+            "entry" => {
+                self.entry = if value.is_empty() {
+                    None
+                } else {
+                    identifier::validate(value)
+                        .map_err(|e| format!("{}: {}", key, e))?;
+                    Some(value.to_string())
+                }
+            },
+            "halt" => {
+                self.halt = match value {
+                    "true" => true,
+                    "false" => false,
+                    _ => return Err(format!("{} にはtrueかfalseが必要です", key))
+                }
+            },
+            _ => return Err(format!("{} は無効な設定です", key)),
+        }
+        Ok(())
+    }
+
+    /// 設定ファイルの内容を読んで設定に反映する
+    pub fn apply_config(&mut self, config: &str) -> Result<(), String> {
+        for (i, line) in config.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(n) => &line[..n],
+                None => line,
+            }.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (key, value) = match line.find('=') {
+                Some(n) => (line[..n].trim(), line[n + 1..].trim()),
+                None => return Err(format!("{}行目: key = valueの形ではありません",
+                                           i + 1))
+            };
+            if let Err(e) = self.apply(key, value) {
+                return Err(format!("{}行目: {}", i + 1, e));
+            }
+        }
+        Ok(())
+    }
+
+    /// 設定ファイルを読み込んで設定に反映する
+    pub fn apply_file(&mut self, path: &str) -> Result<(), String> {
+        let config = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(_) => return Err(format!("{}を開けません", path))
+        };
+
+        self.apply_config(&config).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+impl Default for Bootstrap {
+    fn default() -> Bootstrap {
+        Bootstrap::new()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::Bootstrap;
+
+    #[test]
+    fn test_bootstrap_apply_config() {
+        let mut b = Bootstrap::new();
+        b.apply_config(concat!(
+            "# test\n",
+            "sp = 300\n",
+            "\n",
+            "that=3000 # comment\n",
+            "entry = Main.main\n",
+            "halt = true\n",
+        )).unwrap();
+
+        assert_eq!(b.sp, 300);
+        assert_eq!(b.lcl, None);
+        assert_eq!(b.that, Some(3000));
+        assert_eq!(b.entry, Some("Main.main".to_string()));
+        assert!(b.halt);
+
+        b.apply("entry", "").unwrap();
+        assert_eq!(b.entry, None);
+    }
+
+    #[test]
+    fn test_bootstrap_apply_config_error() {
+        let mut b = Bootstrap::new();
+        assert!(b.apply_config("sp = 40000\n").is_err());
+        assert!(b.apply_config("\nfoo = 1\n").unwrap_err().starts_with("2行目"));
+        assert!(b.apply_config("sp\n").is_err());
+        assert!(b.apply_config("halt = yes\n").is_err());
+        assert!(b.apply_config("entry = foo bar\n").unwrap_err().starts_with("1行目: entry"));
+        assert!(b.apply("entry", "1main").is_err());
+        assert_eq!(b.entry, Some("Sys.init".to_string()));
+    }
+}
@@ -0,0 +1,365 @@
+//! VMプログラムのバイナリ形式（バイトコード）。
+//! テキストの.vmファイルより小さく、読み込むときに字句解析がいらない。
+//! 読み込んだプログラムは`VmFile::to_input`でテキストに戻し、
+//! .vmファイルと同じ`CodeWriter`で変換する
+//!
+//! 数値はすべてLEB128の可変長整数（varint）で書く。
+//!
+//! ```text
+//! "VMBC" version:u8
+//! 文字列の数:varint { 長さ:varint UTF-8のバイト列 }   関数名、ラベル、ファイル名
+//! ファイルの数:varint {
+//!     名前:文字列番号 コマンドの数:varint {
+//!         前のコマンドからの行数:varint opcode:u8 引数
+//!     }
+//! }
+//! ```
+//!
+//! | opcode | コマンド | 引数 |
+//! | --- | --- | --- |
+//! | 0x00〜0x08 | add、sub、neg、eq、gt、lt、and、or、not | なし |
+//! | 0x10 | push | セグメント:u8 index:varint |
+//! | 0x11 | pop | セグメント:u8 index:varint |
+//! | 0x20、0x21、0x22 | label、goto、if-goto | ラベル:文字列番号 |
+//! | 0x30 | function | 関数名:文字列番号 localの数:varint |
+//! | 0x31 | call | 関数名:文字列番号 引数の数:varint |
+//! | 0x32 | return | なし |
+//! | 0x40〜0x48 | 拡張命令のmul、div、shl、shr、dup、swap、ge、le、ne | なし |
+//!
+//! セグメントは0からconstant、local、argument、this、that、temp、pointer、static、
+//! 拡張命令のindirect
+
+use std::collections::HashMap;
+
+use crate::program::{Program, VmFile, Statement, Command, Segment, Op};
+
+const MAGIC: &[u8] = b"VMBC";
+const VERSION: u8 = 1;
+
+/// すべてのファイルの行数の合計の上限。`VmFile::to_input`は行番号の行まで
+/// 空行を入れるので、壊れたバイトコードで巨大な文字列を作らないようにする
+const MAX_LINES: usize = 10_000_000;
+
+const OPS: [Op; 9] = [Op::Add, Op::Sub, Op::Neg, Op::Eq, Op::Gt, Op::Lt,
+                      Op::And, Op::Or, Op::Not];
+const EXTENSION_OPS: [Op; 9] = [Op::Mul, Op::Div, Op::Shl, Op::Shr, Op::Dup, Op::Swap,
+                                Op::Ge, Op::Le, Op::Ne];
+const SEGMENTS: [Segment; 9] = [Segment::Constant, Segment::Local, Segment::Argument,
+                                Segment::This, Segment::That, Segment::Temp,
+                                Segment::Pointer, Segment::Static, Segment::Indirect];
+
+const PUSH: u8 = 0x10;
+const POP: u8 = 0x11;
+const LABEL: u8 = 0x20;
+const GOTO: u8 = 0x21;
+const IF_GOTO: u8 = 0x22;
+const FUNCTION: u8 = 0x30;
+const CALL: u8 = 0x31;
+const RETURN: u8 = 0x32;
+const EXTENSION: u8 = 0x40;
+
+/// バイトコードを書き込む
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn varint(&mut self, mut n: usize) {
+        while n >= 0x80 {
+            self.bytes.push((n & 0x7f) as u8 | 0x80);
+            n >>= 7;
+        }
+        self.bytes.push(n as u8);
+    }
+}
+
+/// 文字列表。最初に現れた順に番号を付ける
+#[derive(Default)]
+struct Strings<'a> {
+    list: Vec<&'a str>,
+    index: HashMap<&'a str, usize>,
+}
+
+impl<'a> Strings<'a> {
+    fn add(&mut self, s: &'a str) {
+        if !self.index.contains_key(s) {
+            self.index.insert(s, self.list.len());
+            self.list.push(s);
+        }
+    }
+}
+
+/// コマンドが使う文字列
+fn string_of(command: &Command) -> Option<&str> {
+    match command {
+        Command::Label(s) | Command::Goto(s) | Command::IfGoto(s)
+        | Command::Function(s, _) | Command::Call(s, _) => Some(s),
+        _ => None,
+    }
+}
+
+/// プログラムをバイトコードにする
+pub fn encode(program: &Program) -> Vec<u8> {
+    let mut strings = Strings::default();
+    for file in &program.files {
+        strings.add(&file.name);
+        for s in &file.statements {
+            if let Some(name) = string_of(&s.command) {
+                strings.add(name);
+            }
+        }
+    }
+
+    let mut w = Writer { bytes: MAGIC.to_vec() };
+    w.bytes.push(VERSION);
+    w.varint(strings.list.len());
+    for s in &strings.list {
+        w.varint(s.len());
+        w.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    w.varint(program.files.len());
+    for file in &program.files {
+        w.varint(strings.index[file.name.as_str()]);
+        w.varint(file.statements.len());
+        let mut line = 0;
+        for s in &file.statements {
+            w.varint(s.line.saturating_sub(line));
+            line = s.line;
+            let segment = |s: &Segment| SEGMENTS.iter().position(|x| x == s).unwrap() as u8;
+            match &s.command {
+                Command::Arithmetic(op) => {
+                    let opcode = match OPS.iter().position(|x| x == op) {
+                        Some(i) => i as u8,
+                        None => EXTENSION + EXTENSION_OPS.iter().position(|x| x == op).unwrap() as u8,
+                    };
+                    w.bytes.push(opcode);
+                },
+                Command::Push(seg, index) | Command::Pop(seg, index) => {
+                    let opcode = if matches!(s.command, Command::Push(_, _)) { PUSH } else { POP };
+                    w.bytes.push(opcode);
+                    w.bytes.push(segment(seg));
+                    w.varint(*index as usize);
+                },
+                Command::Label(l) | Command::Goto(l) | Command::IfGoto(l) => {
+                    w.bytes.push(match s.command {
+                        Command::Label(_) => LABEL,
+                        Command::Goto(_) => GOTO,
+                        _ => IF_GOTO,
+                    });
+                    w.varint(strings.index[l.as_str()]);
+                },
+                Command::Function(f, n) | Command::Call(f, n) => {
+                    let opcode = if matches!(s.command, Command::Function(_, _)) {
+                        FUNCTION
+                    } else {
+                        CALL
+                    };
+                    w.bytes.push(opcode);
+                    w.varint(strings.index[f.as_str()]);
+                    w.varint(*n as usize);
+                },
+                Command::Return => w.bytes.push(RETURN),
+            }
+        }
+    }
+    w.bytes
+}
+
+/// バイトコードを読む
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn error<T>(&self, message: &str) -> Result<T, String> {
+        Err(format!("バイトコードの{}バイト目: {}", self.pos, message))
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        match self.bytes.get(self.pos) {
+            Some(b) => {
+                self.pos += 1;
+                Ok(*b)
+            },
+            None => self.error("途中で終わっています"),
+        }
+    }
+
+    fn varint(&mut self) -> Result<usize, String> {
+        let mut n: usize = 0;
+        for shift in (0..usize::BITS).step_by(7) {
+            let b = self.byte()?;
+            n |= ((b & 0x7f) as usize) << shift;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        self.error("数値が大きすぎます")
+    }
+
+    /// u16に収まる数値
+    fn number(&mut self) -> Result<u16, String> {
+        let n = self.varint()?;
+        if n <= 32767 {
+            Ok(n as u16)
+        } else {
+            self.error(&format!("{} は大きすぎます", n))
+        }
+    }
+
+    fn string<'s>(&mut self, strings: &'s [String]) -> Result<&'s String, String> {
+        let i = self.varint()?;
+        match strings.get(i) {
+            Some(s) => Ok(s),
+            None => self.error(&format!("文字列{}はありません", i)),
+        }
+    }
+}
+
+/// バイトコードをプログラムにする
+pub fn decode(bytes: &[u8]) -> Result<Program, String> {
+    if !bytes.starts_with(MAGIC) {
+        return Err("バイトコードではありません".to_string());
+    }
+    let mut r = Reader { bytes, pos: MAGIC.len() };
+    let version = r.byte()?;
+    if version != VERSION {
+        return r.error(&format!("バージョン{}には対応していません", version));
+    }
+
+    let count = r.varint()?;
+    let mut strings = Vec::new();
+    for _ in 0..count {
+        let len = r.varint()?;
+        let end = r.pos.saturating_add(len);
+        let s = match bytes.get(r.pos..end).map(std::str::from_utf8) {
+            Some(Ok(s)) => s.to_string(),
+            Some(Err(_)) => return r.error("文字列がUTF-8ではありません"),
+            None => return r.error("途中で終わっています"),
+        };
+        r.pos = end;
+        strings.push(s);
+    }
+
+    let count = r.varint()?;
+    let mut files = Vec::new();
+    // 前のファイルまでの行数の合計
+    let mut lines: usize = 0;
+    for _ in 0..count {
+        let name = r.string(&strings)?.clone();
+        let count = r.varint()?;
+        let mut statements = Vec::new();
+        let mut line: usize = 0;
+        for _ in 0..count {
+            line = line.saturating_add(r.varint()?);
+            if lines.saturating_add(line) > MAX_LINES {
+                return r.error(&format!("行番号の合計が{}を超えています", MAX_LINES));
+            }
+            let opcode = r.byte()?;
+            let command = match opcode {
+                0x00..=0x08 => Command::Arithmetic(OPS[opcode as usize]),
+                0x40..=0x48 => Command::Arithmetic(EXTENSION_OPS[(opcode - EXTENSION) as usize]),
+                PUSH | POP => {
+                    let segment = match SEGMENTS.get(r.byte()? as usize) {
+                        Some(s) => *s,
+                        None => return r.error("無効なセグメントです"),
+                    };
+                    let index = r.number()?;
+                    if opcode == PUSH {
+                        Command::Push(segment, index)
+                    } else {
+                        Command::Pop(segment, index)
+                    }
+                },
+                LABEL => Command::Label(r.string(&strings)?.clone()),
+                GOTO => Command::Goto(r.string(&strings)?.clone()),
+                IF_GOTO => Command::IfGoto(r.string(&strings)?.clone()),
+                FUNCTION => Command::Function(r.string(&strings)?.clone(), r.number()?),
+                CALL => Command::Call(r.string(&strings)?.clone(), r.number()?),
+                RETURN => Command::Return,
+                _ => return r.error(&format!("無効なopcode 0x{:02x} です", opcode)),
+            };
+            statements.push(Statement { line, command });
+        }
+        lines += line;
+        files.push(VmFile { name, statements });
+    }
+
+    if r.pos != bytes.len() {
+        return r.error("余分なデータがあります");
+    }
+    Ok(Program { files })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{translate, Input, TranslateOptions};
+
+    const SOURCE: &str = concat!(
+        "// comment\n",
+        "function Main.main 2\n",
+        "  push constant 300   // 2 bytes\n",
+        "pop local 1\n",
+        "\n",
+        "label LOOP\n",
+        "push static 4\n",
+        "eq\n",
+        "not\n",
+        "if-goto LOOP\n",
+        "goto END\n",
+        "label END\n",
+        "call Main.main 0\n",
+        "return\n",
+    );
+
+    #[test]
+    fn test_bytecode_round_trip() {
+        let inputs = [Input::new("Main", SOURCE), Input::new("Sys", "function Sys.init 0\n")];
+        let program = Program::parse(&inputs).unwrap();
+        let bytes = encode(&program);
+        assert!(bytes.len() < SOURCE.len());
+        assert_eq!(decode(&bytes).unwrap(), program);
+
+        // テキストに戻したものは元のファイルと同じアセンブリコードに変換される
+        let decoded: Vec<Input> = decode(&bytes).unwrap().files.iter()
+            .map(VmFile::to_input).collect();
+        let options = TranslateOptions::new();
+        assert_eq!(translate(&decoded, &options).unwrap(), translate(&inputs, &options).unwrap());
+    }
+
+    #[test]
+    fn test_bytecode_errors() {
+        let program = Program::parse(&[Input::new("Main", SOURCE)]).unwrap();
+        let bytes = encode(&program);
+
+        assert!(decode(b"push constant 1").is_err());
+        assert!(decode(&bytes[..bytes.len() - 1]).unwrap_err().contains("途中で終わっています"));
+
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert!(decode(&extra).unwrap_err().contains("余分なデータ"));
+
+        let mut version = bytes.clone();
+        version[4] = 2;
+        assert!(decode(&version).unwrap_err().contains("バージョン2"));
+
+        // 1行目から2^60行先のreturn
+        let mut far = b"VMBC\x01\x01\x04Main\x01\x00\x01".to_vec();
+        far.extend_from_slice(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x10]);
+        far.push(RETURN);
+        assert!(decode(&far).unwrap_err().contains("行番号の合計"));
+    }
+
+    #[test]
+    fn test_bytecode_extensions() {
+        let input = Input::new("Main", "push constant 6\ndup\nmul\npush indirect 3\nne\n");
+        let file = VmFile::parse_with(&input, crate::IdentifierPolicy::Strict,
+                                      crate::Dialect::Extended).unwrap();
+        let program = Program { files: vec![file] };
+        assert_eq!(decode(&encode(&program)).unwrap(), program);
+    }
+}
@@ -0,0 +1,178 @@
+//! ファイルごとの変換結果のキャッシュ。
+//! キーはファイル名、ファイルの内容、変換の設定から計算したハッシュ値。
+//! ディレクトリを指定した場合はそのディレクトリにも保存するので、
+//! プロセスをまたいで使うことができる。
+//! ディレクトリにはアセンブリコードを`{key}.asm`に、各VMコマンドの情報を
+//! `{key}.map`に１行にひとつ`line<TAB>size<TAB>command`の形で保存する
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::{Input, TranslateOptions};
+use crate::unit::{Fragment, Span};
+
+/// FNV-1aでハッシュ値を計算する。
+/// `std::collections::hash_map::DefaultHasher`はRustのバージョンによって
+/// 値が変わる可能性があるので、ディスクに保存するキーには使わない
+pub fn hash(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in data {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h
+}
+
+/// 変換結果のキャッシュ
+pub struct Cache {
+    dir: Option<PathBuf>,
+    memory: HashMap<u64, Fragment>,
+    hits: usize,
+}
+
+impl Cache {
+    /// メモリ上だけに保存するキャッシュを作る
+    pub fn new() -> Cache {
+        Cache {
+            dir: None,
+            memory: HashMap::new(),
+            hits: 0,
+        }
+    }
+
+    /// dirにも保存するキャッシュを作る。dirがない場合は作る
+    pub fn with_dir(dir: &str) -> Result<Cache, String> {
+        if fs::create_dir_all(dir).is_err() {
+            return Err(format!("can't create '{}'.", dir));
+        }
+
+        let mut cache = Cache::new();
+        cache.dir = Some(PathBuf::from(dir));
+        Ok(cache)
+    }
+
+    /// inputをoptionsで変換した結果に対応するキーを返す。
+    /// baseは最初の命令のアドレス（`unit::translate_unit_at`を参照）
+    pub fn key(input: &Input, options: &TranslateOptions, base: usize) -> u64 {
+        let mut data = Vec::new();
+        data.extend(options.fingerprint().as_bytes());
+        data.extend(format!(" {}", base).as_bytes());
+        data.push(0);
+        data.extend(input.name.as_bytes());
+        data.push(0);
+        data.extend(input.source.as_bytes());
+        hash(&data)
+    }
+
+    fn path(&self, key: u64, extension: &str) -> Option<PathBuf> {
+        self.dir.as_ref().map(|d| d.join(format!("{:016x}.{}", key, extension)))
+    }
+
+    /// ディレクトリから変換結果を読み込む
+    fn load(&self, key: u64, name: &str) -> Option<Fragment> {
+        let asm = fs::read_to_string(self.path(key, "asm")?).ok()?;
+        let map = fs::read_to_string(self.path(key, "map")?).ok()?;
+
+        let mut spans = Vec::new();
+        for line in map.lines() {
+            let mut fields = line.splitn(3, '\t');
+            spans.push(Span {
+                line: fields.next()?.parse().ok()?,
+                size: fields.next()?.parse().ok()?,
+                command: fields.next()?.to_string(),
+            });
+        }
+
+        Some(Fragment { name: name.to_string(), asm, spans })
+    }
+
+    /// キャッシュから変換結果を取り出す
+    pub fn get(&mut self, key: u64, name: &str) -> Option<Fragment> {
+        let fragment = match self.memory.get(&key) {
+            Some(f) => Some(f.clone()),
+            None => self.load(key, name),
+        };
+
+        if let Some(f) = &fragment {
+            self.hits += 1;
+            self.memory.insert(key, f.clone());
+        }
+        fragment
+    }
+
+    /// 変換結果をキャッシュに保存する。ディスクへの書き込みに失敗しても
+    /// エラーにはしない
+    pub fn insert(&mut self, key: u64, fragment: &Fragment) {
+        if let (Some(asm), Some(map)) = (self.path(key, "asm"),
+                                         self.path(key, "map")) {
+            let spans: String = fragment.spans.iter()
+                .map(|s| format!("{}\t{}\t{}\n", s.line, s.size, s.command))
+                .collect();
+            // .asmが先にできても.mapがなければ読み込まないので、.mapを後に書く
+            let _ = fs::write(asm, &fragment.asm)
+                        .and_then(|_| fs::write(map, spans));
+        }
+        self.memory.insert(key, fragment.clone());
+    }
+
+    /// キャッシュから取り出せた回数を返す
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Cache {
+        Cache::new()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_hash() {
+        assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let options = TranslateOptions::new();
+        let a = Input::new("A", "add\n");
+        assert_eq!(Cache::key(&a, &options, 0), Cache::key(&a.clone(), &options, 0));
+        assert_ne!(Cache::key(&a, &options, 0),
+                   Cache::key(&Input::new("B", "add\n"), &options, 0));
+        assert_ne!(Cache::key(&a, &options, 0),
+                   Cache::key(&a, &options.clone().bootstrap(false)
+                                        .comment_style(crate::CommentStyle::None), 0));
+        assert_ne!(Cache::key(&a, &options, 0), Cache::key(&a, &options, 1));
+    }
+
+    #[test]
+    fn test_cache_dir() {
+        let dir = env::temp_dir().join(format!("vmtranslator-cache-{}",
+                                               std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let fragment = Fragment {
+            name: "A".to_string(),
+            asm: "@SP \n".to_string(),
+            spans: vec![Span { line: 3, size: 1, command: "push constant 1".to_string() }],
+        };
+
+        let mut cache = Cache::with_dir(dir).unwrap();
+        assert_eq!(cache.get(1, "A"), None);
+        cache.insert(1, &fragment);
+
+        // 別のCacheからでもディスクから読める
+        let mut cache = Cache::with_dir(dir).unwrap();
+        assert_eq!(cache.get(1, "A"), Some(fragment));
+        assert_eq!(cache.hits(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
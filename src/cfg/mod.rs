@@ -0,0 +1,301 @@
+//! 制御フローグラフ（CFG）。
+//! 関数ごとにコマンドを基本ブロックに分け、ブロックの間の辺を作る。
+//! ブロックはlabelコマンドか、goto、if-goto、call、returnコマンドの次から
+//! 始まる。callコマンドは呼び出す関数を記録し、呼び出しから戻った後の
+//! ブロックへの辺を作る。
+//! Graphvizで表示するためのDOT形式と、JSONで書き出せる
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::json::Json;
+use crate::program::{Program, Function, Statement, Command};
+
+/// 辺の種類
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+    /// 次のブロックへ進む
+    Fallthrough,
+    /// goto
+    Jump,
+    /// if-gotoの条件がtrueのとき
+    Branch,
+    /// return
+    Return,
+}
+
+impl EdgeKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EdgeKind::Fallthrough => "fallthrough",
+            EdgeKind::Jump => "jump",
+            EdgeKind::Branch => "branch",
+            EdgeKind::Return => "return",
+        }
+    }
+}
+
+/// 辺の行き先
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Block(usize),
+    /// 関数の出口
+    Exit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edge {
+    pub to: Target,
+    pub kind: EdgeKind,
+}
+
+/// 基本ブロック
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block<'a> {
+    pub statements: &'a [Statement],
+    pub successors: Vec<Edge>,
+    /// ブロック内のcallコマンドが呼び出す関数
+    pub calls: Vec<&'a str>,
+}
+
+/// ひとつの関数のグラフ。最初のブロックが入口
+#[derive(Debug, Clone, PartialEq)]
+pub struct Graph<'a> {
+    /// 関数名。最初のfunctionコマンドより前の部分はファイル名
+    pub name: String,
+    pub blocks: Vec<Block<'a>>,
+}
+
+impl<'a> Graph<'a> {
+    pub fn build(name: &str, function: &Function<'a>) -> Graph<'a> {
+        let body = function.body;
+
+        // ブロックの先頭のコマンドの番号
+        let mut leaders = vec![0];
+        for (i, s) in body.iter().enumerate() {
+            match &s.command {
+                Command::Label(_) => leaders.push(i),
+                Command::Goto(_) | Command::IfGoto(_) | Command::Call(_, _)
+                | Command::Return => leaders.push(i + 1),
+                _ => {},
+            }
+        }
+        leaders.retain(|i| *i < body.len());
+        leaders.dedup();
+
+        let block_of: HashMap<usize, usize> = leaders.iter().enumerate()
+            .map(|(b, i)| (*i, b)).collect();
+        let labels: HashMap<&str, usize> = body.iter().enumerate()
+            .filter_map(|(i, s)| match &s.command {
+                Command::Label(l) => Some((l.as_str(), block_of[&i])),
+                _ => None,
+            })
+            .collect();
+
+        let mut blocks = Vec::new();
+        for (b, start) in leaders.iter().enumerate() {
+            let end = leaders.get(b + 1).copied().unwrap_or(body.len());
+            let statements = &body[*start..end];
+            let next = if end < body.len() { Target::Block(b + 1) } else { Target::Exit };
+            let jump = |l: &str| labels.get(l).map_or(Target::Exit, |b| Target::Block(*b));
+
+            let successors = match statements.last().map(|s| &s.command) {
+                Some(Command::Goto(l)) => vec![Edge { to: jump(l), kind: EdgeKind::Jump }],
+                Some(Command::IfGoto(l)) => vec![
+                    Edge { to: jump(l), kind: EdgeKind::Branch },
+                    Edge { to: next, kind: EdgeKind::Fallthrough },
+                ],
+                Some(Command::Return) => vec![Edge { to: Target::Exit, kind: EdgeKind::Return }],
+                _ => vec![Edge { to: next, kind: EdgeKind::Fallthrough }],
+            };
+            let calls = statements.iter().filter_map(|s| match &s.command {
+                Command::Call(f, _) => Some(f.as_str()),
+                _ => None,
+            }).collect();
+
+            blocks.push(Block { statements, successors, calls });
+        }
+
+        Graph { name: name.to_string(), blocks }
+    }
+
+    /// 関数の入口。コマンドがない関数はブロックがないので出口になる
+    pub fn entry(&self) -> Target {
+        if self.blocks.is_empty() { Target::Exit } else { Target::Block(0) }
+    }
+}
+
+/// プログラム全体のグラフ
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfg<'a> {
+    pub graphs: Vec<Graph<'a>>,
+}
+
+/// DOTの文字列リテラルにする
+fn dot_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn node(graph: &str, target: Target) -> String {
+    match target {
+        Target::Block(b) => dot_string(&format!("{}:{}", graph, b)),
+        Target::Exit => dot_string(&format!("{}:exit", graph)),
+    }
+}
+
+impl<'a> Cfg<'a> {
+    pub fn build(program: &'a Program) -> Cfg<'a> {
+        let mut graphs = Vec::new();
+        for file in &program.files {
+            for function in file.functions() {
+                let name = function.name.unwrap_or(&file.name);
+                graphs.push(Graph::build(name, &function));
+            }
+        }
+        Cfg { graphs }
+    }
+
+    /// Graphvizで表示するためのDOT形式にする。関数ごとにsubgraphを作り、
+    /// 呼び出しは呼び出す関数の入口への点線で書く
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=monospace];\n");
+        let mut calls = String::new();
+        for (i, graph) in self.graphs.iter().enumerate() {
+            let _ = writeln!(dot, "    subgraph cluster_{} {{", i);
+            let _ = writeln!(dot, "        label={};", dot_string(&graph.name));
+            for (b, block) in graph.blocks.iter().enumerate() {
+                let text: String = block.statements.iter()
+                    .map(|s| format!("{}: {}\\l", s.line, s.command)).collect();
+                let _ = writeln!(dot, "        {} [label=\"{}\"];", node(&graph.name, Target::Block(b)),
+                                 text.replace('"', "\\\""));
+                for edge in &block.successors {
+                    let style = match edge.kind {
+                        EdgeKind::Branch => " [label=\"true\"]",
+                        EdgeKind::Fallthrough if block.successors.len() > 1 => " [label=\"false\"]",
+                        _ => "",
+                    };
+                    let _ = writeln!(dot, "        {} -> {}{};", node(&graph.name, Target::Block(b)),
+                                     node(&graph.name, edge.to), style);
+                }
+                for f in &block.calls {
+                    if let Some(callee) = self.graphs.iter().find(|g| g.name == *f) {
+                        let _ = writeln!(calls, "    {} -> {} [style=dashed];",
+                                         node(&graph.name, Target::Block(b)),
+                                         node(f, callee.entry()));
+                    }
+                }
+            }
+            let _ = writeln!(dot, "        {} [label=\"exit\", shape=ellipse];",
+                             node(&graph.name, Target::Exit));
+            dot += "    }\n";
+        }
+        dot += &calls;
+        dot += "}\n";
+        dot
+    }
+
+    pub fn to_json(&self) -> Json {
+        let graphs = self.graphs.iter().map(|graph| {
+            let blocks = graph.blocks.iter().enumerate().map(|(b, block)| {
+                let successors = block.successors.iter().map(|e| Json::object(vec![
+                    ("to", match e.to {
+                        Target::Block(b) => Json::Number(b as i64),
+                        Target::Exit => Json::string("exit"),
+                    }),
+                    ("kind", Json::string(e.kind.name())),
+                ])).collect();
+                let commands = block.statements.iter().map(|s| Json::object(vec![
+                    ("line", Json::Number(s.line as i64)),
+                    ("command", Json::string(&s.command.to_string())),
+                ])).collect();
+                Json::object(vec![
+                    ("id", Json::Number(b as i64)),
+                    ("commands", Json::Array(commands)),
+                    ("successors", Json::Array(successors)),
+                    ("calls", Json::Array(block.calls.iter().map(|f| Json::string(f)).collect())),
+                ])
+            }).collect();
+            Json::object(vec![
+                ("name", Json::string(&graph.name)),
+                ("blocks", Json::Array(blocks)),
+            ])
+        }).collect();
+
+        Json::object(vec![("functions", Json::Array(graphs))])
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Input;
+
+    const SOURCE: &str = concat!(
+        "function Main.main 0\n",
+        "label LOOP\n",
+        "push constant 1\n",
+        "if-goto END\n",
+        "call Main.f 0\n",
+        "goto LOOP\n",
+        "label END\n",
+        "push constant 0\n",
+        "return\n",
+        "function Main.f 0\n",
+        "push constant 0\n",
+        "return\n",
+    );
+
+    #[test]
+    fn test_cfg_build() {
+        let program = Program::parse(&[Input::new("Main", SOURCE)]).unwrap();
+        let cfg = Cfg::build(&program);
+        assert_eq!(cfg.graphs.len(), 2);
+
+        let main = &cfg.graphs[0];
+        assert_eq!(main.name, "Main.main");
+        let lines: Vec<Vec<usize>> = main.blocks.iter()
+            .map(|b| b.statements.iter().map(|s| s.line).collect()).collect();
+        assert_eq!(lines, vec![vec![2, 3, 4], vec![5], vec![6], vec![7, 8, 9]]);
+        assert_eq!(main.blocks[0].successors, vec![
+            Edge { to: Target::Block(3), kind: EdgeKind::Branch },
+            Edge { to: Target::Block(1), kind: EdgeKind::Fallthrough },
+        ]);
+        assert_eq!(main.blocks[1].calls, vec!["Main.f"]);
+        assert_eq!(main.blocks[2].successors,
+                   vec![Edge { to: Target::Block(0), kind: EdgeKind::Jump }]);
+        assert_eq!(main.blocks[3].successors,
+                   vec![Edge { to: Target::Exit, kind: EdgeKind::Return }]);
+    }
+
+    #[test]
+    fn test_cfg_export() {
+        let program = Program::parse(&[Input::new("Main", SOURCE)]).unwrap();
+        let cfg = Cfg::build(&program);
+
+        let dot = cfg.to_dot();
+        assert!(dot.starts_with("digraph cfg {"));
+        assert!(dot.contains("\"Main.main:0\" -> \"Main.main:3\" [label=\"true\"];"));
+        assert!(dot.contains("\"Main.main:1\" -> \"Main.f:0\" [style=dashed];"));
+        assert!(dot.contains("\"Main.main:3\" -> \"Main.main:exit\";"));
+
+        let json = cfg.to_json().to_string();
+        assert!(json.starts_with(r#"{"functions":[{"name":"Main.main","blocks":[{"id":0,"#));
+        assert!(json.contains(r#""successors":[{"to":"exit","kind":"return"}]"#));
+    }
+
+    #[test]
+    fn test_cfg_export_empty_function() {
+        let source = "function Main.main 0\ncall Main.empty 0\nreturn\nfunction Main.empty 0\n";
+        let program = Program::parse(&[Input::new("Main", source)]).unwrap();
+        let cfg = Cfg::build(&program);
+        assert!(cfg.graphs[1].blocks.is_empty());
+        assert_eq!(cfg.graphs[1].entry(), Target::Exit);
+
+        // 呼び出しの点線は存在するノードを指す
+        let dot = cfg.to_dot();
+        assert!(dot.contains("\"Main.main:0\" -> \"Main.empty:exit\" [style=dashed];"));
+        assert!(dot.contains("\"Main.empty:exit\" [label=\"exit\", shape=ellipse];"));
+        assert!(!dot.contains("Main.empty:0"));
+    }
+}
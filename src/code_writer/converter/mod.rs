@@ -64,24 +64,25 @@ macro_rules! binfunc {
 /// 内部でformatマクロを使っているため、concatマクロの中では使えない。
 /// * 第一引数はスタックポインタ名
 /// * 第二引数は条件
-/// * 第三引数のrowsは行数
+/// * 第三引数は条件がtrueのときのラベル
+/// * 第四引数は条件がfalseのときのラベル
 macro_rules! ifd {
-    ($var:expr, $jump:expr, $label:expr) => {
+    ($var:expr, $jump:expr, $t_label:expr, $f_label:expr) => {
         format!(concat!(
-            "@{t_label}-true \n",   // 条件がtrueのときのジャンプ先を指定
-            "D;", $jump, " \n",     // 条件がtrueならt_labelにジャンプする
-            "@0 \n",                // 条件がfalseの場合
-            "D=A \n",               // Dレジスタに0を入れる
-            "@{f_label}-false \n",  // f_labelをジャンプ先として指定
-            "0;JMP \n",             // 無条件でf_labelへジャンプする
-            "({t_label}-true) \n",  // t_labelのジャンプ先
-            "D=-1 \n",              // Dレジスタに-1を入れる
-            "({f_label}-false) \n", // f_labelのジャンプ先
+            "@{t_label} \n",   // 条件がtrueのときのジャンプ先を指定
+            "D;", $jump, " \n", // 条件がtrueならt_labelにジャンプする
+            "@0 \n",           // 条件がfalseの場合
+            "D=A \n",          // Dレジスタに0を入れる
+            "@{f_label} \n",   // f_labelをジャンプ先として指定
+            "0;JMP \n",        // 無条件でf_labelへジャンプする
+            "({t_label}) \n",  // t_labelのジャンプ先
+            "D=-1 \n",         // Dレジスタに-1を入れる
+            "({f_label}) \n",  // f_labelのジャンプ先
             "@", $var, " \n",
             "A=M \n",
-            "M=D \n",               // $var変数にDレジスタの値を入れる
-            inc!($var)              // インクリメントする
-        ), t_label=$label, f_label=$label)
+            "M=D \n",          // $var変数にDレジスタの値を入れる
+            inc!($var)         // インクリメントする
+        ), t_label=$t_label, f_label=$f_label)
     };
 }
 
@@ -106,44 +107,44 @@ pub fn neg() -> String {
     ).to_string()
 }
 
-/// eqコマンドを変換する関数。引数は現在のアセンブリコードの行数。
+/// eqコマンドを変換する関数。引数は条件がtrue、falseのときのラベル。
 /// trueなら0、falseなら-1がスタックに入る
-pub fn eq(label: &str) -> String {
+pub fn eq(t_label: &str, f_label: &str) -> String {
     /*
     引き算をした結果のMが0かどうか
     */
     let mut asm = String::new();
     asm += binfunc!("SP", "-"); // 引き算をする
     asm += pop2d!("SP");        // 引き算の結果をDレジスタに入れる
-    asm += &ifd!("SP", "JEQ", label);
+    asm += &ifd!("SP", "JEQ", t_label, f_label);
     
     asm
 }
 
-/// gtコマンドを変換する関数。引数は現在のアセンブリコードの行数。
+/// gtコマンドを変換する関数。引数は条件がtrue、falseのときのラベル。
 /// trueなら0、falseなら-1がスタックに入る
-pub fn gt(label: &str) -> String {
+pub fn gt(t_label: &str, f_label: &str) -> String {
     let mut asm = String::new();
     /*
     引き算をした結果が0より大きければtrue
     */
     asm += binfunc!("SP", "-"); // 引き算をする
     asm += pop2d!("SP");        // 引き算の結果をDレジスタに入れる
-    asm += &ifd!("SP", "JGT", label);
+    asm += &ifd!("SP", "JGT", t_label, f_label);
 
     asm
 }
 
-/// ltコマンドを変換する関数。引数は現在のアセンブリコードの行数。
+/// ltコマンドを変換する関数。引数は条件がtrue、falseのときのラベル。
 /// trueなら0、falseなら-1がスタックに入る
-pub fn lt(label: &str) -> String {
+pub fn lt(t_label: &str, f_label: &str) -> String {
     let mut asm = String::new();
     /*
     引き算をした結果が0より小さければtrue
     */
     asm += binfunc!("SP", "-"); // 引き算をする
     asm += pop2d!("SP");        // 引き算の結果をDレジスタに入れる
-    asm += &ifd!("SP", "JLT", label);
+    asm += &ifd!("SP", "JLT", t_label, f_label);
 
     asm
 }
@@ -168,6 +169,324 @@ pub fn not() -> String {
 }
 
 
+/// geコマンド。ltの結果を反転する
+pub fn ge(t_label: &str, f_label: &str) -> String {
+    lt(t_label, f_label) + &not()
+}
+
+/// leコマンド。gtの結果を反転する
+pub fn le(t_label: &str, f_label: &str) -> String {
+    gt(t_label, f_label) + &not()
+}
+
+/// neコマンド。eqの結果を反転する
+pub fn ne(t_label: &str, f_label: &str) -> String {
+    eq(t_label, f_label) + &not()
+}
+
+/// mulコマンド。引数はループに使うラベルの接頭辞。
+/// yのビットを下から順に調べ、立っているビットについて
+/// xを左にずらした値を足していく
+pub fn mul(label: &str) -> String {
+    format!(concat!(
+        pop2d!("SP"),
+        "@R13 \n",
+        "M=D \n",         // R13 = y
+        "@SP \n",
+        "A=M-1 \n",
+        "D=M \n",
+        "@R14 \n",
+        "M=D \n",         // R14 = x
+        "@SP \n",
+        "A=M-1 \n",
+        "M=0 \n",         // 結果はスタックの一番上で計算する
+        "@R15 \n",
+        "M=1 \n",         // R15 = 調べるビット
+        "({l}.loop) \n",
+        "@R15 \n",
+        "D=M \n",
+        "@{l}.end \n",
+        "D;JEQ \n",       // 16ビットすべて調べたら終わり
+        "@R13 \n",
+        "D=D&M \n",
+        "@{l}.skip \n",
+        "D;JEQ \n",
+        "@R14 \n",
+        "D=M \n",
+        "@SP \n",
+        "A=M-1 \n",
+        "M=D+M \n",       // ビットが立っていれば足す
+        "({l}.skip) \n",
+        "@R14 \n",
+        "D=M \n",
+        "M=D+M \n",       // xを左に１ビットずらす
+        "@R15 \n",
+        "D=M \n",
+        "M=D+M \n",       // 次のビット
+        "@{l}.loop \n",
+        "0;JMP \n",
+        "({l}.end) \n",
+    ), l=label)
+}
+
+/// divコマンド。引数はループに使うラベルの接頭辞。
+/// 符号を除いた値で筆算をし、最後に符号を付ける。商は0の方向に丸める。
+/// 0で割った場合は0になる。
+/// スタックの上の空いている領域に、符号と、yを2倍ずつした値の表を置く
+pub fn div(label: &str) -> String {
+    format!(concat!(
+        pop2d!("SP"),
+        "@R14 \n",
+        "M=D \n",         // R14 = y
+        "@SP \n",
+        "A=M-1 \n",
+        "D=M \n",
+        "@R13 \n",
+        "M=D \n",         // R13 = x
+        "@SP \n",
+        "A=M \n",
+        "M=0 \n",         // M[SP] = 符号。負なら-1
+        "@R13 \n",
+        "D=M \n",
+        "@{l}.xpos \n",
+        "D;JGE \n",
+        "@R13 \n",
+        "M=-M \n",
+        "@SP \n",
+        "A=M \n",
+        "M=!M \n",
+        "({l}.xpos) \n",
+        "@R14 \n",
+        "D=M \n",
+        "@{l}.zero \n",
+        "D;JEQ \n",
+        "@{l}.ypos \n",
+        "D;JGT \n",
+        "@R14 \n",
+        "M=-M \n",
+        "@SP \n",
+        "A=M \n",
+        "M=!M \n",
+        "({l}.ypos) \n",
+        "@SP \n",
+        "D=M+1 \n",
+        "@R15 \n",
+        "M=D \n",         // R15 = 表の今の位置
+        "@R14 \n",
+        "D=M \n",
+        "@R15 \n",
+        "A=M \n",
+        "M=D \n",         // 表の最初はy
+        "({l}.build) \n", // 2倍してもx以下の間、表に2倍した値を足す
+        "@R15 \n",
+        "A=M \n",
+        "D=M \n",
+        "@R13 \n",
+        "D=M-D \n",
+        "@{l}.down \n",
+        "D;JLT \n",        // 先に引いておき、x-2yが桁あふれしないようにする
+        "@R15 \n",
+        "A=M \n",
+        "D=D-M \n",
+        "@{l}.down \n",
+        "D;JLT \n",
+        "@R15 \n",
+        "A=M \n",
+        "D=M \n",
+        "A=A+1 \n",
+        "M=D \n",
+        "M=D+M \n",
+        "@R15 \n",
+        "M=M+1 \n",
+        "@{l}.build \n",
+        "0;JMP \n",
+        "({l}.down) \n",
+        "@R14 \n",
+        "M=0 \n",         // R14 = 商
+        "({l}.loop) \n",  // 表を大きい方から引いていき、商を1ビットずつ決める
+        "@R14 \n",
+        "D=M \n",
+        "M=D+M \n",
+        "@R15 \n",
+        "A=M \n",
+        "D=M \n",
+        "@R13 \n",
+        "D=M-D \n",
+        "@{l}.next \n",
+        "D;JLT \n",
+        "@R13 \n",
+        "M=D \n",
+        "@R14 \n",
+        "M=M+1 \n",
+        "({l}.next) \n",
+        "@R15 \n",
+        "MD=M-1 \n",
+        "@SP \n",
+        "D=D-M \n",
+        "@{l}.loop \n",
+        "D;JGT \n",
+        "@SP \n",
+        "A=M \n",
+        "D=M \n",
+        "@{l}.pos \n",
+        "D;JEQ \n",
+        "@R14 \n",
+        "M=-M \n",
+        "({l}.pos) \n",
+        "@R14 \n",
+        "D=M \n",
+        "@SP \n",
+        "A=M-1 \n",
+        "M=D \n",
+        "@{l}.end \n",
+        "0;JMP \n",
+        "({l}.zero) \n",
+        "@SP \n",
+        "A=M-1 \n",
+        "M=0 \n",
+        "({l}.end) \n",
+    ), l=label)
+}
+
+/// shlコマンド。xをyビット左にずらす。yが0以下の場合はそのまま
+pub fn shl(label: &str) -> String {
+    format!(concat!(
+        pop2d!("SP"),
+        "@R13 \n",
+        "M=D \n",         // R13 = 残りの回数
+        "({l}.loop) \n",
+        "@R13 \n",
+        "D=M \n",
+        "@{l}.end \n",
+        "D;JLE \n",
+        "@R13 \n",
+        "M=D-1 \n",
+        "@SP \n",
+        "A=M-1 \n",
+        "D=M \n",
+        "M=D+M \n",       // 2倍する
+        "@{l}.end \n",
+        "D;JEQ \n",       // 0になったらそれ以上ずらしても変わらない
+        "@{l}.loop \n",
+        "0;JMP \n",
+        "({l}.end) \n",
+    ), l=label)
+}
+
+/// shrコマンド。xをyビット右にずらす（算術シフト）。yが0以下の場合はそのまま。
+/// xのyビット目から上のビットを１ビットずつ結果の下のビットへ写し、
+/// xが負なら空いた上のビットを1で埋める。
+/// スタックの上の空いている領域に残りの回数を置く
+pub fn shr(label: &str) -> String {
+    format!(concat!(
+        pop2d!("SP"),     // M[SP] = 残りの回数
+        "@R14 \n",
+        "M=1 \n",         // R14 = xで調べるビット
+        "({l}.mask) \n",
+        "@SP \n",
+        "A=M \n",
+        "D=M \n",
+        "@{l}.masked \n",
+        "D;JLE \n",
+        "@SP \n",
+        "A=M \n",
+        "M=D-1 \n",
+        "@R14 \n",
+        "D=M \n",
+        "MD=D+M \n",
+        "@{l}.mask \n",
+        "D;JNE \n",
+        "({l}.masked) \n",
+        "@SP \n",
+        "A=M-1 \n",
+        "D=M \n",
+        "@R13 \n",
+        "M=D \n",         // R13 = x
+        "@SP \n",
+        "A=M-1 \n",
+        "M=0 \n",         // 結果はスタックの一番上で計算する
+        "@R15 \n",
+        "M=1 \n",         // R15 = 結果に書くビット
+        "({l}.loop) \n",
+        "@R14 \n",
+        "D=M \n",
+        "@{l}.fill \n",
+        "D;JEQ \n",
+        "@R13 \n",
+        "D=D&M \n",
+        "@{l}.skip \n",
+        "D;JEQ \n",
+        "@R15 \n",
+        "D=M \n",
+        "@SP \n",
+        "A=M-1 \n",
+        "M=D|M \n",
+        "({l}.skip) \n",
+        "@R14 \n",
+        "D=M \n",
+        "M=D+M \n",
+        "@R15 \n",
+        "D=M \n",
+        "M=D+M \n",
+        "@{l}.loop \n",
+        "0;JMP \n",
+        "({l}.fill) \n",
+        "@R13 \n",
+        "D=M \n",
+        "@{l}.end \n",
+        "D;JGE \n",
+        "({l}.ones) \n",
+        "@R15 \n",
+        "D=M \n",
+        "@{l}.end \n",
+        "D;JEQ \n",
+        "@SP \n",
+        "A=M-1 \n",
+        "M=D|M \n",
+        "@R15 \n",
+        "D=M \n",
+        "M=D+M \n",
+        "@{l}.ones \n",
+        "0;JMP \n",
+        "({l}.end) \n",
+    ), l=label)
+}
+
+/// dupコマンド。スタックの一番上の値をもう一度積む
+pub fn dup() -> String {
+    concat!(
+        "@SP \n",
+        "A=M-1 \n",
+        "D=M \n",
+        "@SP \n",
+        "A=M \n",
+        "M=D \n",
+        inc!("SP"),
+    ).to_string()
+}
+
+/// swapコマンド。スタックの上の２つの値を入れ替える
+pub fn swap() -> String {
+    concat!(
+        pop2d!("SP"),
+        "@R13 \n",
+        "M=D \n",   // R13 = 一番上の値
+        "@SP \n",
+        "A=M-1 \n",
+        "D=M \n",
+        "@SP \n",
+        "A=M \n",
+        "M=D \n",   // 二番目の値を一番上へ
+        "@R13 \n",
+        "D=M \n",
+        "@SP \n",
+        "A=M-1 \n",
+        "M=D \n",   // 一番上の値を二番目へ
+        inc!("SP"),
+    ).to_string()
+}
+
+
 // Dレジスタの値をスタックへ入れる
 macro_rules! d2stack {
     () => {
@@ -439,6 +758,25 @@ pub fn ret() -> String {
 }
 
 
+/// レジスタ(SP, LCLなど)に定数を代入する
+pub fn set_register(register: &str, value: u16) -> String {
+    format!(concat!(
+        "@{v} \n",
+        "D=A \n",
+        "@{r} \n",
+        "M=D \n",
+    ), v=value, r=register)
+}
+
+/// 無限ループでプログラムを停止させる
+pub fn halt(label: &str) -> String {
+    format!(concat!(
+        "({l}) \n",
+        "@{l} \n",
+        "0;JMP \n",
+    ), l=label)
+}
+
 /// SPが指す番地に定数(n)を代入してSPをインクリメントする
 pub fn push_constant(n: isize) -> String {
     /*
@@ -536,6 +874,42 @@ pub fn pop_static(index: isize, filename: &str) -> String {
     ), filename, n=index)
 }
 
+/// push indirectコマンド。スタックの一番上の値をアドレスとして、
+/// そのアドレスにindexを足した番地の値に置き換える
+pub fn push_indirect(index: isize) -> String {
+    format!(concat!(
+        "@SP \n",
+        "A=M-1 \n",
+        "D=M \n",     // アドレスをDレジスタへ
+        "@{n} \n",
+        "A=D+A \n",
+        "D=M \n",     // アドレス+indexの番地の値
+        "@SP \n",
+        "A=M-1 \n",
+        "M=D \n",
+    ), n=index)
+}
+
+/// pop indirectコマンド。スタックから値とアドレスをpopし、
+/// アドレスにindexを足した番地に値を書き込む
+pub fn pop_indirect(index: isize) -> String {
+    format!(concat!(
+        pop2d!("SP"),
+        "@R14 \n",
+        "M=D \n",     // R14 = 値
+        pop2d!("SP"),
+        "@{n} \n",
+        "D=D+A \n",
+        "@R15 \n",
+        "M=D \n",     // R15 = アドレス+index
+        "@R14 \n",
+        "D=M \n",
+        "@R15 \n",
+        "A=M \n",
+        "M=D \n",
+    ), n=index)
+}
+
 
 #[cfg(test)]
 mod test {
@@ -4,18 +4,58 @@
 //! * R13 pop2d!マクロ内で使われる
 //! * R14 returnコマンドのLCLの値を一時保存するために使われる
 //! * R15 returnコマンドのreturn addressを一時保存するために使われる
+//! * R13~R15は拡張命令（mul、div、shl、shr、swap、indirect）の計算にも使われる。
+//!   divとshrはスタックの上の空いている領域も一時的に使う
 
 #![allow(dead_code)]
 use std::io::Write;
 
 mod converter;
-mod symbol_manager;
-use symbol_manager::SymbolManager;
+pub(crate) mod symbol_manager;
+pub(crate) use symbol_manager::SymbolManager;
+pub use symbol_manager::NamingScheme;
+use crate::identifier::IdentifierPolicy;
+use crate::program::{self, Dialect, Op, Segment};
+use crate::bootstrap::Bootstrap;
+use crate::hack;
+
+/// アセンブリコードに書き込むコメントの形式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommentStyle {
+    /// コメントを書かない
+    None,
+    /// 各コマンドの前に`// push constant 1`のようなコメントを１行だけ書く
+    Compact,
+    /// 各コマンドを`// [start] ...`と`// [end] ...`で囲み、
+    /// ファイルの先頭に`// [file] ...`を書く
+    Full,
+    /// 各コマンドの前に、最初の命令のアドレス、ファイル名と行番号、
+    /// 現在の関数、元のソースコードの行を
+    /// `// @53 Main:8 (Main.main) push constant 1 // x`の形で書く
+    Verbose,
+}
+
+impl CommentStyle {
+    /// `none`, `compact`, `full`, `verbose`のどれかを読む
+    pub fn from_name(name: &str) -> Option<CommentStyle> {
+        match name {
+            "none" => Some(CommentStyle::None),
+            "compact" => Some(CommentStyle::Compact),
+            "full" => Some(CommentStyle::Full),
+            "verbose" => Some(CommentStyle::Verbose),
+            _ => None,
+        }
+    }
+}
 
 /// VMコマンドをHackのアセンブリコードに変換する。
 pub struct CodeWriter<W> {
     filename: String,
     sm: SymbolManager,
+    comment_style: CommentStyle,
+    dialect: Dialect,
+    address: usize, // 次に書く命令のアドレス
+    source: Option<(usize, String)>, // 変換中のコマンドの行番号と元の行
     asm: W
 }
 
@@ -25,36 +65,165 @@ impl <W: Write> CodeWriter<W> {
         CodeWriter {
             filename: String::new(),
             sm: SymbolManager::new(),
+            comment_style: CommentStyle::Full,
+            dialect: Dialect::Strict,
+            address: 0,
+            source: None,
             asm: stream,
         }
     }
 
+    /// symbolの名前の付け方を設定する。デフォルトは`NamingScheme::Current`
+    pub fn set_naming_scheme(&mut self, scheme: NamingScheme) {
+        self.sm.set_naming_scheme(scheme);
+    }
+
+    /// ラベルと関数名の扱いを設定する。`IdentifierPolicy::Escape`の場合は
+    /// 識別子の規則に合わない名前を変換してからsymbolに使う
+    pub fn set_identifier_policy(&mut self, policy: IdentifierPolicy) {
+        self.sm.set_identifier_policy(policy);
+    }
+
+    /// 受け付けるコマンドの範囲を設定する。デフォルトは`Dialect::Strict`で、
+    /// 拡張命令はエラーになる
+    pub fn set_dialect(&mut self, dialect: Dialect) {
+        self.dialect = dialect;
+    }
+
+    /// 次に書く命令のアドレスを設定する。
+    /// `CommentStyle::Verbose`のコメントに書くアドレスはここから数える
+    pub fn set_address(&mut self, address: usize) {
+        self.address = address;
+    }
+
+    /// 次に書く命令のアドレスを返す
+    pub fn address(&self) -> usize {
+        self.address
+    }
+
+    /// 次に変換するコマンドの行番号と元の行を知らせる。
+    /// `CommentStyle::Verbose`のコメントに使う
+    pub fn set_source_line(&mut self, line: usize, source: &str) {
+        self.source = Some((line, source.trim().to_string()));
+    }
+
+    /// 書き込むコメントの形式を設定する。デフォルトは`CommentStyle::Full`
+    pub fn set_comment_style(&mut self, style: CommentStyle) {
+        self.comment_style = style;
+    }
+
+    /// 書き込み先への参照を返す
+    pub fn get_ref(&self) -> &W {
+        &self.asm
+    }
+
+    /// CodeWriterを消費して書き込み先を返す
+    pub fn into_inner(self) -> W {
+        self.asm
+    }
+
     /// CodeWriterモジュールに新しいVMファイルの変換が開始したことを知らせる
     pub fn set_file_name(&mut self, filename: &str) {
         self.filename = filename.to_string();
-        let asm = format!("// [file] {} \n", filename);
+        self.sm.set_file_name(filename);
+        if self.comment_style == CommentStyle::Full {
+            let asm = format!("// [file] {} \n", filename);
+            self.write_raw(&asm);
+        }
+    }
+
+    /// アセンブリコードをそのまま書き込み、命令の数だけアドレスを進める
+    fn write_raw(&mut self, asm: &str) {
         let _ = self.asm.write(asm.as_bytes());
+        self.address += hack::count_instructions(asm);
+    }
+
+    /// コメントの形式に合わせてコマンドのアセンブリコードを書き込む。
+    /// commentはコメントに書くコマンドの内容
+    fn write_code(&mut self, comment: &str, asm: &str) {
+        let source = self.source.take();
+        let asm_code = match self.comment_style {
+            CommentStyle::None => asm.to_string(),
+            CommentStyle::Compact => format!("// {}\n{}", comment.trim(), asm),
+            CommentStyle::Full => format!(concat!(
+                "// [start] {c}\n",
+                "{}",
+                "// [end] {c}\n"
+            ), asm, c=comment),
+            CommentStyle::Verbose => {
+                let file = if self.filename.is_empty() {
+                    "-"
+                } else {
+                    &self.filename
+                };
+                let function = match self.sm.function_name() {
+                    "" => "-",
+                    f => f,
+                };
+                let (line, text) = match source {
+                    Some((line, text)) => (line, text),
+                    None => (0, comment.trim().to_string()),
+                };
+                format!("// @{} {}:{} ({}) {}\n{}", self.address, file, line,
+                        function, text, asm)
+            },
+        };
+
+        self.write_raw(&asm_code);
     }
 
     /// VMの初期化（これは「ブートストラップ」と呼ばれる）
     /// を行うアセンブリコードを書く。このコードは出力ファイルの先頭に
     /// 配置しなければならない
     pub fn write_init(&mut self) {
-        let asm = format!(concat!(
-            "@256 \n", // SP(スタックポインタ)を256に設定する
-            "D=A \n",
-            "@SP \n",
-            "M=D \n",
-        ));
+        // SP(スタックポインタ)を256に設定する
+        let asm = converter::set_register("SP", 256);
 
-        let _ = self.asm.write(asm.as_bytes());
+        self.write_raw(&asm);
+    }
+
+    /// 設定に従ってブートストラップコードを書く。
+    /// レジスタを初期化し、エントリー関数を呼び、必要なら停止するループを書く
+    pub fn write_bootstrap(&mut self, bootstrap: &Bootstrap)
+        -> Result<(), String>
+    {
+        let mut asm = converter::set_register("SP", bootstrap.sp);
+        let registers = [("LCL", bootstrap.lcl), ("ARG", bootstrap.arg),
+                         ("THIS", bootstrap.this), ("THAT", bootstrap.that)];
+        for (register, value) in registers.iter() {
+            if let Some(v) = value {
+                asm += &converter::set_register(register, *v);
+            }
+        }
+        match self.comment_style {
+            // 今までの出力と同じにするためにレジスタの設定はコメントで囲まない
+            CommentStyle::None | CommentStyle::Full => self.write_raw(&asm),
+            _ => self.write_code("bootstrap", &asm),
+        }
+
+        if let Some(entry) = &bootstrap.entry {
+            self.write_call(entry, 0)?;
+        }
+
+        if bootstrap.halt {
+            let asm = converter::halt(&self.sm.get_halt_symbol());
+            self.write_code("halt", &asm);
+        }
+
+        Ok(())
     }
 
     /// labelコマンドを行うアセンブリコードを書く
     pub fn write_label(&mut self, label: &str) -> Result<(), String> {
         // labelが被らないようにSymbolManagerを使う
-        let label = self.sm.get_goto_symbol(label);
-        let _ = self.asm.write(format!("({}) \n", label).as_bytes());
+        self.sm.check_label(label)?;
+        let symbol = self.sm.get_goto_symbol(label);
+        let asm = format!("({}) \n", symbol);
+        match self.comment_style {
+            // 今までの出力と同じにするためにラベルはコメントで囲まない
+            CommentStyle::None | CommentStyle::Full => self.write_raw(&asm),
+            _ => self.write_code(&format!("label {}", label), &asm),
+        }
         Ok(())
     }
 
@@ -67,13 +236,7 @@ impl <W: Write> CodeWriter<W> {
             "0;JMP \n"
         ), label);
 
-        let asm_code = format!(concat!(
-            "// [start] goto {l}\n",
-            "{}",
-            "// [end] goto {l}\n"
-        ), asm, l=label);
-
-        let _ = self.asm.write(asm_code.as_bytes());
+        self.write_code(&format!("goto {}", label), &asm);
 
         Ok(())
     }
@@ -84,13 +247,7 @@ impl <W: Write> CodeWriter<W> {
         let label = self.sm.get_goto_symbol(label);
         let asm = converter::if_goto(&label);
 
-        let asm_code = format!(concat!(
-            "// [start] if-goto {l}\n",
-            "{}",
-            "// [end] if-goto {l}\n"
-        ), asm, l=label);
-
-        let _ = self.asm.write(asm_code.as_bytes());
+        self.write_code(&format!("if-goto {}", label), &asm);
 
         Ok(())
     }
@@ -103,13 +260,7 @@ impl <W: Write> CodeWriter<W> {
        let return_address = self.sm.get_return_address_symbol(function);
        let asm = converter::call(&funcname, argc, &return_address);
 
-       let asm_code = format!(concat!(
-           "// [start] call {f} {n}\n",
-           "{}",
-           "// [end] call {f} {n}\n"
-       ), asm, f=function, n=argc);
-
-       let _ = self.asm.write(asm_code.as_bytes());
+       self.write_code(&format!("call {} {}", function, argc), &asm);
 
        Ok(())
    }
@@ -121,14 +272,8 @@ impl <W: Write> CodeWriter<W> {
         let funcname = self.sm.get_function_symbol(function);
         let asm = converter::function(&funcname, number);
 
-        let asm_code = format!(concat!(
-            "// [start] function {f} {n}\n",
-            "{}",
-            "// [end] function {f} {n}\n"
-        ), asm, f=function, n=number);
-        let _ = self.asm.write(asm_code.as_bytes());
-
         self.sm.set_function_name(function);
+        self.write_code(&format!("function {} {}", function, number), &asm);
 
         Ok(())
     }
@@ -137,38 +282,58 @@ impl <W: Write> CodeWriter<W> {
     pub fn write_return(&mut self) -> Result<(), String> {
         let asm = converter::ret();
 
-        let asm_code = format!(concat!(
-            "// [start] return\n",
-            "{}",
-            "// [end] return\n"
-        ), asm);
-
-        let _ = self.asm.write(asm_code.as_bytes());
+        self.write_code("return", &asm);
 
         Ok(())
     }
 
     /// 与えられた算術コマンドをアセンブリコードに変換し、それを書き込む
     pub fn write_arithmetic(&mut self, command: &str) -> Result<(), String> {
+        let extension = Op::from_name(command).is_some_and(|op| op.is_extension());
+        if extension && self.dialect == Dialect::Strict {
+            return Err(program::extension_error(command));
+        }
         let asm = match command {
             "add" => converter::add(),
             "sub" => converter::sub(),
             "neg" => converter::neg(),
-            "eq" => converter::eq(&self.sm.get_ifd_symbol()),
-            "gt" => converter::gt(&self.sm.get_ifd_symbol()),
-            "lt" => converter::lt(&self.sm.get_ifd_symbol()),
+            "eq" => {
+                let (t, f) = self.sm.get_ifd_labels();
+                converter::eq(&t, &f)
+            },
+            "gt" => {
+                let (t, f) = self.sm.get_ifd_labels();
+                converter::gt(&t, &f)
+            },
+            "lt" => {
+                let (t, f) = self.sm.get_ifd_labels();
+                converter::lt(&t, &f)
+            },
             "and" => converter::and(),
             "or" => converter::or(),
             "not" => converter::not(),
+            "mul" => converter::mul(&self.sm.get_loop_symbol()),
+            "div" => converter::div(&self.sm.get_loop_symbol()),
+            "shl" => converter::shl(&self.sm.get_loop_symbol()),
+            "shr" => converter::shr(&self.sm.get_loop_symbol()),
+            "dup" => converter::dup(),
+            "swap" => converter::swap(),
+            "ge" => {
+                let (t, f) = self.sm.get_ifd_labels();
+                converter::ge(&t, &f)
+            },
+            "le" => {
+                let (t, f) = self.sm.get_ifd_labels();
+                converter::le(&t, &f)
+            },
+            "ne" => {
+                let (t, f) = self.sm.get_ifd_labels();
+                converter::ne(&t, &f)
+            },
             _ => return Err(format!("{} は無効なコマンドです", command))
         };
 
-        let asm_code = format!(concat!(
-            "// [start] {c} \n",
-            "{}",
-            "// [end] {c} \n"
-        ), asm, c=command);
-        let _ = self.asm.write(asm_code.as_bytes());
+        self.write_code(&format!("{} ", command), &asm);
 
         Ok(())
     }
@@ -177,6 +342,10 @@ impl <W: Write> CodeWriter<W> {
     /// 変換し、それを書き込む
     pub fn write_push_pop(&mut self, command: &str, segment: &str, 
                           index: isize) -> Result<(), String> {
+        let extension = Segment::from_name(segment).is_some_and(|s| s.is_extension());
+        if extension && self.dialect == Dialect::Strict {
+            return Err(program::extension_error(&format!("{} {}", command, segment)));
+        }
         let asm = match command {
             "push" => match segment {
                 "constant" => converter::push_constant(index),
@@ -187,6 +356,7 @@ impl <W: Write> CodeWriter<W> {
                 "temp" => converter::push_temp(index),
                 "pointer" => converter::push_pointer(index),
                 "static" => converter::push_static(index, &self.filename),
+                "indirect" => converter::push_indirect(index),
                 _ => return Err(format!("push {} は無効なセグメントです", 
                                         segment))
             },
@@ -198,18 +368,14 @@ impl <W: Write> CodeWriter<W> {
                 "temp" => converter::pop_temp(index),
                 "pointer" => converter::pop_pointer(index),
                 "static" => converter::pop_static(index, &self.filename),
+                "indirect" => converter::pop_indirect(index),
                 _ => return Err(format!("pop {} は無効なセグメントです", 
                                         segment))
             },
             _ => return Err(format!("{} は無効なコマンドです", command)),
         };
 
-        let asm_code = format!(concat!(
-            "// [start] {c} {s} {i} \n",
-            "{}",
-            "// [end] {c} {s} {i} \n"
-        ), asm, c=command, s=segment, i=index);
-        let _ = self.asm.write(asm_code.as_bytes());
+        self.write_code(&format!("{} {} {} ", command, segment, index), &asm);
         Ok(())
     }
 }
@@ -217,9 +383,12 @@ impl <W: Write> CodeWriter<W> {
 
 #[cfg(test)]
 mod test {
+    #[allow(unused_imports)]
     use std::io::Read;
 
     use super::CodeWriter;
+    use super::CommentStyle;
+    use crate::bootstrap::Bootstrap;
     use std::io::Cursor;
 
     #[test]
@@ -265,4 +434,74 @@ mod test {
         cw.write_arithmetic("eq").unwrap();
         println!("{}", String::from_utf8(cw.asm.get_ref().to_vec()).unwrap());
     }
+
+    #[test]
+    fn test_code_writer_write_bootstrap() {
+        let mut cw = CodeWriter::new(Cursor::new(Vec::new()));
+        cw.set_comment_style(CommentStyle::None);
+        let mut bootstrap = Bootstrap::new();
+        bootstrap.sp = 300;
+        bootstrap.that = Some(3000);
+        bootstrap.entry = Some("Main.main".to_string());
+        bootstrap.halt = true;
+        cw.write_bootstrap(&bootstrap).unwrap();
+
+        let asm = String::from_utf8(cw.into_inner().into_inner()).unwrap();
+        assert!(asm.starts_with("@300 \nD=A \n@SP \nM=D \n@3000 \nD=A \n@THAT \n"));
+        assert!(asm.contains("@symbol-function-Main.main \n"));
+        assert!(asm.ends_with("(symbol-halt) \n@symbol-halt \n0;JMP \n"));
+
+        // デフォルトの設定では今までと同じくレジスタの設定をコメントで囲まない
+        let mut cw = CodeWriter::new(Cursor::new(Vec::new()));
+        cw.write_bootstrap(&Bootstrap::new()).unwrap();
+        let asm = String::from_utf8(cw.into_inner().into_inner()).unwrap();
+        assert!(asm.starts_with("@256 \nD=A \n@SP \nM=D \n// [start] call Sys.init"));
+    }
+
+    #[test]
+    fn test_code_writer_comment_style_compact() {
+        let mut cw = CodeWriter::new(Cursor::new(Vec::new()));
+        cw.set_comment_style(CommentStyle::Compact);
+        cw.set_file_name("Foo");
+        cw.write_push_pop("push", "constant", 3).unwrap();
+        cw.write_arithmetic("add").unwrap();
+
+        let asm = String::from_utf8(cw.into_inner().into_inner()).unwrap();
+        let comments: Vec<&str> = asm.lines().filter(|l| l.starts_with("//"))
+                                     .collect();
+        assert_eq!(comments, vec!["// push constant 3", "// add"]);
+    }
+
+    #[test]
+    fn test_code_writer_comment_style_verbose() {
+        let mut cw = CodeWriter::new(Cursor::new(Vec::new()));
+        cw.set_comment_style(CommentStyle::Verbose);
+        cw.set_file_name("Foo");
+        cw.set_address(100);
+        cw.set_source_line(2, "function Foo.bar 0 ");
+        cw.write_function("Foo.bar", 0).unwrap();
+        cw.set_source_line(3, "  push constant 3  // three");
+        cw.write_push_pop("push", "constant", 3).unwrap();
+        assert_eq!(cw.address(), 107);
+
+        let asm = String::from_utf8(cw.into_inner().into_inner()).unwrap();
+        let comments: Vec<&str> = asm.lines().filter(|l| l.starts_with("//"))
+                                     .collect();
+        assert_eq!(comments, vec![
+            "// @100 Foo:2 (Foo.bar) function Foo.bar 0",
+            "// @100 Foo:3 (Foo.bar) push constant 3  // three",
+        ]);
+    }
+
+    #[test]
+    fn test_code_writer_comment_style_none() {
+        let mut cw = CodeWriter::new(Cursor::new(Vec::new()));
+        cw.set_comment_style(CommentStyle::None);
+        cw.set_file_name("Foo");
+        cw.write_push_pop("push", "static", 3).unwrap();
+
+        let asm = String::from_utf8(cw.into_inner().into_inner()).unwrap();
+        assert!(!asm.contains("//"));
+        assert!(asm.starts_with("@Foo.3 \n"));
+    }
 }
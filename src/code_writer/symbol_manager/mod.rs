@@ -1,20 +1,120 @@
 //! symbolを管理するモジュール。
 //! symbolが被らないようにする。
+//! ファイル名が設定されている場合は、カウンターを使うsymbolにファイル名を入れる。
+//! こうすることでファイルごとに別々のSymbolManagerを使っても被らなくなるので、
+//! ファイルごとに独立して変換できる。
+//!
+//! symbolの名前の付け方は`NamingScheme`で選ぶ
+//!
+//! | symbol | `Current` | `Spec` |
+//! | --- | --- | --- |
+//! | 関数 | `symbol-function-f` | `f` |
+//! | return address | `symbol-return-address-File-f-N` | `caller$ret.N` |
+//! | 比較 | `symbol-ifd-File-N-true` | `scope$cmp.N.true` |
+//! | 拡張命令のループ | `symbol-loop-File-N.loop` | `scope$loop.N.loop` |
+//! | ラベル | `function$label` | `function$label` |
+//! | 停止ループ | `symbol-halt` | `bootstrap$halt` |
+//!
+//! `Spec`のcallerはcallコマンドがある関数で、Nは関数ごとに0から数える。
+//! scopeは変換中の関数で、関数の外ではファイル名になる。
+//! ラベルのsymbolと被らないように、`Spec`では`ret.N`、`cmp.N`、`loop.N`と
+//! それに`.`で続く名前のラベルはエラーにする
+//!
+//! `IdentifierPolicy::Escape`の場合は、symbolに入れるラベルと関数名を
+//! `identifier::escape`で変換する
+
+use std::collections::HashMap;
+
+use crate::identifier::{self, IdentifierPolicy};
+
+/// symbolの名前の付け方
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NamingScheme {
+    /// 今までの名前の付け方
+    Current,
+    /// nand2tetrisの仕様と公式のVMエミュレーターに合わせた名前の付け方
+    Spec,
+}
+
+impl NamingScheme {
+    /// `current`か`spec`を読む
+    pub fn from_name(name: &str) -> Option<NamingScheme> {
+        match name {
+            "current" => Some(NamingScheme::Current),
+            "spec" => Some(NamingScheme::Spec),
+            _ => None,
+        }
+    }
+}
 
 pub struct SymbolManager {
+    scheme: NamingScheme,
+    policy: IdentifierPolicy,
     file_name: String,
     function_name: String,
     ifd_count: usize,
+    loop_count: usize,
     ra_count: usize, // return address count
+    ra_counts: HashMap<String, usize>, // 関数ごとのreturn address count
 }
 
 impl SymbolManager {
     pub fn new() -> SymbolManager {
         SymbolManager {
+            scheme: NamingScheme::Current,
+            policy: IdentifierPolicy::Strict,
             file_name: String::new(),
             function_name: String::new(),
             ifd_count: 0,
-            ra_count: 0, 
+            loop_count: 0,
+            ra_count: 0,
+            ra_counts: HashMap::new(),
+        }
+    }
+
+    /// symbolの名前の付け方を設定する。デフォルトは`NamingScheme::Current`
+    pub fn set_naming_scheme(&mut self, scheme: NamingScheme) {
+        self.scheme = scheme;
+    }
+
+    /// ラベルと関数名の扱いを設定する。デフォルトは`IdentifierPolicy::Strict`
+    pub fn set_identifier_policy(&mut self, policy: IdentifierPolicy) {
+        self.policy = policy;
+    }
+
+    /// symbolに入れるラベルもしくは関数名を返す
+    fn name(&self, name: &str) -> String {
+        match self.policy {
+            IdentifierPolicy::Strict => name.to_string(),
+            IdentifierPolicy::Escape => identifier::escape(name),
+        }
+    }
+
+    /// 変換中のファイル名を設定する。
+    /// 関数はファイルをまたがないので、関数名はリセットする
+    pub fn set_file_name(&mut self, file: &str) {
+        self.file_name = file.to_string();
+        self.function_name.clear();
+    }
+
+    /// カウンターを使うsymbolに入れるファイル名の部分を返す
+    fn scope(&self) -> String {
+        if self.file_name.is_empty() {
+            String::new()
+        } else {
+            format!("{}-", self.file_name)
+        }
+    }
+
+    /// `NamingScheme::Spec`で使う、変換中の関数名を返す。
+    /// 関数の外ではファイル名、ファイル名もない場合は`bootstrap`を返す
+    fn spec_scope(&self) -> String {
+        if !self.function_name.is_empty() {
+            self.name(&self.function_name)
+        } else if !self.file_name.is_empty() {
+            self.file_name.clone()
+        } else {
+            "bootstrap".to_string()
         }
     }
 
@@ -22,28 +122,112 @@ impl SymbolManager {
         self.function_name = function.to_string();
     }
 
+    /// 変換中の関数名を返す。関数の外の場合は空の文字列を返す
+    pub fn function_name(&self) -> &str {
+        &self.function_name
+    }
+
     /// functionコマンドで使うsymbolを取得する
     pub fn get_function_symbol(&mut self, function: &str) -> String {
-        format!("symbol-function-{}", function)
+        match self.scheme {
+            NamingScheme::Current => format!("symbol-function-{}",
+                                             self.name(function)),
+            NamingScheme::Spec => self.name(function),
+        }
     }
 
-    /// callコマンドで使うreturn addressのシンボルを取得する
+    /// callコマンドで使うreturn addressのシンボルを取得する。
+    /// funcは呼び出す関数
     pub fn get_return_address_symbol(&mut self, func: &str) -> String {
-        let s = format!("symbol-return-address-{}-{}", func, self.ra_count);
-        self.ra_count += 1;
-        s
+        match self.scheme {
+            NamingScheme::Current => {
+                let s = format!("symbol-return-address-{}{}-{}", self.scope(),
+                                self.name(func), self.ra_count);
+                self.ra_count += 1;
+                s
+            },
+            NamingScheme::Spec => {
+                let caller = self.spec_scope();
+                let count = self.ra_counts.entry(caller.clone()).or_insert(0);
+                let s = format!("{}$ret.{}", caller, count);
+                *count += 1;
+                s
+            },
+        }
     }
 
     /// converterモジュールのifdマクロで使うsymbolを取得する
     pub fn get_ifd_symbol(&mut self) -> String {
-        let s = format!("symbol-ifd-{}", self.ifd_count);
+        let s = match self.scheme {
+            NamingScheme::Current => format!("symbol-ifd-{}{}", self.scope(),
+                                             self.ifd_count),
+            NamingScheme::Spec => format!("{}$cmp.{}", self.spec_scope(),
+                                          self.ifd_count),
+        };
         self.ifd_count+=1;
         s
     }
 
-    /// gotoのときに使うラベルを取得する
+    /// converterモジュールのifdマクロで使う、条件がtrueのときとfalseのときの
+    /// ラベルを取得する
+    pub fn get_ifd_labels(&mut self) -> (String, String) {
+        let s = self.get_ifd_symbol();
+        match self.scheme {
+            NamingScheme::Current => (format!("{}-true", s), format!("{}-false", s)),
+            NamingScheme::Spec => (format!("{}.true", s), format!("{}.false", s)),
+        }
+    }
+
+    /// 拡張命令（mulなど）のループに使うラベルの接頭辞を取得する。
+    /// converterモジュールが`.loop`、`.end`などを付けて使う
+    pub fn get_loop_symbol(&mut self) -> String {
+        let s = match self.scheme {
+            NamingScheme::Current => format!("symbol-loop-{}{}", self.scope(),
+                                             self.loop_count),
+            NamingScheme::Spec => format!("{}$loop.{}", self.spec_scope(),
+                                          self.loop_count),
+        };
+        self.loop_count += 1;
+        s
+    }
+
+    /// ブートストラップコードの最後で停止するためのラベルを取得する
+    pub fn get_halt_symbol(&self) -> String {
+        match self.scheme {
+            NamingScheme::Current => "symbol-halt".to_string(),
+            NamingScheme::Spec => "bootstrap$halt".to_string(),
+        }
+    }
+
+    /// labelコマンドのラベルが使えるか調べる。
+    /// `NamingScheme::Spec`では生成するsymbolと同じ形のラベルは使えない
+    pub fn check_label(&self, label: &str) -> Result<(), String> {
+        if self.scheme != NamingScheme::Spec {
+            return Ok(());
+        }
+        let name = self.name(label);
+        let mut parts = name.splitn(3, '.');
+        let generated = matches!(parts.next(), Some("ret") | Some("cmp") | Some("loop"))
+            && parts.next().is_some_and(|n| {
+                !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())
+            });
+        if generated {
+            Err(format!("ラベル{}は生成するsymbolと被るので、specの名前の付け方では使えません",
+                        label))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// gotoのときに使うラベルを取得する。
+    /// VMの仕様に合わせて`関数名$ラベル`の形にする。
+    /// 最初のfunctionコマンドより前では関数名の代わりにファイル名を使う
     pub fn get_goto_symbol(&self, label: &str) -> String {
-        format!("symbol-goto-{}-{}", self.function_name, label)
+        if self.function_name.is_empty() {
+            format!("{}${}", self.file_name, self.name(label))
+        } else {
+            format!("{}${}", self.name(&self.function_name), self.name(label))
+        }
     }
 }
 
@@ -51,6 +235,8 @@ impl SymbolManager {
 #[cfg(test)]
 mod test {
     use super::SymbolManager;
+    use super::NamingScheme;
+    use crate::identifier::IdentifierPolicy;
 
     #[test]
     fn test_symbol_manager() {
@@ -63,4 +249,75 @@ mod test {
         assert_eq!(&sm.get_ifd_symbol(), "symbol-ifd-0");
         assert_eq!(&sm.get_ifd_symbol(), "symbol-ifd-1");
     }
+
+    #[test]
+    fn test_symbol_manager_get_goto_symbol() {
+        let mut sm = SymbolManager::new();
+        sm.set_file_name("Main");
+        assert_eq!(&sm.get_goto_symbol("LOOP"), "Main$LOOP");
+        sm.set_function_name("Main.main");
+        assert_eq!(&sm.get_goto_symbol("LOOP"), "Main.main$LOOP");
+
+        // ファイルが変わると関数の外になる
+        sm.set_file_name("Sub");
+        assert_eq!(&sm.get_goto_symbol("LOOP"), "Sub$LOOP");
+    }
+
+    #[test]
+    fn test_symbol_manager_spec() {
+        let mut sm = SymbolManager::new();
+        sm.set_naming_scheme(NamingScheme::Spec);
+        assert_eq!(&sm.get_return_address_symbol("Sys.init"), "bootstrap$ret.0");
+
+        sm.set_file_name("Main");
+        sm.set_function_name("Main.main");
+        assert_eq!(&sm.get_function_symbol("Main.main"), "Main.main");
+        assert_eq!(&sm.get_return_address_symbol("Math.abs"), "Main.main$ret.0");
+        assert_eq!(&sm.get_return_address_symbol("Math.abs"), "Main.main$ret.1");
+        assert_eq!(sm.get_ifd_labels(), ("Main.main$cmp.0.true".to_string(),
+                                         "Main.main$cmp.0.false".to_string()));
+
+        sm.set_function_name("Main.sub");
+        assert_eq!(&sm.get_return_address_symbol("Math.abs"), "Main.sub$ret.0");
+    }
+
+    #[test]
+    fn test_symbol_manager_check_label() {
+        let mut sm = SymbolManager::new();
+        for label in ["ret.0", "cmp.12.true", "loop.3.end", "LOOP", "ret", "ret.x", "cmp.1a"] {
+            assert!(sm.check_label(label).is_ok());
+        }
+
+        sm.set_naming_scheme(NamingScheme::Spec);
+        for label in ["ret.0", "cmp.12.true", "cmp.0", "loop.3.end"] {
+            assert!(sm.check_label(label).is_err(), "{}", label);
+        }
+        for label in ["LOOP", "ret", "ret.x", "cmp.1a", "Ret.0", "loop.", "my.ret.0"] {
+            assert!(sm.check_label(label).is_ok(), "{}", label);
+        }
+    }
+
+    #[test]
+    fn test_symbol_manager_escape() {
+        let mut sm = SymbolManager::new();
+        sm.set_identifier_policy(IdentifierPolicy::Escape);
+        sm.set_file_name("Main");
+        sm.set_function_name("Main.1st");
+        assert_eq!(&sm.get_function_symbol("Main.1st"), "symbol-function-Main.1st");
+        assert_eq!(&sm.get_goto_symbol("(LOOP)"), "Main.1st$:28LOOP:29");
+        assert_eq!(sm.function_name(), "Main.1st");
+
+        sm.set_naming_scheme(NamingScheme::Spec);
+        sm.set_function_name("1st");
+        assert_eq!(&sm.get_return_address_symbol("f"), ":31st$ret.0");
+    }
+
+    #[test]
+    fn test_symbol_manager_file_scope() {
+        let mut sm = SymbolManager::new();
+        sm.set_file_name("Main");
+        assert_eq!(&sm.get_ifd_symbol(), "symbol-ifd-Main-0");
+        assert_eq!(&sm.get_return_address_symbol("Foo.bar"),
+                   "symbol-return-address-Main-Foo.bar-0");
+    }
 }
\ No newline at end of file
@@ -0,0 +1,313 @@
+//! VMコードのデバッガー。
+//! `vm`モジュールのインタープリターでプログラムを実行し、VMコマンドの単位で
+//! 止めたり、関数のセグメントや呼び出し履歴を表示したりする。
+//!
+//! | コマンド | 内容 |
+//! | --- | --- |
+//! | `break file:line`、`break function`（`b`） | ブレークポイントを設定する |
+//! | `delete n` | n番のブレークポイントを削除する |
+//! | `breakpoints` | ブレークポイントの一覧 |
+//! | `continue`（`c`） | ブレークポイントか停止するまで実行する |
+//! | `step`（`s`） | コマンドをひとつ実行する。callの場合は関数に入る |
+//! | `next`（`n`） | コマンドをひとつ実行する。callの場合は戻るまで実行する |
+//! | `finish` | 今の関数から戻るまで実行する |
+//! | `print segment [n]`（`p`） | local、argument、this、that、temp、pointer、static、stackの値 |
+//! | `backtrace`（`bt`） | 呼び出し履歴 |
+//! | `list`（`l`） | 次に実行するコマンド |
+//! | `help` | コマンドの一覧 |
+//! | `quit`（`q`） | 終了する |
+
+use std::fmt::Write as _;
+use std::io::{BufRead, Write};
+
+use crate::program::{Program, Segment};
+use crate::vm::{Vm, State};
+
+/// `continue`などで実行するコマンドの最大数。無限ループで戻らなくなるのを防ぐ
+const MAX_STEPS: usize = 10_000_000;
+
+const HELP: &str = "\
+break file:line | break function (b)  ブレークポイントを設定する
+delete n                             n番のブレークポイントを削除する
+breakpoints                          ブレークポイントの一覧
+continue (c)                         ブレークポイントか停止するまで実行する
+step (s)                             コマンドをひとつ実行する
+next (n)                             callの場合は戻るまで実行する
+finish                               今の関数から戻るまで実行する
+print segment [n] (p)                local、argument、this、that、temp、pointer、static、stack
+backtrace (bt)                       呼び出し履歴
+list (l)                             次に実行するコマンド
+quit (q)                             終了する";
+
+/// thisとthatを表示するときのデフォルトの数
+const DEFAULT_COUNT: usize = 8;
+
+pub struct Debugger {
+    vm: Vm,
+    /// 設定したときの指定とコマンドの番号。削除したものは`None`
+    breakpoints: Vec<Option<(String, usize)>>,
+}
+
+impl Debugger {
+    pub fn new(program: &Program) -> Debugger {
+        Debugger { vm: Vm::new(program), breakpoints: Vec::new() }
+    }
+
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    /// 次に実行するコマンドの場所
+    fn location(&self) -> String {
+        match self.vm.current() {
+            Some(l) => format!("{}:{} {}", l.file, l.line, l.command),
+            None => "停止しました".to_string(),
+        }
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        self.vm.state() == State::Running
+            && self.breakpoints.iter().flatten().any(|(_, pc)| *pc == self.vm.pc)
+    }
+
+    /// conditionが成り立つまで、もしくはブレークポイントか停止するまで実行する
+    fn run_until(&mut self, condition: impl Fn(&Vm) -> bool) -> String {
+        for _ in 0..MAX_STEPS {
+            match self.vm.step() {
+                Ok(State::Halted) => return self.location(),
+                Ok(State::Running) => {},
+                Err(e) => return format!("Error: {} ({})", e, self.location()),
+            }
+            if condition(&self.vm) {
+                return self.location();
+            }
+            if self.at_breakpoint() {
+                return format!("breakpoint: {}", self.location());
+            }
+        }
+        format!("{}個のコマンドを実行しました: {}", MAX_STEPS, self.location())
+    }
+
+    fn set_breakpoint(&mut self, spec: &str) -> String {
+        let pc = match spec.rsplit_once(':') {
+            Some((file, line)) => match line.parse() {
+                Ok(line) => self.vm.find_line(file, line),
+                Err(_) => return format!("{} の行番号が正しくありません", spec),
+            },
+            None => self.vm.function(spec),
+        };
+        match pc {
+            Some(pc) => {
+                self.breakpoints.push(Some((spec.to_string(), pc)));
+                let l = &self.vm.code()[pc];
+                format!("breakpoint {}: {}:{} {}", self.breakpoints.len(), l.file,
+                        l.line, l.command)
+            },
+            None => format!("{} が見つかりません", spec),
+        }
+    }
+
+    fn print(&self, segment: &str, count: Option<usize>) -> String {
+        let frame = self.vm.frames.last();
+        let values: Vec<(String, i16)> = match segment {
+            "stack" => self.vm.stack().iter().enumerate()
+                           .map(|(i, v)| (i.to_string(), *v)).collect(),
+            "static" => {
+                let file = match self.vm.current() {
+                    Some(l) => l.file.clone(),
+                    None => return "停止しています".to_string(),
+                };
+                self.vm.statics(&file).iter()
+                    .map(|(i, a)| (i.to_string(), self.vm.ram[*a])).collect()
+            },
+            name => {
+                let segment = match Segment::from_name(name) {
+                    Some(s) if !matches!(s, Segment::Constant | Segment::Static
+                                         | Segment::Indirect) => s,
+                    _ => return format!("{} は表示できません", name),
+                };
+                let default = match segment {
+                    Segment::Local => frame.map_or(0, |f| f.locals as usize),
+                    Segment::Argument => frame.map_or(0, |f| f.args as usize),
+                    Segment::Temp => 8,
+                    Segment::Pointer => 2,
+                    _ => DEFAULT_COUNT,
+                };
+                let count = count.unwrap_or(default);
+                if count > self.vm.ram.len() {
+                    return format!("表示できるのは{}個までです", self.vm.ram.len());
+                }
+                self.vm.segment(segment, count).iter().enumerate()
+                    .map(|(i, v)| (i.to_string(), *v)).collect()
+            },
+        };
+
+        if values.is_empty() {
+            return format!("{}: (空)", segment);
+        }
+        let mut text = String::new();
+        for (i, v) in values {
+            let _ = writeln!(text, "{}[{}] = {}", segment, i, v);
+        }
+        text.trim_end().to_string()
+    }
+
+    fn backtrace(&self) -> String {
+        let mut text = String::new();
+        let mut location = self.vm.current().map(|l| format!("{}:{}", l.file, l.line));
+        for (i, frame) in self.vm.frames.iter().rev().enumerate() {
+            let _ = writeln!(text, "#{} {} ({})", i, frame.function,
+                             location.as_deref().unwrap_or("-"));
+            location = frame.call_site.map(|pc| {
+                let l = &self.vm.code()[pc];
+                format!("{}:{}", l.file, l.line)
+            });
+        }
+        if text.is_empty() {
+            return format!("(関数の外) {}", location.unwrap_or_default());
+        }
+        text.trim_end().to_string()
+    }
+
+    /// デバッガーのコマンドをひとつ実行し、表示する文字列を返す。
+    /// `quit`の場合は`None`を返す
+    pub fn execute(&mut self, line: &str) -> Option<String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let depth = self.vm.frames.len();
+        let output = match words.as_slice() {
+            [] => String::new(),
+            ["quit"] | ["q"] => return None,
+            ["help"] => HELP.to_string(),
+            ["break", spec] | ["b", spec] => self.set_breakpoint(spec),
+            ["delete", n] => match n.parse::<usize>().ok()
+                                    .and_then(|n| self.breakpoints.get_mut(n.wrapping_sub(1))) {
+                Some(b) if b.is_some() => {
+                    *b = None;
+                    format!("breakpoint {} を削除しました", n)
+                },
+                _ => format!("breakpoint {} はありません", n),
+            },
+            ["breakpoints"] => {
+                let list: Vec<String> = self.breakpoints.iter().enumerate()
+                    .filter_map(|(i, b)| b.as_ref().map(|(spec, _)| format!("{}: {}", i + 1, spec)))
+                    .collect();
+                list.join("\n")
+            },
+            ["continue"] | ["c"] => self.run_until(|_| false),
+            ["step"] | ["s"] => self.run_until(|_| true),
+            ["next"] | ["n"] => self.run_until(move |vm| vm.frames.len() <= depth),
+            ["finish"] => self.run_until(move |vm| vm.frames.len() < depth),
+            ["print", segment] | ["p", segment] => self.print(segment, None),
+            ["print", segment, n] | ["p", segment, n] => match n.parse() {
+                Ok(n) => self.print(segment, Some(n)),
+                Err(_) => format!("{} は数値ではありません", n),
+            },
+            ["backtrace"] | ["bt"] => self.backtrace(),
+            ["list"] | ["l"] => self.location(),
+            _ => format!("{} は無効なコマンドです", line.trim()),
+        };
+        Some(output)
+    }
+}
+
+/// inputからコマンドを読みながらデバッガーを動かす
+pub fn run<R: BufRead, W: Write>(program: &Program, input: R, mut output: W)
+    -> std::io::Result<()>
+{
+    let mut debugger = Debugger::new(program);
+    writeln!(output, "{}", debugger.location())?;
+    write!(output, "(vmdb) ")?;
+    output.flush()?;
+    for line in input.lines() {
+        match debugger.execute(&line?) {
+            Some(text) if text.is_empty() => {},
+            Some(text) => writeln!(output, "{}", text)?,
+            None => break,
+        }
+        write!(output, "(vmdb) ")?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Input;
+
+    fn debugger() -> Debugger {
+        let program = Program::parse(&[
+            Input::new("Sys", concat!(
+                "function Sys.init 0\n",
+                "push constant 2\n",
+                "push constant 3\n",
+                "call Main.add 2\n",
+                "pop static 0\n",
+                "label END\n",
+                "goto END\n",
+            )),
+            Input::new("Main", concat!(
+                "function Main.add 1\n",
+                "push argument 0\n",
+                "push argument 1\n",
+                "add\n",
+                "pop local 0\n",
+                "push local 0\n",
+                "return\n",
+            )),
+        ]).unwrap();
+        Debugger::new(&program)
+    }
+
+    fn exec(d: &mut Debugger, line: &str) -> String {
+        d.execute(line).unwrap()
+    }
+
+    #[test]
+    fn test_debugger_breakpoints() {
+        let mut d = debugger();
+        assert_eq!(exec(&mut d, "list"), "Sys:1 function Sys.init 0");
+        assert_eq!(exec(&mut d, "b Main:4"), "breakpoint 1: Main:4 add");
+        assert_eq!(exec(&mut d, "c"), "breakpoint: Main:4 add");
+        assert_eq!(exec(&mut d, "p argument"), "argument[0] = 2\nargument[1] = 3");
+        assert_eq!(exec(&mut d, "p stack"), "stack[0] = 2\nstack[1] = 3");
+        assert_eq!(exec(&mut d, "bt"), "#0 Main.add (Main:4)\n#1 Sys.init (Sys:4)");
+        assert_eq!(exec(&mut d, "finish"), "Sys:5 pop static 0");
+        assert_eq!(exec(&mut d, "s"), "Sys:6 label END");
+        assert_eq!(exec(&mut d, "p static"), "static[0] = 5");
+        assert_eq!(exec(&mut d, "c"), "停止しました");
+        assert_eq!(d.execute("q"), None);
+    }
+
+    #[test]
+    fn test_debugger_step() {
+        let mut d = debugger();
+        assert_eq!(exec(&mut d, "b Main.add"), "breakpoint 1: Main:1 function Main.add 1");
+        assert_eq!(exec(&mut d, "delete 1"), "breakpoint 1 を削除しました");
+        assert_eq!(exec(&mut d, "n"), "Sys:2 push constant 2");
+        assert_eq!(exec(&mut d, "n"), "Sys:3 push constant 3");
+        assert_eq!(exec(&mut d, "n"), "Sys:4 call Main.add 2");
+        assert_eq!(exec(&mut d, "n"), "Sys:5 pop static 0");
+
+        let mut d = debugger();
+        for _ in 0..4 {
+            exec(&mut d, "s");
+        }
+        assert_eq!(exec(&mut d, "l"), "Main:1 function Main.add 1");
+        exec(&mut d, "s");
+        assert_eq!(exec(&mut d, "p local"), "local[0] = 0");
+        assert_eq!(exec(&mut d, "p nothing"), "nothing は表示できません");
+        assert_eq!(exec(&mut d, "p this 70000"), "表示できるのは32768個までです");
+        assert_eq!(d.vm.segment(Segment::This, 70000).len(), 32768);
+    }
+
+    #[test]
+    fn test_debugger_run() {
+        let mut output = Vec::new();
+        let program = Program::parse(&[Input::new("Main", "push constant 1\n")]).unwrap();
+        run(&program, "s\nq\n".as_bytes(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "Main:1 push constant 1\n(vmdb) 停止しました\n(vmdb) ");
+    }
+}
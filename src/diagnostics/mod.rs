@@ -0,0 +1,109 @@
+//! 変換中に見つかったエラーを位置情報と一緒に扱うモジュール
+
+use std::fmt;
+
+/// ひとつのエラー。ファイル名と行番号はわかる場合のみ入る
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// 位置情報のないエラーを作る
+    pub fn new(message: &str) -> Diagnostic {
+        Diagnostic {
+            file: None,
+            line: None,
+            message: message.to_string(),
+        }
+    }
+
+    /// ファイル名と行番号を持つエラーを作る
+    pub fn at(file: &str, line: usize, message: &str) -> Diagnostic {
+        Diagnostic {
+            file: Some(file.to_string()),
+            line: Some(line),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, "{}:{}: {}", file, line,
+                                               self.message),
+            (Some(file), None) => write!(f, "{}: {}", file, self.message),
+            _ => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// エラーのリスト
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Diagnostics {
+    list: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Diagnostics {
+        Diagnostics { list: Vec::new() }
+    }
+
+    pub fn push(&mut self, diagnostic: Diagnostic) {
+        self.list.push(diagnostic);
+    }
+
+    /// 別のリストのエラーをすべて追加する
+    pub fn extend(&mut self, other: Diagnostics) {
+        self.list.extend(other.list);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Diagnostic> {
+        self.list.iter()
+    }
+}
+
+impl From<Diagnostic> for Diagnostics {
+    fn from(diagnostic: Diagnostic) -> Diagnostics {
+        Diagnostics { list: vec![diagnostic] }
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    /// エラーを１行にひとつずつ書く
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, diagnostic) in self.list.iter().enumerate() {
+            if i != 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_diagnostics_display() {
+        let mut d = Diagnostics::new();
+        d.push(Diagnostic::at("Main", 3, "foo は無効なコマンドです"));
+        d.push(Diagnostic::new("vm_pathがありません"));
+        assert_eq!(format!("{}", d),
+                   "Main:3: foo は無効なコマンドです\nvm_pathがありません");
+    }
+}
@@ -0,0 +1,135 @@
+//! Hackコンピューターのエミュレーター。
+//! `hack::assemble`で作った機械語を命令ごとに実行する。
+//! `@LOOP`、`0;JMP`のように自分自身へジャンプし続けるループに入るか、
+//! ROMの最後の命令を実行すると停止する
+
+/// RAMの大きさ。スクリーンとキーボードのメモリーマップを含む
+const RAM_SIZE: usize = 32768;
+
+pub struct Emulator {
+    rom: Vec<u16>,
+    pub ram: Vec<i16>,
+    pub a: i16,
+    pub d: i16,
+    pub pc: usize,
+    halted: bool,
+}
+
+impl Emulator {
+    pub fn new(rom: Vec<u16>) -> Emulator {
+        let halted = rom.is_empty();
+        Emulator { rom, ram: vec![0; RAM_SIZE], a: 0, d: 0, pc: 0, halted }
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    fn address(&self) -> usize {
+        self.a as u16 as usize % RAM_SIZE
+    }
+
+    /// ALUの計算。bitsはC命令のzx、nx、zy、ny、f、noの6ビット
+    fn alu(x: i16, y: i16, bits: u16) -> i16 {
+        let x = if bits & 0b100000 != 0 { 0 } else { x };
+        let x = if bits & 0b010000 != 0 { !x } else { x };
+        let y = if bits & 0b001000 != 0 { 0 } else { y };
+        let y = if bits & 0b000100 != 0 { !y } else { y };
+        let out = if bits & 0b000010 != 0 { x.wrapping_add(y) } else { x & y };
+        if bits & 0b000001 != 0 { !out } else { out }
+    }
+
+    /// 命令をひとつ実行する。停止している場合は何もせず`false`を返す
+    pub fn step(&mut self) -> bool {
+        if self.halted {
+            return false;
+        }
+        let word = self.rom[self.pc];
+        let pc = self.pc;
+
+        if word & 0x8000 == 0 {
+            self.a = word as i16;
+            self.pc += 1;
+        } else {
+            let y = if word & 0x1000 != 0 { self.ram[self.address()] } else { self.a };
+            let out = Emulator::alu(self.d, y, (word >> 6) & 0b111111);
+            let jump = match word & 0b111 {
+                0 => false,
+                1 => out > 0,
+                2 => out == 0,
+                3 => out >= 0,
+                4 => out < 0,
+                5 => out != 0,
+                6 => out <= 0,
+                _ => true,
+            };
+            // Mへの書き込みとジャンプ先は、書き込む前のAの値を使う
+            let target = self.address();
+            let jump_target = self.a as u16 as usize;
+            if word & 0b001000 != 0 {
+                self.ram[target] = out;
+            }
+            if word & 0b100000 != 0 {
+                self.a = out;
+            }
+            if word & 0b010000 != 0 {
+                self.d = out;
+            }
+            if jump {
+                self.pc = jump_target;
+                // 直前の@命令で自分自身へ戻るループ
+                if self.pc + 1 == pc && self.rom[self.pc] as usize == self.pc {
+                    self.halted = true;
+                }
+            } else {
+                self.pc += 1;
+            }
+        }
+
+        if self.pc >= self.rom.len() {
+            self.halted = true;
+        }
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hack;
+
+    #[test]
+    fn test_emulator() {
+        // RAM[0] + RAM[1] を RAM[2] に書き、停止する
+        let rom = hack::assemble(concat!(
+            "@R0 \n", "D=M \n", "@R1 \n", "D=D+M \n", "@R2 \n", "M=D \n",
+            "(END) \n", "@END \n", "0;JMP \n",
+        )).unwrap();
+        let mut emulator = Emulator::new(rom);
+        emulator.ram[0] = 3;
+        emulator.ram[1] = -5;
+        let mut steps = 0;
+        while emulator.step() {
+            steps += 1;
+        }
+        assert!(emulator.is_halted());
+        assert_eq!(emulator.ram[2], -2);
+        assert_eq!(steps, 8);
+    }
+
+    #[test]
+    fn test_emulator_jump_with_a_destination() {
+        // AM=M-1;JMPは、Aを書き換える前のAの値（@3）へジャンプする
+        let rom = hack::assemble(concat!(
+            "@3 \n", "AM=M-1;JMP \n", "D=1 \n", "(END) \n", "@END \n", "0;JMP \n",
+        )).unwrap();
+        let mut emulator = Emulator::new(rom);
+        emulator.ram[3] = 10;
+        emulator.step();
+        emulator.step();
+        assert_eq!(emulator.pc, 3);
+        assert_eq!((emulator.a, emulator.ram[3]), (9, 9));
+        assert_eq!(emulator.d, 0);
+    }
+}
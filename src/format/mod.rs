@@ -0,0 +1,106 @@
+//! vmファイルの整形。
+//! コメントを残したまま、次の規則で書き直す
+//! * コマンドの単語の間は空白１つにする
+//! * functionコマンドの後のコマンドは4文字字下げする
+//! * 行末のコメントはコマンドの後に空白１つを空けて書く
+//! * コメントだけの行は次のコマンドと同じ字下げにする
+//! * 連続する空行は１行にまとめ、ファイルの最初と最後の空行は削除する
+//! * 行末の空白を削除し、改行は`\n`にしてファイルの最後にも付ける
+
+use crate::parser::cst::SyntaxTree;
+
+/// 関数の中の字下げ
+const INDENT: &str = "    ";
+
+/// vmファイルの内容を整形する
+pub fn format(source: &str) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    // 次のコマンドの字下げが決まるまで保留しているコメントだけの行
+    let mut comments: Vec<&str> = Vec::new();
+    let mut in_function = false;
+    let mut blank = false;
+
+    let tree = SyntaxTree::parse(source);
+    for line in tree.lines() {
+        let comment = line.comment.as_deref().unwrap_or("").trim_end();
+        if line.is_blank() {
+            blank = !lines.is_empty() || !comments.is_empty();
+            if blank && !comments.is_empty() {
+                let indent = if in_function { INDENT } else { "" };
+                lines.extend(comments.drain(..).map(|c| format!("{}{}", indent, c)));
+            }
+            continue;
+        }
+
+        if blank {
+            lines.push(String::new());
+            blank = false;
+        }
+        if !line.is_command() {
+            comments.push(comment);
+            continue;
+        }
+
+        let function = line.tokens[0].text == "function";
+        in_function |= function;
+        let indent = if in_function && !function { INDENT } else { "" };
+        lines.extend(comments.drain(..).map(|c| format!("{}{}", indent, c)));
+
+        let mut formatted = format!("{}{}", indent, line.code());
+        if !comment.is_empty() {
+            formatted += " ";
+            formatted += comment;
+        }
+        lines.push(formatted);
+    }
+
+    let indent = if in_function { INDENT } else { "" };
+    lines.extend(comments.drain(..).map(|c| format!("{}{}", indent, c)));
+
+    let mut result = String::new();
+    for line in lines {
+        result += &line;
+        result += "\n";
+    }
+    result
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::format;
+
+    #[test]
+    fn test_format() {
+        let source = concat!(
+            "\n",
+            "// Main.vm  \r\n",
+            "push   constant 1//one\n",
+            "\n",
+            "\n",
+            "  // main function\n",
+            "function  Main.main 0\n",
+            "push constant 2    // two\n",
+            "label\tLOOP\n",
+            "  // loop\n",
+            "\n",
+            "goto LOOP\n",
+            "\n",
+        );
+        let expected = concat!(
+            "// Main.vm\n",
+            "push constant 1 //one\n",
+            "\n",
+            "// main function\n",
+            "function Main.main 0\n",
+            "    push constant 2 // two\n",
+            "    label LOOP\n",
+            "    // loop\n",
+            "\n",
+            "    goto LOOP\n",
+        );
+        assert_eq!(format(source), expected);
+        // 整形済みのものは変わらない
+        assert_eq!(format(expected), expected);
+    }
+}
@@ -0,0 +1,225 @@
+//! Hackアセンブリコードを扱うための関数群
+
+use std::collections::{HashMap, HashSet};
+
+/// コメントと両端の空白を除いた行の内容を返す。
+/// 空行やコメントだけの行の場合は`None`を返す
+pub fn instruction(line: &str) -> Option<&str> {
+    let line = match line.find("//") {
+        Some(i) => &line[..i],
+        None => line,
+    };
+    let line = line.trim();
+
+    if line.is_empty() {
+        None
+    } else {
+        Some(line)
+    }
+}
+
+/// ROMに置かれる命令（A命令かC命令）の行かどうか。
+/// ラベル宣言、コメント、空行は命令ではない
+pub fn is_instruction(line: &str) -> bool {
+    match instruction(line) {
+        Some(l) => !l.starts_with('('),
+        None => false,
+    }
+}
+
+/// アセンブリコードに含まれる命令の数を返す
+pub fn count_instructions(asm: &str) -> usize {
+    asm.lines().filter(|l| is_instruction(l)).count()
+}
+
+/// 定義済みのsymbol
+const PREDEFINED: [&str; 23] = [
+    "SP", "LCL", "ARG", "THIS", "THAT", "SCREEN", "KBD",
+    "R0", "R1", "R2", "R3", "R4", "R5", "R6", "R7",
+    "R8", "R9", "R10", "R11", "R12", "R13", "R14", "R15",
+];
+
+/// アセンブラが変数としてRAMを割り当てるsymbolを、割り当てる順番に返す。
+/// 変数はA命令で使われている、数値でも定義済みのsymbolでもラベルでもない
+/// symbolで、最初に使われた順にRAM[16]から割り当てられる
+pub fn variables(asm: &str) -> Vec<&str> {
+    let labels: HashSet<&str> = asm.lines()
+        .filter_map(instruction)
+        .filter_map(|l| l.strip_prefix('(')?.strip_suffix(')'))
+        .collect();
+
+    let mut seen = HashSet::new();
+    let mut variables = Vec::new();
+    for symbol in asm.lines().filter_map(instruction)
+                     .filter_map(|l| l.strip_prefix('@')) {
+        if symbol.starts_with(|c: char| c.is_ascii_digit())
+            || PREDEFINED.contains(&symbol) || labels.contains(symbol) {
+            continue;
+        }
+        if seen.insert(symbol) {
+            variables.push(symbol);
+        }
+    }
+    variables
+}
+
+/// 定義済みのsymbolのアドレス
+fn predefined_address(symbol: &str) -> Option<u16> {
+    let address = match symbol {
+        "SP" => 0,
+        "LCL" => 1,
+        "ARG" => 2,
+        "THIS" => 3,
+        "THAT" => 4,
+        "SCREEN" => 16384,
+        "KBD" => 24576,
+        _ => symbol.strip_prefix('R')?.parse().ok().filter(|n| *n < 16)?,
+    };
+    Some(address)
+}
+
+/// C命令のcompの部分のビット（aビットを含む7ビット）
+fn comp_bits(comp: &str) -> Option<u16> {
+    let (comp, a) = if comp.contains('M') {
+        (comp.replace('M', "A"), 1 << 6)
+    } else {
+        (comp.to_string(), 0)
+    };
+    let bits = match comp.as_str() {
+        "0" => 0b101010,
+        "1" => 0b111111,
+        "-1" => 0b111010,
+        "D" => 0b001100,
+        "A" => 0b110000,
+        "!D" => 0b001101,
+        "!A" => 0b110001,
+        "-D" => 0b001111,
+        "-A" => 0b110011,
+        "D+1" | "1+D" => 0b011111,
+        "A+1" | "1+A" => 0b110111,
+        "D-1" => 0b001110,
+        "A-1" => 0b110010,
+        "D+A" | "A+D" => 0b000010,
+        "D-A" => 0b010011,
+        "A-D" => 0b000111,
+        "D&A" | "A&D" => 0b000000,
+        "D|A" | "A|D" => 0b010101,
+        _ => return None,
+    };
+    Some(a | bits)
+}
+
+/// C命令を機械語にする
+fn c_instruction(line: &str) -> Option<u16> {
+    let (dest, rest) = match line.split_once('=') {
+        Some((dest, rest)) => (dest.trim(), rest),
+        None => ("", line),
+    };
+    let (comp, jump) = match rest.split_once(';') {
+        Some((comp, jump)) => (comp.trim(), jump.trim()),
+        None => (rest.trim(), ""),
+    };
+
+    let mut d = 0;
+    for c in dest.chars() {
+        d |= match c {
+            'A' => 0b100,
+            'D' => 0b010,
+            'M' => 0b001,
+            _ => return None,
+        };
+    }
+    let j = match jump {
+        "" => 0,
+        "JGT" => 1,
+        "JEQ" => 2,
+        "JGE" => 3,
+        "JLT" => 4,
+        "JNE" => 5,
+        "JLE" => 6,
+        "JMP" => 7,
+        _ => return None,
+    };
+    Some(0b111 << 13 | comp_bits(comp)? << 6 | d << 3 | j)
+}
+
+/// ラベル宣言とそのアドレス（次の命令のアドレス）
+pub fn labels(asm: &str) -> Vec<(&str, usize)> {
+    let mut labels = Vec::new();
+    let mut address = 0;
+    for line in asm.lines().filter_map(instruction) {
+        match line.strip_prefix('(').and_then(|l| l.strip_suffix(')')) {
+            Some(label) => labels.push((label, address)),
+            None => address += 1,
+        }
+    }
+    labels
+}
+
+/// アセンブリコードを機械語にする。
+/// 変数はアセンブラと同じように最初に使われた順にRAM[16]から割り当てる
+pub fn assemble(asm: &str) -> Result<Vec<u16>, String> {
+    let labels: HashMap<&str, u16> = labels(asm).into_iter()
+        .map(|(l, a)| (l, a as u16))
+        .collect();
+    let variables: HashMap<&str, u16> = variables(asm).into_iter().enumerate()
+        .map(|(i, v)| (v, 16 + i as u16))
+        .collect();
+
+    let mut code = Vec::new();
+    for line in asm.lines().filter_map(instruction).filter(|l| !l.starts_with('(')) {
+        let word = match line.strip_prefix('@') {
+            Some(value) if value.starts_with(|c: char| c.is_ascii_digit()) => {
+                value.parse().ok().filter(|n| *n <= 32767)
+            },
+            Some(symbol) => predefined_address(symbol)
+                .or_else(|| labels.get(symbol).copied())
+                .or_else(|| variables.get(symbol).copied()),
+            None => c_instruction(line),
+        };
+        match word {
+            Some(w) => code.push(w),
+            None => return Err(format!("{} は正しい命令ではありません", line)),
+        }
+    }
+    Ok(code)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_instruction() {
+        assert!(is_instruction("@SP "));
+        assert!(is_instruction("M=M+1 // inc"));
+        assert!(!is_instruction("(LOOP) "));
+        assert!(!is_instruction("// [start] add "));
+        assert!(!is_instruction("   "));
+        assert_eq!(count_instructions("// a\n@SP \n(LOOP) \nM=M+1 // b\n\n"), 2);
+    }
+
+    #[test]
+    fn test_variables() {
+        let asm = concat!(
+            "@Main.1 \n", "@SP \n", "@R13 \n", "@100 \n",
+            "@Main.0 \n", "@LOOP \n", "@Main.1 \n", "(LOOP) \n",
+        );
+        assert_eq!(variables(asm), vec!["Main.1", "Main.0"]);
+    }
+
+    #[test]
+    fn test_assemble() {
+        let asm = concat!(
+            "@2 \n", "D=A \n", "@R13 \n", "M=D \n", "(LOOP) \n",
+            "@Main.0 \n", "AM=M-1 \n", "D;JGT \n", "@LOOP \n", "0;JMP \n",
+        );
+        assert_eq!(assemble(asm).unwrap(), vec![
+            2, 0b1110110000010000, 13, 0b1110001100001000,
+            16, 0b1111110010101000, 0b1110001100000001, 4, 0b1110101010000111,
+        ]);
+        assert!(assemble("D=X \n").is_err());
+        assert_eq!(labels(asm), vec![("LOOP", 4)]);
+    }
+}
@@ -0,0 +1,93 @@
+//! ラベルと関数名の検査。
+//! VMの仕様では識別子は英字、数字、`_`、`.`、`$`、`:`からなり、数字で始まらない。
+//! Hackアセンブリのsymbolも同じ規則なので、この規則に合わない名前をそのまま
+//! アセンブリコードに書くとアセンブラでエラーになる。
+//!
+//! `IdentifierPolicy::Escape`の場合は規則に合わない名前をエラーにせず、
+//! `escape`関数で規則に合う名前に変換して使う
+
+/// 規則に合わない識別子の扱い
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IdentifierPolicy {
+    /// エラーにする
+    Strict,
+    /// `escape`関数で変換する
+    Escape,
+}
+
+/// 識別子に使える文字かどうか
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$' | ':')
+}
+
+/// nameが識別子の規則に合っているか調べる。
+/// 合っていない場合は最初の問題の位置と理由を返す
+pub fn validate(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("識別子が空です".to_string());
+    }
+    for (i, c) in name.chars().enumerate() {
+        if !is_identifier_char(c) {
+            return Err(format!("{} の{}文字目 '{}' は識別子に使えない文字です",
+                               name, i + 1, c));
+        }
+    }
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        return Err(format!("{} は数字で始まっています", name));
+    }
+    Ok(())
+}
+
+/// nameを識別子の規則に合う名前に変換する。
+/// 使えない文字と先頭の数字はUTF-8の各バイトを`:XX`（XXは16進数）にする。
+/// 別の名前が同じ名前にならないように、`:`も`:3A`にする。
+/// それ以外の文字は変えないので、`:`を含まない正しい識別子はそのままになる
+/// ```
+/// use vmtranslator::identifier::escape;
+///
+/// assert_eq!(escape("Main.loop"), "Main.loop");
+/// assert_eq!(escape("1st(x)"), ":31st:28x:29");
+/// assert_eq!(escape("a:b"), "a:3Ab");
+/// ```
+pub fn escape(name: &str) -> String {
+    let mut escaped = String::new();
+    for (i, c) in name.chars().enumerate() {
+        let leading_digit = i == 0 && c.is_ascii_digit();
+        if is_identifier_char(c) && c != ':' && !leading_digit {
+            escaped.push(c);
+        } else {
+            let mut buf = [0; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                escaped.push_str(&format!(":{:02X}", b));
+            }
+        }
+    }
+    escaped
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(validate("Main.main$LOOP:1_a"), Ok(()));
+        assert_eq!(validate("(LOOP)"),
+                   Err("(LOOP) の1文字目 '(' は識別子に使えない文字です".to_string()));
+        assert_eq!(validate("a@b"),
+                   Err("a@b の2文字目 '@' は識別子に使えない文字です".to_string()));
+        assert_eq!(validate("1abc"), Err("1abc は数字で始まっています".to_string()));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a-b"), "a:2Db");
+        assert_eq!(escape("é"), ":C3:A9");
+        assert_eq!(escape("12"), ":312");
+        for name in ["a-b", "1abc", "é", "a:b", "(x)"] {
+            assert_eq!(validate(&escape(name)), Ok(()));
+        }
+        assert_ne!(escape("a:2Db"), escape("a-b"));
+    }
+}
@@ -0,0 +1,181 @@
+//! パースして検査したプログラムをJSONで書き出す（`--emit ir`）。
+//! 採点や可視化のツールがパーサーを実装し直さなくてよいように、ファイル、関数、
+//! コマンドの種類と引数、行番号と、変換するときに`SymbolManager`が付ける
+//! symbolの名前を書く
+//!
+//! ```text
+//! {"version":1,"files":[{"name":"Main","functions":[
+//!   {"name":"Main.main","locals":0,"line":1,"symbol":"symbol-function-Main.main",
+//!    "commands":[{"line":2,"command":"push constant 1","kind":"push",
+//!                 "segment":"constant","index":1}, ...]}]}]}
+//! ```
+//! 最初のfunctionコマンドより前のコマンドは、nameとsymbolが`null`の関数になる
+
+use crate::{Input, TranslateOptions, Diagnostics};
+use crate::code_writer::SymbolManager;
+use crate::json::Json;
+use crate::labels;
+use crate::program::{VmFile, Command, Segment, Op};
+use crate::unit;
+
+/// コマンドとsymbolの名前
+fn command(sm: &mut SymbolManager, file: &str, line: usize, command: &Command) -> Json {
+    let mut pairs = vec![
+        ("line", Json::Number(line as i64)),
+        ("command", Json::string(&command.to_string())),
+    ];
+    match command {
+        Command::Arithmetic(op) => {
+            pairs.push(("kind", Json::string("arithmetic")));
+            pairs.push(("op", Json::string(op.name())));
+            if matches!(op, Op::Eq | Op::Gt | Op::Lt | Op::Ge | Op::Le | Op::Ne) {
+                let (t, f) = sm.get_ifd_labels();
+                pairs.push(("true", Json::string(&t)));
+                pairs.push(("false", Json::string(&f)));
+            }
+            if matches!(op, Op::Mul | Op::Div | Op::Shl | Op::Shr) {
+                pairs.push(("loop", Json::string(&sm.get_loop_symbol())));
+            }
+        },
+        Command::Push(segment, index) | Command::Pop(segment, index) => {
+            let kind = if matches!(command, Command::Push(_, _)) { "push" } else { "pop" };
+            pairs.push(("kind", Json::string(kind)));
+            pairs.push(("segment", Json::string(segment.name())));
+            pairs.push(("index", Json::Number(*index as i64)));
+            if *segment == Segment::Static {
+                pairs.push(("symbol", Json::string(&format!("{}.{}", file, index))));
+            }
+        },
+        Command::Label(label) | Command::Goto(label) | Command::IfGoto(label) => {
+            let kind = match command {
+                Command::Label(_) => "label",
+                Command::Goto(_) => "goto",
+                _ => "if-goto",
+            };
+            pairs.push(("kind", Json::string(kind)));
+            pairs.push(("label", Json::string(label)));
+            pairs.push(("symbol", Json::string(&sm.get_goto_symbol(label))));
+        },
+        Command::Call(function, args) => {
+            pairs.push(("kind", Json::string("call")));
+            pairs.push(("function", Json::string(function)));
+            pairs.push(("args", Json::Number(*args as i64)));
+            pairs.push(("symbol", Json::string(&sm.get_function_symbol(function))));
+            pairs.push(("return_address",
+                        Json::string(&sm.get_return_address_symbol(function))));
+        },
+        Command::Return => pairs.push(("kind", Json::string("return"))),
+        // functionコマンドは関数のJSONに書く
+        Command::Function(_, _) => pairs.push(("kind", Json::string("function"))),
+    }
+    Json::object(pairs)
+}
+
+/// ひとつのファイル。symbolの名前は変換するときと同じ順番で付ける
+fn file(file: &VmFile, options: &TranslateOptions) -> Json {
+    let mut sm = SymbolManager::new();
+    sm.set_naming_scheme(options.naming_scheme);
+    sm.set_identifier_policy(options.identifier_policy);
+    sm.set_file_name(&file.name);
+
+    let functions = file.functions().iter().map(|f| {
+        let (name, symbol) = match f.name {
+            Some(name) => {
+                let symbol = sm.get_function_symbol(name);
+                sm.set_function_name(name);
+                (Json::string(name), Json::string(&symbol))
+            },
+            None => (Json::Null, Json::Null),
+        };
+        let commands = f.body.iter()
+            .map(|s| command(&mut sm, &file.name, s.line, &s.command))
+            .collect();
+        Json::object(vec![
+            ("name", name),
+            ("locals", Json::Number(f.locals as i64)),
+            ("line", Json::Number(f.line as i64)),
+            ("symbol", symbol),
+            ("commands", Json::Array(commands)),
+        ])
+    }).collect();
+
+    Json::object(vec![
+        ("name", Json::string(&file.name)),
+        ("functions", Json::Array(functions)),
+    ])
+}
+
+/// 変換するときと同じようにファイル名、コマンド、ラベルを検査し、
+/// プログラムをJSONにする。エラーがあった場合はすべてのファイルのエラーを集めて返す
+pub fn emit(inputs: &[Input], options: &TranslateOptions) -> Result<Json, Diagnostics> {
+    let diagnostics = unit::check_names(inputs);
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+
+    let mut files = Vec::new();
+    let mut diagnostics = Diagnostics::new();
+    for input in inputs {
+        match VmFile::parse_with(input, options.identifier_policy, options.dialect) {
+            Ok(f) => {
+                diagnostics.extend(labels::check_labels(&f));
+                files.push(f);
+            },
+            Err(e) => diagnostics.extend(e),
+        }
+    }
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+
+    Ok(Json::object(vec![
+        ("version", Json::Number(1)),
+        ("files", Json::Array(files.iter().map(|f| file(f, options)).collect())),
+    ]))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{translate, NamingScheme};
+
+    const SOURCE: &str = concat!(
+        "function Main.main 1\n",
+        "push static 2\n",
+        "label LOOP\n",
+        "eq\n",
+        "if-goto LOOP\n",
+        "call Main.f 1\n",
+        "return\n",
+    );
+
+    #[test]
+    fn test_emit_ir() {
+        let options = TranslateOptions::new().naming_scheme(NamingScheme::Spec);
+        let inputs = [Input::new("Main", SOURCE)];
+        let ir = emit(&inputs, &options).unwrap();
+
+        let function = &ir.get("files").unwrap().as_array().unwrap()[0]
+            .get("functions").unwrap().as_array().unwrap()[0];
+        assert_eq!(function.get("name").unwrap().as_str(), Some("Main.main"));
+        assert_eq!(function.get("locals").unwrap().as_i64(), Some(1));
+        let commands = function.get("commands").unwrap().as_array().unwrap();
+        assert_eq!(commands[0].to_string(), concat!(
+            r#"{"line":2,"command":"push static 2","kind":"push","#,
+            r#""segment":"static","index":2,"symbol":"Main.2"}"#));
+        assert_eq!(commands[2].get("true").unwrap().as_str(), Some("Main.main$cmp.0.true"));
+        assert_eq!(commands[3].get("symbol").unwrap().as_str(), Some("Main.main$LOOP"));
+        assert_eq!(commands[4].get("return_address").unwrap().as_str(),
+                   Some("Main.main$ret.0"));
+
+        // symbolは変換したアセンブリコードと一致する
+        let asm = translate(&inputs, &options.bootstrap(false)).unwrap().asm;
+        for symbol in ["Main.main$cmp.0.false", "Main.main$LOOP", "Main.main$ret.0"] {
+            assert!(asm.contains(&format!("({})", symbol)), "{}", symbol);
+        }
+
+        let e = emit(&[Input::new("Main", "goto NOWHERE\n")], &TranslateOptions::new());
+        assert!(e.is_err());
+    }
+}
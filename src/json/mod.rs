@@ -0,0 +1,318 @@
+//! 他のツールとデータをやりとりするための最小限のJSONの実装。
+//! 数値は整数だけを扱い、読み込んだ小数は切り捨てる
+
+use std::fmt;
+
+/// JSONの値。Objectはキーの順番を保つ
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(i64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// キーと値のリストからObjectを作る
+    pub fn object(pairs: Vec<(&str, Json)>) -> Json {
+        Json::Object(pairs.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    pub fn string(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+
+    /// JSONの文字列を読む
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut reader = Reader { chars: text.chars().collect(), pos: 0 };
+        let value = reader.value()?;
+        reader.skip_whitespace();
+        if reader.pos < reader.chars.len() {
+            return Err(format!("{}文字目: 余分な文字があります", reader.pos + 1));
+        }
+        Ok(value)
+    }
+
+    /// Objectのkeyの値を返す。Objectでない場合やkeyがない場合は`None`
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(pairs) => pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(list) => Some(list),
+            _ => None,
+        }
+    }
+}
+
+/// JSONを読むための状態
+struct Reader {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Reader {
+    fn error<T>(&self, message: &str) -> Result<T, String> {
+        Err(format!("{}文字目: {}", self.pos + 1, message))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    /// 次の文字を読む
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.get(self.pos).copied();
+        self.pos += 1;
+        c
+    }
+
+    fn expect(&mut self, word: &str) -> Result<(), String> {
+        for c in word.chars() {
+            if self.next() != Some(c) {
+                self.pos -= 1;
+                return self.error(&format!("{} が必要です", word));
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.chars.get(self.pos) {
+            Some('n') => self.expect("null").map(|_| Json::Null),
+            Some('t') => self.expect("true").map(|_| Json::Bool(true)),
+            Some('f') => self.expect("false").map(|_| Json::Bool(false)),
+            Some('"') => self.string().map(Json::String),
+            Some('[') => self.array(),
+            Some('{') => self.object(),
+            Some(c) if *c == '-' || c.is_ascii_digit() => self.number(),
+            Some(_) => self.error("値が必要です"),
+            None => self.error("値がありません"),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        while self.chars.get(self.pos)
+                  .is_some_and(|c| c.is_ascii_digit() || "+-.eE".contains(*c)) {
+            self.pos += 1;
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        match text.parse::<i64>() {
+            Ok(n) => Ok(Json::Number(n)),
+            Err(_) => match text.parse::<f64>() {
+                Ok(f) => Ok(Json::Number(f as i64)),
+                Err(_) => self.error(&format!("{} は数値ではありません", text)),
+            },
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.pos += 1; // "
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => {
+                    let c = match self.next() {
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('u') => self.unicode()?,
+                        Some(c) => c,
+                        None => return self.error("文字列が終わっていません"),
+                    };
+                    s.push(c);
+                },
+                Some(c) => s.push(c),
+                None => return self.error("文字列が終わっていません"),
+            }
+        }
+    }
+
+    /// `\u`の後の4桁の16進数を読む
+    fn hex4(&mut self) -> Result<u32, String> {
+        let text: String = self.chars.iter().skip(self.pos).take(4).collect();
+        match u32::from_str_radix(&text, 16) {
+            Ok(n) if text.len() == 4 => {
+                self.pos += 4;
+                Ok(n)
+            },
+            _ => self.error("\\uの後には4桁の16進数が必要です"),
+        }
+    }
+
+    /// `\u`の後を読む。サロゲートペアは次の`\u`と合わせて読む
+    fn unicode(&mut self) -> Result<char, String> {
+        let high = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            self.expect("\\u")?;
+            let low = self.hex4()?;
+            0x10000 + ((high - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff)
+        } else {
+            high
+        };
+        Ok(char::from_u32(code).unwrap_or('\u{fffd}'))
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.pos += 1; // [
+        let mut list = Vec::new();
+        self.skip_whitespace();
+        if self.chars.get(self.pos) == Some(&']') {
+            self.pos += 1;
+            return Ok(Json::Array(list));
+        }
+        loop {
+            list.push(self.value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => {},
+                Some(']') => return Ok(Json::Array(list)),
+                _ => {
+                    self.pos -= 1;
+                    return self.error(", か ] が必要です");
+                },
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.pos += 1; // {
+        let mut pairs = Vec::new();
+        self.skip_whitespace();
+        if self.chars.get(self.pos) == Some(&'}') {
+            self.pos += 1;
+            return Ok(Json::Object(pairs));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.chars.get(self.pos) != Some(&'"') {
+                return self.error("キーが必要です");
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(":")?;
+            pairs.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => {},
+                Some('}') => return Ok(Json::Object(pairs)),
+                _ => {
+                    self.pos -= 1;
+                    return self.error(", か } が必要です");
+                },
+            }
+        }
+    }
+}
+
+/// 文字列をJSONの文字列リテラルとして書く
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    /// 空白を入れずに１行で書く
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write_string(f, s),
+            Json::Array(list) => {
+                write!(f, "[")?;
+                for (i, v) in list.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(pairs) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in pairs.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, k)?;
+                    write!(f, ":{}", v)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::Json;
+
+    #[test]
+    fn test_json_display() {
+        let json = Json::object(vec![
+            ("file", Json::string("Main\"1\"\n")),
+            ("line", Json::Number(-3)),
+            ("list", Json::Array(vec![Json::Null, Json::Bool(true)])),
+            ("empty", Json::Object(Vec::new())),
+        ]);
+        assert_eq!(json.to_string(), concat!(
+            r#"{"file":"Main\"1\"\n","line":-3,"#,
+            r#""list":[null,true],"empty":{}}"#
+        ));
+    }
+
+    #[test]
+    fn test_json_parse() {
+        let text = r#" {"a": [1, -2, 3.7, true, false, null], "b": {"c": "x\ny\u00e9\ud83d\ude00"}, "d": []} "#;
+        let json = Json::parse(text).unwrap();
+        assert_eq!(json.get("a").unwrap().as_array().unwrap()[2].as_i64(), Some(3));
+        assert_eq!(json.get("b").and_then(|b| b.get("c")).and_then(|c| c.as_str()),
+                   Some("x\nyé😀"));
+        assert_eq!(Json::parse(&json.to_string()).unwrap(), json);
+
+        assert!(Json::parse("{\"a\" 1}").is_err());
+        assert!(Json::parse("[1,]").is_err());
+        assert!(Json::parse("\"abc").is_err());
+        assert!(Json::parse("1 2").is_err());
+    }
+}
@@ -0,0 +1,146 @@
+//! ラベルの解決。
+//! VMの仕様ではラベルのスコープはそれを定義した関数の中だけなので、
+//! 関数ごとにラベルの定義と参照を調べ、次のものをエラーにする
+//! * 同じ関数の中で同じラベルを２回以上定義している
+//! * goto、if-gotoのラベルが同じ関数の中で定義されていない
+//!   （他の関数で定義されている場合は、関数をまたいだジャンプとして報告する）
+//!
+//! 最初のfunctionコマンドより前のコマンドは、ファイルごとのスコープに属する
+
+use std::collections::HashMap;
+
+use crate::{Diagnostic, Diagnostics};
+use crate::program::{VmFile, Command};
+
+/// ひとつのスコープ（関数もしくはファイルの先頭部分）
+struct Scope<'a> {
+    /// 関数名。最初のfunctionコマンドより前の部分は`None`
+    function: Option<&'a str>,
+    /// ラベル名と定義した行番号
+    labels: HashMap<&'a str, usize>,
+    /// 参照しているラベルと行番号
+    jumps: Vec<(&'a str, usize)>,
+    /// ２回目以降に定義したラベルと、最初に定義した行番号、その行番号
+    duplicates: Vec<(&'a str, usize, usize)>,
+}
+
+impl<'a> Scope<'a> {
+    fn new(function: Option<&'a str>) -> Scope<'a> {
+        Scope {
+            function,
+            labels: HashMap::new(),
+            jumps: Vec::new(),
+            duplicates: Vec::new(),
+        }
+    }
+}
+
+/// ファイルをスコープに分ける
+fn scopes(file: &VmFile) -> Vec<Scope<'_>> {
+    let mut scopes = Vec::new();
+    let mut current = Scope::new(None);
+    for s in &file.statements {
+        match &s.command {
+            Command::Function(name, _) => {
+                scopes.push(std::mem::replace(&mut current, Scope::new(Some(name))));
+            },
+            Command::Label(label) => {
+                match current.labels.get(label.as_str()) {
+                    Some(first) => {
+                        let first = *first;
+                        current.duplicates.push((label, first, s.line));
+                    },
+                    None => {
+                        current.labels.insert(label, s.line);
+                    },
+                }
+            },
+            Command::Goto(label) | Command::IfGoto(label) => {
+                current.jumps.push((label, s.line));
+            },
+            _ => {},
+        }
+    }
+    scopes.push(current);
+
+    scopes
+}
+
+/// ファイルのラベルを検査する。問題がなければ空のDiagnosticsを返す
+pub fn check_labels(file: &VmFile) -> Diagnostics {
+    let mut diagnostics = Diagnostics::new();
+
+    let scopes = scopes(file);
+    for scope in &scopes {
+        for (label, first, line) in &scope.duplicates {
+            diagnostics.push(Diagnostic::at(&file.name, *line, &format!(
+                "label {} は{}行目で既に定義されています", label, first)));
+        }
+
+        for (label, line) in &scope.jumps {
+            if scope.labels.contains_key(label) {
+                continue;
+            }
+
+            let other = scopes.iter()
+                              .find(|s| s.labels.contains_key(label))
+                              .map(|s| s.function.unwrap_or("(関数の外)"));
+            let message = match other {
+                Some(function) => format!(
+                    "label {} は{}のラベルです。関数をまたいでジャンプすることは\
+                     できません", label, function),
+                None => format!("label {} は定義されていません", label),
+            };
+            diagnostics.push(Diagnostic::at(&file.name, *line, &message));
+        }
+    }
+
+    diagnostics
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::check_labels;
+    use crate::Input;
+    use crate::program::VmFile;
+
+    fn check(source: &str) -> Vec<(usize, String)> {
+        let file = VmFile::parse(&Input::new("Main", source)).unwrap();
+        check_labels(&file).iter()
+            .map(|d| (d.line.unwrap(), d.message.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_check_labels_ok() {
+        assert!(check(concat!(
+            "label TOP\n",
+            "goto TOP\n",
+            "function A 0\n",
+            "goto END\n",
+            "label END\n",
+            "function B 0\n",
+            "label END\n",
+            "if-goto END\n",
+        )).is_empty());
+    }
+
+    #[test]
+    fn test_check_labels_errors() {
+        let d = check(concat!(
+            "function A 0\n",
+            "label L\n",
+            "label L\n",
+            "goto NOWHERE\n",
+            "function B 0\n",
+            "if-goto L\n",
+        ));
+        assert_eq!(d.len(), 3);
+        assert_eq!(d[0].0, 3);
+        assert!(d[0].1.contains("2行目"));
+        assert_eq!(d[1], (4, "label NOWHERE は定義されていません".to_string()));
+        assert_eq!(d[2].0, 6);
+        assert!(d[2].1.contains("Aのラベル"));
+    }
+}
@@ -0,0 +1,610 @@
+//! VMコマンドをHackアセンブリコードへ変換するライブラリ
+//!
+//! ```
+//! use vmtranslator::{translate, Input, TranslateOptions};
+//!
+//! let inputs = vec![Input::new("Main", "push constant 1\npush constant 2\nadd\n")];
+//! let options = TranslateOptions::new().bootstrap(false);
+//! let output = translate(&inputs, &options).unwrap();
+//! assert!(output.asm.contains("@SP"));
+//! ```
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use program::VmFile;
+
+pub mod parser;
+pub mod code_writer;
+pub mod diagnostics;
+pub mod options;
+pub mod bootstrap;
+pub mod watch;
+pub mod unit;
+pub mod cache;
+pub mod hack;
+pub mod json;
+pub mod source_map;
+pub mod program;
+pub mod labels;
+pub mod identifier;
+pub mod statics;
+pub mod format;
+pub mod lint;
+pub mod stack;
+pub mod cfg;
+pub mod lsp;
+pub mod vm;
+pub mod debugger;
+pub mod emulator;
+pub mod profile;
+pub mod size;
+pub mod ir;
+pub mod bytecode;
+mod optimizer;
+
+pub use parser::{Parser, CommandType};
+pub use code_writer::{CodeWriter, CommentStyle, NamingScheme};
+pub use diagnostics::{Diagnostic, Diagnostics};
+pub use options::{TranslateOptions, OptLevel};
+pub use identifier::IdentifierPolicy;
+pub use program::Dialect;
+pub use cache::Cache;
+pub use bootstrap::Bootstrap;
+pub use source_map::SourceMap;
+pub use statics::StaticMap;
+pub use size::SizeReport;
+
+/// 変換するひとつのvmファイル。
+/// nameはstaticセグメントのシンボル名に使われるファイル名（拡張子なし）
+#[derive(Debug, Clone, PartialEq)]
+pub struct Input {
+    pub name: String,
+    pub source: String,
+}
+
+impl Input {
+    pub fn new(name: &str, source: &str) -> Input {
+        Input {
+            name: name.to_string(),
+            source: source.to_string(),
+        }
+    }
+
+    /// vmファイルを読み込む。nameは`unit_name`でパスから決める
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Input, Diagnostic> {
+        let path = path.as_ref();
+        let name = unit_name(path)?;
+        let source = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(_) => return Err(Diagnostic::new(
+                &format!("{}を開けません", path.display())))
+        };
+
+        Ok(Input::new(&name, &source))
+    }
+}
+
+/// vmファイルのパスから変換単位の名前（staticセグメントのシンボル名に使う
+/// ファイル名）を返す。名前はディレクトリと最後の拡張子を除いたもので、
+/// `dir/a.b.vm`は`a.b`になる。
+/// Windowsの区切り文字`\`はどのOSでも区切り文字として扱う
+pub fn unit_name(path: &Path) -> Result<String, Diagnostic> {
+    let stem = match path.file_stem().map(|s| s.to_str()) {
+        Some(Some(stem)) => stem,
+        Some(None) => return Err(Diagnostic::new(&format!(
+            "{} のファイル名がUTF-8ではありません", path.display()))),
+        None => return Err(Diagnostic::new(&format!(
+            "{} にファイル名がありません", path.display()))),
+    };
+
+    let name = stem.rsplit('\\').next().unwrap_or(stem);
+    Ok(name.to_string())
+}
+
+/// 変換の結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct Output {
+    /// Hackアセンブリコード
+    pub asm: String,
+    /// 命令のアドレスから元のVMコマンドを調べるためのソースマップ
+    pub source_map: SourceMap,
+    /// static変数に割り当てられるRAMのアドレス
+    pub statics: StaticMap,
+    /// 関数、ファイル、VMコマンドの種類ごとの命令数
+    pub size: SizeReport,
+}
+
+impl Output {
+    /// Hackの命令数を返す。コメント、空行、ラベル宣言は数えない
+    pub fn instruction_count(&self) -> usize {
+        hack::count_instructions(&self.asm)
+    }
+}
+
+/// vmコードを変換してCodeWriterへ保存する。エラーはすべて関数の外に投げ捨てる
+pub fn vm_to_asm<R, W>(p: &mut Parser<R>, cw: &mut CodeWriter<W>)
+    -> Result<(), String> where R: Read,
+                                W: Write {
+    while p.has_more_commands() {
+        p.advance();
+        vm_command_to_asm(p, cw)?;
+    }
+    Ok(())
+}
+
+/// 現コマンドの第２引数を返す。ない場合はエラーを返す
+fn required_arg2<R: Read>(p: &Parser<R>) -> Result<isize, String> {
+    match p.arg2() {
+        Some(n) => Ok(n),
+        None => Err(format!("{} の引数が正しくありません",
+                            p.arg1().unwrap_or_default()))
+    }
+}
+
+/// Parserの現コマンドを変換してCodeWriterへ保存する
+pub(crate) fn vm_command_to_asm<R, W>(p: &Parser<R>, cw: &mut CodeWriter<W>)
+    -> Result<(), String> where R: Read,
+                                W: Write {
+    match p.command_type() {
+        CommandType::PUSH => {
+            cw.write_push_pop("push", &p.arg1().unwrap(), required_arg2(p)?)?;
+        },
+        CommandType::POP => {
+            cw.write_push_pop("pop", &p.arg1().unwrap(), required_arg2(p)?)?;
+        },
+        CommandType::ARITHMETIC => {
+            cw.write_arithmetic(p.arg1().unwrap().as_str())?;
+        },
+        CommandType::LABEL => cw.write_label(&p.arg1().unwrap())?,
+        CommandType::GOTO => cw.write_goto(&p.arg1().unwrap())?,
+        CommandType::IF => cw.write_if_goto(&p.arg1().unwrap())?,
+        CommandType::FUNCTION => {
+            cw.write_function(&p.arg1().unwrap(), required_arg2(p)? as usize)?
+        },
+        CommandType::RETURN => cw.write_return()?,
+        CommandType::CALL => {
+            cw.write_call(&p.arg1().unwrap(), required_arg2(p)? as usize)?
+        },
+        CommandType::None => return Err(format!("{} は無効なコマンドです",
+                                                p.arg1().unwrap())),
+    }
+    Ok(())
+}
+
+/// pathからvmファイルのリストを取得する
+pub fn get_f_list(vm_path: &str) -> Result<Vec<String>, String> {
+    // ファイル名とFile構造体のリスト
+    let mut f_list: Vec<String> = Vec::new();
+    let metadata = match fs::metadata(vm_path) {
+        Ok(m) => m,
+        Err(_) => return Err(format!("'{}' is not exist.", vm_path))
+    };
+
+    if metadata.is_file() {
+        f_list.push(vm_path.to_string());
+        return Ok(f_list)
+    }
+
+    let entries = match fs::read_dir(vm_path) {
+        Ok(entries) => entries,
+        Err(_) => return Err(format!("can't read '{}'.", vm_path))
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path.is_file() || path.extension().is_none_or(|e| e != "vm") {
+            continue;
+        }
+        match path.to_str() {
+            Some(filename) => f_list.push(filename.to_string()),
+            None => return Err(format!("'{}' is not UTF-8.", path.display()))
+        }
+    }
+
+    if f_list.is_empty() {
+        return Err(format!("There isn't vm files in '{}'.", vm_path));
+    }
+    // ディレクトリ内の順番に依存しないように並べ替える
+    f_list.sort();
+
+    Ok(f_list)
+}
+
+/// ひとつのファイルを読み込む。
+/// 拡張子が.vmbの場合はバイトコードとして読み込み、含まれるファイルをテキストに戻す
+pub(crate) fn load_file(path: &str) -> Result<Vec<Input>, Diagnostic> {
+    if Path::new(path).extension().is_some_and(|e| e == "vmb") {
+        let bytes = fs::read(path)
+            .map_err(|_| Diagnostic::new(&format!("{}を開けません", path)))?;
+        let program = bytecode::decode(&bytes)
+            .map_err(|e| Diagnostic::new(&format!("{}: {}", path, e)))?;
+        return Ok(program.files.iter().map(VmFile::to_input).collect());
+    }
+
+    Ok(vec![Input::from_path(path)?])
+}
+
+/// vm_pathにあるvmファイルをすべて読み込む。
+/// vm_pathの拡張子が.vmbの場合はバイトコードとして読み込み、テキストに戻す
+pub fn load_inputs(vm_path: &str) -> Result<Vec<Input>, Diagnostics> {
+    let f_list = get_f_list(vm_path).map_err(|e| Diagnostic::new(&e))?;
+
+    let mut inputs = Vec::new();
+    for filename in f_list {
+        inputs.extend(load_file(&filename)?);
+    }
+
+    Ok(inputs)
+}
+
+/// vmファイルのリストをひとつのアセンブリコードへ変換する。
+/// エラーがあった場合はすべてのファイルのエラーを集めて返す
+pub fn translate(inputs: &[Input], options: &TranslateOptions)
+    -> Result<Output, Diagnostics>
+{
+    translate_with(inputs, options, None)
+}
+
+/// `translate`と同じだが、変更のないファイルはキャッシュにある変換結果を使う
+pub fn translate_cached(inputs: &[Input], options: &TranslateOptions,
+                        cache: &mut Cache) -> Result<Output, Diagnostics>
+{
+    translate_with(inputs, options, Some(cache))
+}
+
+fn translate_with(inputs: &[Input], options: &TranslateOptions,
+                  cache: Option<&mut Cache>) -> Result<Output, Diagnostics>
+{
+    let diagnostics = unit::check_names(inputs);
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+
+    let bootstrap = unit::bootstrap(options)?;
+
+    let fragments = if options.comment_style == CommentStyle::Verbose {
+        // コメントに命令のアドレスを書くので、前のファイルの命令の数が
+        // わかってから次のファイルを変換する
+        let base = hack::count_instructions(&bootstrap.asm);
+        translate_sequential(inputs, options, cache, base)?
+    } else {
+        translate_parallel(inputs, options, cache)?
+    };
+
+    let output = unit::link(&bootstrap, &fragments);
+    output.statics.check()?;
+    if options.rom_limit {
+        output.size.check()?;
+    }
+    if options.rom_limit && output.size.total >= size::ROM_SIZE {
+        // 最後の命令の後のラベルはアドレスが32768になる
+        size::check_labels(&output.asm)?;
+    }
+    Ok(output)
+}
+
+/// 各ファイルを順番に、前のファイルの命令の次のアドレスから変換する
+fn translate_sequential(inputs: &[Input], options: &TranslateOptions,
+                        mut cache: Option<&mut Cache>, mut base: usize)
+    -> Result<Vec<unit::Fragment>, Diagnostics>
+{
+    let mut diagnostics = Diagnostics::new();
+    let mut fragments = Vec::new();
+    for input in inputs {
+        let key = Cache::key(input, options, base);
+        let result = match cache.as_mut().and_then(|c| c.get(key, &input.name)) {
+            Some(fragment) => Ok(fragment),
+            None => unit::translate_unit_at(input, options, base),
+        };
+
+        match result {
+            Ok(fragment) => {
+                if let Some(c) = cache.as_mut() {
+                    c.insert(key, &fragment);
+                }
+                base += fragment.spans.iter().map(|s| s.size).sum::<usize>();
+                fragments.push(fragment);
+            },
+            Err(e) => diagnostics.extend(e),
+        }
+    }
+
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+    Ok(fragments)
+}
+
+/// キャッシュにないファイルを`TranslateOptions::jobs`個のスレッドで変換する
+fn translate_parallel(inputs: &[Input], options: &TranslateOptions,
+                      mut cache: Option<&mut Cache>)
+    -> Result<Vec<unit::Fragment>, Diagnostics>
+{
+    // キャッシュにないファイルだけを変換する
+    let keys: Vec<u64> = match cache {
+        Some(_) => inputs.iter().map(|i| Cache::key(i, options, 0)).collect(),
+        None => Vec::new(),
+    };
+    let mut cached: Vec<Option<unit::Fragment>> = match cache.as_mut() {
+        Some(c) => inputs.iter().zip(&keys)
+                         .map(|(input, key)| c.get(*key, &input.name))
+                         .collect(),
+        None => vec![None; inputs.len()],
+    };
+    let misses: Vec<usize> = (0..inputs.len())
+        .filter(|i| cached[*i].is_none())
+        .collect();
+    let targets: Vec<&Input> = misses.iter().map(|i| &inputs[*i]).collect();
+    let results = unit::translate_units(&targets, options, options.jobs);
+
+    let mut diagnostics = Diagnostics::new();
+    for (i, result) in misses.into_iter().zip(results) {
+        match result {
+            Ok(fragment) => {
+                if let Some(c) = cache.as_mut() {
+                    c.insert(keys[i], &fragment);
+                }
+                cached[i] = Some(fragment);
+            },
+            Err(e) => diagnostics.extend(e),
+        }
+    }
+
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+    Ok(cached.into_iter().flatten().collect())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_translate() {
+        let inputs = vec![Input::new("Main", "push constant 7\npop static 0\n")];
+        let options = TranslateOptions::new().comment_style(CommentStyle::None);
+        let asm = translate(&inputs, &options).unwrap().asm;
+
+        assert!(asm.starts_with("@256 \n"));
+        assert!(asm.contains("@symbol-function-Sys.init \n"));
+        assert!(asm.contains("@Main.0 \n"));
+        assert!(!asm.contains("//"));
+    }
+
+    #[test]
+    fn test_translate_errors() {
+        let inputs = vec![
+            Input::new("A", "push constant 1\nfoo\n"),
+            Input::new("B", "\npush nowhere 1\n"),
+        ];
+        let d = translate(&inputs, &TranslateOptions::new()).unwrap_err();
+        let d: Vec<&Diagnostic> = d.iter().collect();
+
+        assert_eq!(d.len(), 2);
+        assert_eq!(d[0].file.as_deref(), Some("A"));
+        assert_eq!(d[0].line, Some(2));
+        assert_eq!(d[1].file.as_deref(), Some("B"));
+        assert_eq!(d[1].line, Some(2));
+    }
+
+    #[test]
+    fn test_translate_opt_level() {
+        let inputs = vec![Input::new("Main", concat!(
+            "push constant 7\n",
+            "pop static 0\n",
+            "push constant 3\n",
+            "push constant 4\n",
+            "add\n",
+            "pop temp 2\n",
+            "push static 0\n",
+            "push temp 2\n",
+            "sub\n",
+            "pop pointer 1\n",
+            "push constant 5\n",
+        ))];
+        let o0 = TranslateOptions::new().bootstrap(false);
+        let o1 = o0.clone().opt_level(OptLevel::O1);
+        let asm0 = translate(&inputs, &o0).unwrap().asm;
+        let asm1 = translate(&inputs, &o1).unwrap().asm;
+        assert!(asm1.lines().count() < asm0.lines().count());
+
+        // 最適化しても実行結果は変わらない
+        let run = |asm: &str| {
+            let mut emulator = emulator::Emulator::new(hack::assemble(asm).unwrap());
+            while emulator.step() {}
+            emulator.ram
+        };
+        let ram = run(&asm0);
+        assert_eq!(ram[16], 7);
+        assert_eq!(ram[7], 7);
+        assert_eq!(ram[4], 0);
+        assert_eq!(ram[0], 257);
+        assert_eq!(run(&asm1), ram);
+    }
+
+    #[test]
+    fn test_translate_naming_scheme() {
+        let inputs = vec![Input::new("Sys", concat!(
+            "function Sys.init 0\n",
+            "push constant 1\n",
+            "push constant 2\n",
+            "lt\n",
+            "call Sys.init 0\n",
+        ))];
+        let mut bootstrap = Bootstrap::new();
+        bootstrap.halt = true;
+        let options = TranslateOptions::new().bootstrap_code(bootstrap)
+                                             .naming_scheme(NamingScheme::Spec);
+        let asm = translate(&inputs, &options).unwrap().asm;
+
+        assert!(asm.contains("(Sys.init)"));
+        assert!(asm.contains("(bootstrap$ret.0)"));
+        assert!(asm.contains("(Sys.init$ret.0)"));
+        assert!(asm.contains("(Sys.init$cmp.0.true)"));
+        assert!(asm.contains("(bootstrap$halt)"));
+        assert!(!asm.contains("symbol-"));
+
+        // 生成するsymbolと被るラベルはエラーになる
+        let inputs = vec![Input::new("Sys", concat!(
+            "function Sys.init 0\n",
+            "call Sys.init 0\n",
+            "label ret.0\n",
+            "goto ret.0\n",
+        ))];
+        let e = translate(&inputs, &options).unwrap_err().to_string();
+        assert!(e.contains("Sys:3"), "{}", e);
+        assert!(translate(&inputs, &TranslateOptions::new()).is_ok());
+    }
+
+    #[test]
+    fn test_translate_cached() {
+        let mut cache = Cache::new();
+        let options = TranslateOptions::new();
+        let mut inputs = vec![Input::new("A", "push constant 1\n"),
+                              Input::new("B", "push constant 2\n")];
+        let first = translate_cached(&inputs, &options, &mut cache).unwrap();
+        assert_eq!(cache.hits(), 0);
+
+        let second = translate_cached(&inputs, &options, &mut cache).unwrap();
+        assert_eq!(cache.hits(), 2);
+        assert_eq!(first, second);
+
+        inputs[1].source = "push constant 3\n".to_string();
+        let third = translate_cached(&inputs, &options, &mut cache).unwrap();
+        assert_eq!(cache.hits(), 3);
+        assert_eq!(third, translate(&inputs, &options).unwrap());
+    }
+
+    #[test]
+    fn test_translate_verbose_addresses() {
+        let inputs = vec![Input::new("A", "push constant 1\n"),
+                          Input::new("B", "push constant 2\n")];
+        let options = TranslateOptions::new().comment_style(CommentStyle::Verbose);
+        let output = translate(&inputs, &options).unwrap();
+
+        // コメントのアドレスとソースマップのアドレスが一致する
+        for entry in output.source_map.entries().iter().skip(1) {
+            let comment = format!("// @{} {}:{} ", entry.start,
+                                  entry.file.as_ref().unwrap(), entry.line);
+            assert!(output.asm.contains(&comment), "{}", comment);
+        }
+    }
+
+    #[test]
+    fn test_translate_statics() {
+        let inputs = vec![Input::new("A", "push static 1\npop static 0\n"),
+                          Input::new("B", "push static 0\npush static 1\n")];
+        let options = TranslateOptions::new().bootstrap(false);
+        let output = translate(&inputs, &options).unwrap();
+        assert_eq!(output.statics.per_file(), vec![(Some("A"), 2), (Some("B"), 2)]);
+        assert_eq!(output.statics.entries()[0].address, 16);
+
+        let source: String = (0..121).map(|i| format!("push static {}\n", i)).collect();
+        let inputs = vec![Input::new("A", &source), Input::new("B", &source)];
+        let e = translate(&inputs, &options).unwrap_err();
+        assert!(e.to_string().contains("242個"));
+    }
+
+    #[test]
+    fn test_unit_name() {
+        let name = |p: &str| unit_name(Path::new(p)).unwrap();
+        assert_eq!(name("dir/Main.vm"), "Main");
+        assert_eq!(name("dir/a.b.vm"), "a.b");
+        assert_eq!(name(r"C:\dir\Main.vm"), "Main");
+        assert!(unit_name(Path::new("")).is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let path = Path::new(std::ffi::OsStr::from_bytes(b"dir/\xff.vm"));
+            assert!(unit_name(path).unwrap_err().message.contains("UTF-8"));
+        }
+    }
+
+    #[test]
+    fn test_output_instruction_count() {
+        let output = Output {
+            asm: "// a\n@SP \n(LOOP) \nM=M+1 // b\n\n".to_string(),
+            source_map: SourceMap::new(),
+            statics: StaticMap::default(),
+            size: SizeReport::default(),
+        };
+        assert_eq!(output.instruction_count(), 2);
+    }
+
+    /// 拡張命令を変換したコードをエミュレーターで実行し、スタックの一番上の値を返す
+    fn run_extended(source: &str) -> i16 {
+        let inputs = vec![Input::new("Main", source)];
+        let options = TranslateOptions::new().bootstrap(false).dialect(Dialect::Extended);
+        let output = translate(&inputs, &options).unwrap();
+        let mut emulator = emulator::Emulator::new(hack::assemble(&output.asm).unwrap());
+        while emulator.step() {}
+        emulator.ram[emulator.ram[0] as usize - 1]
+    }
+
+    /// nをスタックに積むVMコード
+    fn push(n: i16) -> String {
+        if n < 0 {
+            format!("push constant {}\nneg\n", -(n as i32))
+        } else {
+            format!("push constant {}\n", n)
+        }
+    }
+
+    #[test]
+    fn test_translate_extensions() {
+        let values: [i16; 14] = [0, 1, 2, 3, 7, -1, -2, -7, 100, -100, 255, 12345, -12345, 32767];
+        let shifts: [i16; 7] = [-1, 0, 1, 3, 15, 16, 100];
+        let mut cases = Vec::new();
+        for &x in &values {
+            for &y in &values {
+                cases.push(("mul", x, y, x.wrapping_mul(y)));
+                cases.push(("div", x, y, if y == 0 { 0 } else { x / y }));
+                // gt、ltと同じく引き算で比べるので、桁あふれする組み合わせは除く
+                if x.checked_sub(y).is_some() {
+                    cases.push(("ge", x, y, -((x >= y) as i16)));
+                    cases.push(("le", x, y, -((x <= y) as i16)));
+                    cases.push(("ne", x, y, -((x != y) as i16)));
+                }
+                cases.push(("swap", x, y, x));
+            }
+            for &y in &shifts {
+                let shl = if y <= 0 { x } else { x.checked_shl(y as u32).unwrap_or(0) };
+                let shr = if y <= 0 { x } else { x >> y.min(15) };
+                cases.push(("shl", x, y, shl));
+                cases.push(("shr", x, y, shr));
+            }
+        }
+
+        for (op, x, y, expected) in &cases {
+            let source = format!("{}{}{}\n", push(*x), push(*y), op);
+            assert_eq!(run_extended(&source), *expected, "{} {} {}", x, op, y);
+        }
+
+        let dup = "push constant 5\ndup\nadd\n";
+        assert_eq!(run_extended(dup), 10);
+        // RAM[3000 + 2]に9を書き、読み出す
+        let indirect = concat!(
+            "push constant 3000\npush constant 9\npop indirect 2\n",
+            "push constant 3001\npush indirect 1\n",
+        );
+        assert_eq!(run_extended(indirect), 9);
+    }
+
+    #[test]
+    fn test_translate_extensions_strict() {
+        let inputs = vec![Input::new("Main", "push constant 2\ndup\nmul\npush indirect 0\n")];
+        let d = translate(&inputs, &TranslateOptions::new()).unwrap_err();
+        let lines: Vec<Option<usize>> = d.iter().map(|d| d.line).collect();
+        assert_eq!(lines, vec![Some(2), Some(3), Some(4)]);
+        assert!(d.to_string().contains("mul は拡張命令です"), "{}", d);
+
+        let mut cw = CodeWriter::new(Vec::new());
+        assert!(cw.write_arithmetic("ge").is_err());
+        cw.set_dialect(Dialect::Extended);
+        assert!(cw.write_arithmetic("ge").is_ok());
+    }
+}
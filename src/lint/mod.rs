@@ -0,0 +1,384 @@
+//! VMコードのリンター。
+//! 変換はできるが間違いの可能性が高いコードを警告する。
+//! 各ルールにはIDと名前があり、`Linter`でルールごとに有効・無効を切り替えられる
+//!
+//! 警告はコメントで抑制できる。コマンドの行末か、その直前のコメントだけの行に
+//! `lint-allow:`とルールのIDか名前を書くとその行の警告を、
+//! `lint-allow-file:`を書くとファイル全体の警告を抑制する。
+//! ```text
+//! label UNUSED // lint-allow: unused-label
+//! // lint-allow: L001, L002
+//! // lint-allow-file: uninitialized-local
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::{Input, Diagnostic, Diagnostics};
+use crate::parser::cst::SyntaxTree;
+use crate::stack;
+use crate::program::{Program, VmFile, Function, Command, Segment};
+
+/// リンターのルール
+#[derive(Debug, PartialEq)]
+pub struct Rule {
+    pub id: &'static str,
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+/// すべてのルール
+pub const RULES: [Rule; 8] = [
+    Rule { id: "L001", name: "unreachable-code",
+           description: "goto、returnの後にある、ラベルのないコマンド" },
+    Rule { id: "L002", name: "missing-return",
+           description: "returnかgotoで終わらない関数" },
+    Rule { id: "L003", name: "unused-label",
+           description: "goto、if-gotoで使われていないラベル" },
+    Rule { id: "L004", name: "unused-pointer",
+           description: "pop pointerの後、this、thatセグメントを使っていない" },
+    Rule { id: "L005", name: "uninitialized-local",
+           description: "書き込む前に読んでいるlocal" },
+    Rule { id: "L006", name: "unused-locals",
+           description: "使っているlocalより多いlocalの数を指定した関数" },
+    Rule { id: "L007", name: "duplicate-function",
+           description: "複数の場所で定義されている関数" },
+    Rule { id: "L008", name: "stack-effect",
+           description: "スタックの高さが合わない分岐、値の不足、returnのときに残った値" },
+];
+
+/// IDか名前からルールを探す
+pub fn find_rule(id_or_name: &str) -> Option<&'static Rule> {
+    RULES.iter().find(|r| r.id == id_or_name || r.name == id_or_name)
+}
+
+/// ひとつの警告
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub rule: &'static Rule,
+    pub diagnostic: Diagnostic,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let d = &self.diagnostic;
+        write!(f, "{}:{}: [{} {}] {}", d.file.as_deref().unwrap_or("-"),
+               d.line.unwrap_or(0), self.rule.id, self.rule.name, d.message)
+    }
+}
+
+/// ルールの設定。デフォルトではすべてのルールが有効
+#[derive(Debug, Clone, Default)]
+pub struct Linter {
+    disabled: HashSet<&'static str>,
+}
+
+impl Linter {
+    pub fn new() -> Linter {
+        Linter { disabled: HashSet::new() }
+    }
+
+    /// ルールを有効にする。ルールはIDか名前で指定する
+    pub fn enable(&mut self, rule: &str) -> Result<(), String> {
+        let rule = find_rule(rule).ok_or(format!("{} というルールはありません", rule))?;
+        self.disabled.remove(rule.id);
+        Ok(())
+    }
+
+    /// ルールを無効にする。ルールはIDか名前で指定する
+    pub fn disable(&mut self, rule: &str) -> Result<(), String> {
+        let rule = find_rule(rule).ok_or(format!("{} というルールはありません", rule))?;
+        self.disabled.insert(rule.id);
+        Ok(())
+    }
+
+    pub fn is_enabled(&self, rule: &Rule) -> bool {
+        !self.disabled.contains(rule.id)
+    }
+
+    /// すべてのファイルを検査する。パースできないファイルがある場合はエラーを返す
+    pub fn lint(&self, inputs: &[Input]) -> Result<Vec<Warning>, Diagnostics> {
+        let program = Program::parse(inputs)?;
+
+        let mut warnings = Vec::new();
+        for file in &program.files {
+            let functions = file.functions();
+            for function in &functions {
+                check_function(file, function, &mut warnings);
+            }
+            for d in stack::check_stack(file).iter() {
+                warnings.push(Warning { rule: find_rule("L008").unwrap(),
+                                        diagnostic: d.clone() });
+            }
+        }
+        check_duplicate_functions(&program, &mut warnings);
+        // ファイルごとに行番号の順番に並べる
+        let order: HashMap<&str, usize> = inputs.iter().enumerate()
+            .map(|(i, input)| (input.name.as_str(), i)).collect();
+        warnings.sort_by_key(|w| {
+            let d = &w.diagnostic;
+            (d.file.as_deref().and_then(|f| order.get(f)).copied(), d.line)
+        });
+
+        let suppressions: HashMap<&str, Suppressions> = inputs.iter()
+            .map(|i| (i.name.as_str(), Suppressions::parse(&i.source)))
+            .collect();
+        warnings.retain(|w| {
+            let file = w.diagnostic.file.as_deref().unwrap_or("");
+            let line = w.diagnostic.line.unwrap_or(0);
+            self.is_enabled(w.rule)
+                && !suppressions.get(file).is_some_and(|s| s.allows(w.rule, line))
+        });
+        Ok(warnings)
+    }
+}
+
+/// コメントで抑制されている警告
+struct Suppressions {
+    file: HashSet<&'static str>,
+    lines: HashMap<usize, HashSet<&'static str>>,
+}
+
+/// コメントのmarkerの後に書かれているルールのIDを返す
+fn allowed_rules(comment: &str, marker: &str) -> HashSet<&'static str> {
+    match comment.find(marker) {
+        Some(i) => comment[i + marker.len()..]
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter_map(find_rule)
+            .map(|r| r.id)
+            .collect(),
+        None => HashSet::new(),
+    }
+}
+
+impl Suppressions {
+    fn parse(source: &str) -> Suppressions {
+        let mut file = HashSet::new();
+        let mut lines: HashMap<usize, HashSet<&'static str>> = HashMap::new();
+
+        let tree = SyntaxTree::parse(source);
+        for item in tree.items() {
+            let mut allowed = HashSet::new();
+            // 直前のコメントだけの行
+            if let Some(prev) = item.trivia.last().and_then(|l| l.comment.as_deref()) {
+                allowed.extend(allowed_rules(prev, "lint-allow:"));
+            }
+            if let Some(comment) = &item.command.comment {
+                allowed.extend(allowed_rules(comment, "lint-allow:"));
+            }
+            lines.insert(item.command.number, allowed);
+        }
+        for line in tree.lines() {
+            if let Some(comment) = &line.comment {
+                file.extend(allowed_rules(comment, "lint-allow-file:"));
+            }
+        }
+
+        Suppressions { file, lines }
+    }
+
+    fn allows(&self, rule: &Rule, line: usize) -> bool {
+        self.file.contains(rule.id)
+            || self.lines.get(&line).is_some_and(|r| r.contains(rule.id))
+    }
+}
+
+fn warn(warnings: &mut Vec<Warning>, id: &str, file: &str, line: usize,
+        message: String) {
+    warnings.push(Warning {
+        rule: find_rule(id).unwrap(),
+        diagnostic: Diagnostic::at(file, line, &message),
+    });
+}
+
+/// ひとつの関数を検査する
+fn check_function(file: &VmFile, function: &Function, warnings: &mut Vec<Warning>) {
+    let body = function.body;
+    let name = &file.name;
+
+    // L001 unreachable-code
+    let mut reachable = true;
+    let mut reported = false; // 続くコマンドは同じ理由なので報告しない
+    for s in body {
+        match &s.command {
+            Command::Label(_) => {
+                reachable = true;
+                reported = false;
+            },
+            _ if !reachable && !reported => {
+                warn(warnings, "L001", name, s.line,
+                     format!("{} は実行されません", s.command));
+                reported = true;
+            },
+            _ if !reachable => {},
+            Command::Goto(_) | Command::Return => reachable = false,
+            _ => {},
+        }
+    }
+
+    let function_name = match function.name {
+        Some(f) => f,
+        None => return, // 以降のルールは関数の中だけを検査する
+    };
+
+    // L002 missing-return
+    let terminated = matches!(body.last().map(|s| &s.command),
+                              Some(Command::Return) | Some(Command::Goto(_)));
+    if !terminated {
+        warn(warnings, "L002", name, function.line,
+             format!("関数 {} がreturnで終わっていません", function_name));
+    }
+
+    // L003 unused-label
+    let jumps: HashSet<&str> = body.iter().filter_map(|s| match &s.command {
+        Command::Goto(l) | Command::IfGoto(l) => Some(l.as_str()),
+        _ => None,
+    }).collect();
+    for s in body {
+        if let Command::Label(label) = &s.command {
+            if !jumps.contains(label.as_str()) {
+                warn(warnings, "L003", name, s.line,
+                     format!("label {} は使われていません", label));
+            }
+        }
+    }
+
+    // L004 unused-pointer
+    for (i, s) in body.iter().enumerate() {
+        let (pointer, segment) = match &s.command {
+            Command::Pop(Segment::Pointer, 0) => (0, Segment::This),
+            Command::Pop(Segment::Pointer, _) => (1, Segment::That),
+            _ => continue,
+        };
+        let used = body[i + 1..].iter()
+            .take_while(|s| s.command != Command::Pop(Segment::Pointer, pointer))
+            .any(|s| matches!(&s.command,
+                              Command::Push(seg, _) | Command::Pop(seg, _)
+                              if *seg == segment));
+        if !used {
+            warn(warnings, "L004", name, s.line,
+                 format!("{} の後で{}セグメントを使っていません",
+                         s.command, segment.name()));
+        }
+    }
+
+    // L005 uninitialized-local
+    let mut written = HashSet::new();
+    let mut reported = HashSet::new();
+    let mut max_local = None;
+    for s in body {
+        match &s.command {
+            Command::Pop(Segment::Local, i) => {
+                written.insert(*i);
+                max_local = max_local.max(Some(*i));
+            },
+            Command::Push(Segment::Local, i) => {
+                if !written.contains(i) && reported.insert(*i) {
+                    warn(warnings, "L005", name, s.line,
+                         format!("local {} は書き込む前に読まれています", i));
+                }
+                max_local = max_local.max(Some(*i));
+            },
+            _ => {},
+        }
+    }
+
+    // L006 unused-locals
+    let used = max_local.map_or(0, |m| m as usize + 1);
+    if function.locals as usize > used {
+        warn(warnings, "L006", name, function.line, format!(
+            "関数 {} のlocalは{}個ですが、使っているのは{}個です",
+            function_name, function.locals, used));
+    }
+}
+
+/// L007 duplicate-function
+fn check_duplicate_functions(program: &Program, warnings: &mut Vec<Warning>) {
+    let mut defined: HashMap<&str, (&str, usize)> = HashMap::new();
+    for file in &program.files {
+        for s in &file.statements {
+            if let Command::Function(f, _) = &s.command {
+                match defined.get(f.as_str()) {
+                    Some((first_file, first_line)) => {
+                        warn(warnings, "L007", &file.name, s.line, format!(
+                            "関数 {} は{}:{}で既に定義されています",
+                            f, first_file, first_line));
+                    },
+                    None => {
+                        defined.insert(f, (&file.name, s.line));
+                    },
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lint(linter: &Linter, source: &str) -> Vec<(&'static str, usize)> {
+        linter.lint(&[Input::new("Main", source)]).unwrap().iter()
+              .map(|w| (w.rule.id, w.diagnostic.line.unwrap()))
+              .collect()
+    }
+
+    #[test]
+    fn test_lint_rules() {
+        let source = concat!(
+            "function Main.main 3\n",     // L006
+            "push local 0\n",             // L005
+            "pop pointer 0\n",            // L004
+            "label UNUSED\n",             // L003
+            "goto END\n",
+            "push constant 1\n",          // L001
+            "label END\n",
+            "push constant 0\n",
+            "pop pointer 1\n",
+            "push that 0\n",
+            "return\n",
+            "function Main.loop 0\n",
+            "label LOOP\n",
+            "goto LOOP\n",
+            "function Main.main 0\n",     // L002, L007
+        );
+        let mut linter = Linter::new();
+        linter.disable("stack-effect").unwrap();
+        assert_eq!(lint(&linter, source),
+                   vec![("L006", 1), ("L005", 2), ("L004", 3), ("L003", 4),
+                        ("L001", 6), ("L002", 15), ("L007", 15)]);
+    }
+
+    #[test]
+    fn test_lint_config() {
+        let source = "function Main.main 0\nlabel A\nlabel B\n";
+        let mut linter = Linter::new();
+        linter.disable("missing-return").unwrap();
+        assert_eq!(lint(&linter, source), vec![("L003", 2), ("L003", 3)]);
+
+        linter.disable("L003").unwrap();
+        assert!(lint(&linter, source).is_empty());
+        linter.enable("unused-label").unwrap();
+        assert_eq!(lint(&linter, source).len(), 2);
+        assert!(linter.disable("nothing").is_err());
+    }
+
+    #[test]
+    fn test_lint_suppression() {
+        let linter = Linter::new();
+        let source = concat!(
+            "function Main.main 0\n",
+            "label A // lint-allow: unused-label\n",
+            "// lint-allow: L003\n",
+            "label B\n",
+            "label C\n",
+            "push constant 0\n",
+            "return\n",
+        );
+        assert_eq!(lint(&linter, source), vec![("L003", 5)]);
+
+        let source = format!("// lint-allow-file: L003\n{}", source);
+        assert!(lint(&linter, &source).is_empty());
+    }
+}
@@ -0,0 +1,678 @@
+//! vmファイルのためのLanguage Server Protocolのサーバー。
+//! 標準入出力でJSON-RPCのメッセージをやりとりし、次の機能を提供する
+//! * パース、ラベルの検査、リンターの結果の表示（textDocument/publishDiagnostics）
+//! * callの関数とgoto、if-gotoのラベルの定義への移動（textDocument/definition）
+//! * カーソルのあるコマンドから生成されるアセンブリコードの表示（textDocument/hover）
+//! * コマンド、セグメント名、関数名、ラベルの補完（textDocument/completion）
+//!
+//! ドキュメントは全体を送る形（TextDocumentSyncKind.Full）で同期する。
+//! 位置の文字の番号は、LSPのデフォルトと同じくUTF-16のコード単位で数える。
+//! 関数の定義は開いているドキュメントと、同じディレクトリにあるvmファイルから探す
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{Input, TranslateOptions, CommentStyle, Diagnostic, get_f_list, unit_name};
+use crate::json::Json;
+use crate::labels;
+use crate::lint::Linter;
+use crate::parser::cst::{SyntaxTree, Line};
+use crate::program::{VmFile, Segment};
+use crate::unit;
+
+/// 行の最初に書けるコマンド
+const COMMANDS: [&str; 17] = [
+    "push", "pop", "add", "sub", "neg", "eq", "gt", "lt", "and", "or", "not",
+    "label", "goto", "if-goto", "function", "call", "return",
+];
+
+/// 受け付けるメッセージの本体の大きさの上限
+const MAX_MESSAGE: usize = 64 * 1024 * 1024;
+
+/// JSON-RPCのエラーコード
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// LSPのCompletionItemKind
+const KIND_FUNCTION: i64 = 3;
+const KIND_VARIABLE: i64 = 6;
+const KIND_KEYWORD: i64 = 14;
+const KIND_REFERENCE: i64 = 18;
+
+/// ヘッダーとJSONの本体からなるメッセージを読む。入力が終わった場合は`None`。
+/// 読めなかった場合は外側の、本体がJSONとして読めない場合は内側のエラーになる
+fn read_message<R: BufRead>(input: &mut R) -> Result<Option<Result<Json, String>>, String> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).map_err(|e| e.to_string())? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(n) = header.strip_prefix("Content-Length:") {
+            length = n.trim().parse::<usize>().ok();
+        }
+    }
+
+    let length = length.ok_or("Content-Lengthがありません")?;
+    if length > MAX_MESSAGE {
+        return Err(format!("Content-Length {} は上限の{}を超えています", length, MAX_MESSAGE));
+    }
+    let mut body = vec![0; length];
+    input.read_exact(&mut body).map_err(|e| e.to_string())?;
+    let message = String::from_utf8(body).map_err(|e| e.to_string())
+        .and_then(|body| Json::parse(&body));
+    Ok(Some(message))
+}
+
+/// エラーの応答
+fn error_reply(id: Json, code: i64, message: String) -> Json {
+    Json::object(vec![
+        ("jsonrpc", Json::string("2.0")),
+        ("id", id),
+        ("error", Json::object(vec![
+            ("code", Json::Number(code)),
+            ("message", Json::String(message)),
+        ])),
+    ])
+}
+
+fn write_message<W: Write>(output: &mut W, message: &Json) -> Result<(), String> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+        .and_then(|_| output.flush())
+        .map_err(|e| e.to_string())
+}
+
+/// 標準入出力などでサーバーを動かす。exit通知を受け取るか入力が終わると戻る
+pub fn run<R: BufRead, W: Write>(mut input: R, mut output: W) -> Result<(), String> {
+    let mut server = Server::new();
+    while let Some(message) = read_message(&mut input)? {
+        let replies = match message {
+            Ok(message) => server.handle(&message),
+            // 読めないメッセージにはidがわからないのでnullで応答し、次のメッセージを待つ
+            Err(e) => vec![error_reply(Json::Null, PARSE_ERROR,
+                                       format!("メッセージを読めません: {}", e))],
+        };
+        for reply in replies {
+            write_message(&mut output, &reply)?;
+        }
+        if server.exit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// `file://`のURIをパスにする
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let mut bytes = Vec::new();
+    let mut rest = path.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let decoded = if b == b'%' && tail.len() >= 2 {
+            std::str::from_utf8(&tail[..2]).ok()
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+        } else {
+            None
+        };
+        match decoded {
+            Some(d) => {
+                bytes.push(d);
+                rest = &tail[2..];
+            },
+            None => {
+                bytes.push(b);
+                rest = tail;
+            },
+        }
+    }
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for b in path.to_string_lossy().bytes() {
+        if b.is_ascii_alphanumeric() || b"/-_.~".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri += &format!("%{:02X}", b);
+        }
+    }
+    uri
+}
+
+/// 文字列の長さ（UTF-16のコード単位の数）
+fn utf16_len(s: &str) -> usize {
+    s.encode_utf16().count()
+}
+
+/// 0から始まる行番号と文字の位置
+fn position(line: usize, character: usize) -> Json {
+    Json::object(vec![
+        ("line", Json::Number(line as i64)),
+        ("character", Json::Number(character as i64)),
+    ])
+}
+
+/// 1から始まる行番号の行全体の範囲
+fn line_range(text: &str, line: usize) -> Json {
+    let line = line.max(1) - 1;
+    let len = text.lines().nth(line).map_or(0, utf16_len);
+    Json::object(vec![("start", position(line, 0)), ("end", position(line, len))])
+}
+
+/// カーソルのある単語の番号。単語の上にない場合は次の単語の番号
+fn token_at(line: &Line, character: usize) -> usize {
+    let mut column = 0;
+    for (i, t) in line.tokens.iter().enumerate() {
+        column += utf16_len(&t.leading);
+        let end = column + utf16_len(&t.text);
+        if character <= end {
+            return i;
+        }
+        column = end;
+    }
+    line.tokens.len()
+}
+
+/// ドキュメントの中の関数とラベルの定義
+struct Symbols {
+    /// 関数名と行番号（0から始まる）
+    functions: Vec<(String, usize)>,
+    /// 関数名（最初のfunctionコマンドより前は空の文字列）、ラベル名、行番号
+    labels: Vec<(String, String, usize)>,
+}
+
+impl Symbols {
+    /// パースできない行があっても読めるように、CSTから定義を集める
+    fn collect(text: &str) -> Symbols {
+        let mut functions = Vec::new();
+        let mut labels = Vec::new();
+        let mut function = String::new();
+        for item in SyntaxTree::parse(text).items() {
+            let tokens = &item.command.tokens;
+            let line = item.command.number - 1;
+            match (tokens[0].text.as_str(), tokens.get(1)) {
+                ("function", Some(name)) => {
+                    function = name.text.clone();
+                    functions.push((function.clone(), line));
+                },
+                ("label", Some(name)) => {
+                    labels.push((function.clone(), name.text.clone(), line));
+                },
+                _ => {},
+            }
+        }
+        Symbols { functions, labels }
+    }
+
+    /// lineを含む関数の名前
+    fn function_at(&self, line: usize) -> &str {
+        self.functions.iter().rev().find(|(_, l)| *l <= line).map_or("", |(f, _)| f)
+    }
+}
+
+/// 開いているドキュメントを管理するサーバー
+pub struct Server {
+    documents: HashMap<String, String>,
+    shutdown: bool,
+    exit: bool,
+}
+
+impl Default for Server {
+    fn default() -> Server {
+        Server::new()
+    }
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server { documents: HashMap::new(), shutdown: false, exit: false }
+    }
+
+    /// ひとつのメッセージを処理し、送り返すメッセージを返す
+    pub fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let method = message.get("method").and_then(|m| m.as_str()).unwrap_or("");
+        let params = message.get("params").unwrap_or(&Json::Null);
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => return self.notify(method, params),
+        };
+
+        let result = match method {
+            _ if self.shutdown => {
+                Err((INVALID_REQUEST, format!("shutdownの後の {} は受け付けません", method)))
+            },
+            "initialize" => Ok(Json::object(vec![
+                ("capabilities", Json::object(vec![
+                    ("textDocumentSync", Json::Number(1)),
+                    ("definitionProvider", Json::Bool(true)),
+                    ("hoverProvider", Json::Bool(true)),
+                    ("completionProvider", Json::object(vec![
+                        ("triggerCharacters", Json::Array(vec![Json::string(" ")])),
+                    ])),
+                ])),
+                ("serverInfo", Json::object(vec![("name", Json::string("vmtranslator"))])),
+            ])),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Json::Null)
+            },
+            "textDocument/definition" => Ok(self.definition(params)),
+            "textDocument/hover" => Ok(self.hover(params)),
+            "textDocument/completion" => Ok(self.completion(params)),
+            _ => Err((METHOD_NOT_FOUND, format!("{} は対応していないメソッドです", method))),
+        };
+
+        match result {
+            Ok(result) => vec![Json::object(vec![
+                ("jsonrpc", Json::string("2.0")),
+                ("id", id),
+                ("result", result),
+            ])],
+            Err((code, message)) => vec![error_reply(id, code, message)],
+        }
+    }
+
+    /// 通知を処理する
+    fn notify(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        let document = params.get("textDocument");
+        let uri = document.and_then(|d| d.get("uri")).and_then(|u| u.as_str())
+                          .unwrap_or("").to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = document.and_then(|d| d.get("text"))
+                                   .and_then(|t| t.as_str()).unwrap_or("");
+                self.documents.insert(uri.clone(), text.to_string());
+                vec![self.publish_diagnostics(&uri)]
+            },
+            "textDocument/didChange" => {
+                let text = params.get("contentChanges").and_then(|c| c.as_array())
+                                 .and_then(|c| c.last()).and_then(|c| c.get("text"))
+                                 .and_then(|t| t.as_str());
+                if let Some(text) = text {
+                    self.documents.insert(uri.clone(), text.to_string());
+                }
+                vec![self.publish_diagnostics(&uri)]
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                // 閉じたドキュメントの表示を消す
+                vec![Json::object(vec![
+                    ("jsonrpc", Json::string("2.0")),
+                    ("method", Json::string("textDocument/publishDiagnostics")),
+                    ("params", Json::object(vec![
+                        ("uri", Json::String(uri)),
+                        ("diagnostics", Json::Array(Vec::new())),
+                    ])),
+                ])]
+            },
+            "exit" => {
+                self.exit = true;
+                Vec::new()
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    /// ドキュメントのInput。名前はstaticセグメントと同じファイル名にする
+    fn input(&self, uri: &str) -> Option<Input> {
+        let text = self.documents.get(uri)?;
+        let name = uri_to_path(uri).and_then(|p| unit_name(&p).ok())
+                                   .unwrap_or_else(|| "Main".to_string());
+        Some(Input::new(&name, text))
+    }
+
+    fn publish_diagnostics(&self, uri: &str) -> Json {
+        let mut list = Vec::new();
+        if let Some(input) = self.input(uri) {
+            let lsp_diagnostic = |d: &Diagnostic, severity: i64, code: Option<&str>| {
+                let mut pairs = vec![
+                    ("range", line_range(&input.source, d.line.unwrap_or(1))),
+                    ("severity", Json::Number(severity)),
+                    ("source", Json::string("vmtranslator")),
+                    ("message", Json::string(&d.message)),
+                ];
+                if let Some(code) = code {
+                    pairs.push(("code", Json::string(code)));
+                }
+                Json::object(pairs)
+            };
+
+            match VmFile::parse(&input) {
+                Err(errors) => {
+                    list.extend(errors.iter().map(|d| lsp_diagnostic(d, 1, None)));
+                },
+                Ok(file) => {
+                    let errors = labels::check_labels(&file);
+                    list.extend(errors.iter().map(|d| lsp_diagnostic(d, 1, None)));
+                    if let Ok(warnings) = Linter::new().lint(std::slice::from_ref(&input)) {
+                        list.extend(warnings.iter().map(|w| {
+                            lsp_diagnostic(&w.diagnostic, 2, Some(w.rule.id))
+                        }));
+                    }
+                },
+            }
+        }
+
+        Json::object(vec![
+            ("jsonrpc", Json::string("2.0")),
+            ("method", Json::string("textDocument/publishDiagnostics")),
+            ("params", Json::object(vec![
+                ("uri", Json::string(uri)),
+                ("diagnostics", Json::Array(list)),
+            ])),
+        ])
+    }
+
+    /// paramsのドキュメントのURI、カーソルの行（0から始まる）、その行
+    fn cursor(&self, params: &Json) -> Option<(String, usize, usize, Line)> {
+        let uri = params.get("textDocument")?.get("uri")?.as_str()?.to_string();
+        let position = params.get("position")?;
+        let line = position.get("line")?.as_i64()? as usize;
+        let character = position.get("character")?.as_i64()? as usize;
+        let text = self.documents.get(&uri)?;
+        let source = text.lines().nth(line).unwrap_or("");
+        Some((uri, line, character, Line::parse(source, line + 1)))
+    }
+
+    /// 開いているドキュメントと、uriと同じディレクトリにあるvmファイルの内容
+    fn workspace(&self, uri: &str) -> Vec<(String, String)> {
+        let mut files: Vec<(String, String)> = self.documents.iter()
+            .map(|(u, t)| (u.clone(), t.clone())).collect();
+        let dir = uri_to_path(uri).and_then(|p| p.parent().map(|d| d.to_path_buf()));
+        let f_list = dir.and_then(|d| get_f_list(d.to_str()?).ok()).unwrap_or_default();
+        for path in f_list {
+            let file_uri = path_to_uri(Path::new(&path));
+            if self.documents.contains_key(&file_uri) {
+                continue;
+            }
+            if let Ok(text) = fs::read_to_string(&path) {
+                files.push((file_uri, text));
+            }
+        }
+        files.sort();
+        files
+    }
+
+    fn definition(&self, params: &Json) -> Json {
+        let (uri, line, character, cursor) = match self.cursor(params) {
+            Some(c) => c,
+            None => return Json::Null,
+        };
+        // 行の最後より後ろにカーソルがある場合、2番目の単語はないことがある
+        let name = match cursor.tokens.get(1) {
+            Some(t) if token_at(&cursor, character) == 1 => t.text.as_str(),
+            _ => return Json::Null,
+        };
+        let location = |uri: &str, text: &str, line: usize| Json::object(vec![
+            ("uri", Json::string(uri)),
+            ("range", line_range(text, line + 1)),
+        ]);
+
+        match cursor.tokens[0].text.as_str() {
+            "call" | "function" => {
+                for (file_uri, text) in self.workspace(&uri) {
+                    let symbols = Symbols::collect(&text);
+                    if let Some((_, l)) = symbols.functions.iter().find(|(f, _)| f == name) {
+                        return location(&file_uri, &text, *l);
+                    }
+                }
+                Json::Null
+            },
+            "goto" | "if-goto" | "label" => {
+                let text = &self.documents[&uri];
+                let symbols = Symbols::collect(text);
+                let function = symbols.function_at(line);
+                symbols.labels.iter()
+                    .find(|(f, l, _)| f == function && l == name)
+                    .map_or(Json::Null, |(_, _, l)| location(&uri, text, *l))
+            },
+            _ => Json::Null,
+        }
+    }
+
+    fn hover(&self, params: &Json) -> Json {
+        let (uri, line, _, cursor) = match self.cursor(params) {
+            Some(c) if c.3.is_command() => c,
+            _ => return Json::Null,
+        };
+        let input = match self.input(&uri) {
+            Some(i) => i,
+            None => return Json::Null,
+        };
+
+        // Verboseのコメントの行番号でコマンドの範囲を探す
+        let options = TranslateOptions::new().comment_style(CommentStyle::Verbose);
+        let contents = |value: String| Json::object(vec![("contents", Json::object(vec![
+            ("kind", Json::string("markdown")),
+            ("value", Json::String(value)),
+        ]))]);
+        let fragment = match unit::translate_unit(&input, &options) {
+            Ok(f) => f,
+            Err(e) => {
+                return contents(format!("`{}`\n\n変換できません:\n```text\n{}\n```",
+                                        cursor.code(), e));
+            },
+        };
+        let marker = format!(" {}:{} ", input.name, line + 1);
+        let asm: Vec<&str> = fragment.asm.lines()
+            .skip_while(|l| !(l.starts_with("// @") && l.contains(&marker)))
+            .skip(1)
+            .take_while(|l| !l.starts_with("// @"))
+            .map(|l| l.trim_end())
+            .collect();
+
+        contents(format!("`{}`\n```asm\n{}\n```", cursor.code(), asm.join("\n")))
+    }
+
+    fn completion(&self, params: &Json) -> Json {
+        let (uri, line, character, cursor) = match self.cursor(params) {
+            Some(c) => c,
+            None => return Json::Null,
+        };
+        let item = |label: &str, kind: i64| Json::object(vec![
+            ("label", Json::string(label)),
+            ("kind", Json::Number(kind)),
+        ]);
+
+        let items: Vec<Json> = match (token_at(&cursor, character),
+                                      cursor.tokens.first().map(|t| t.text.as_str())) {
+            (0, _) => COMMANDS.iter().map(|c| item(c, KIND_KEYWORD)).collect(),
+            (1, Some(command @ ("push" | "pop"))) => {
+                ["constant", "local", "argument", "this", "that", "temp", "pointer", "static"]
+                    .iter()
+                    .filter(|s| !(command == "pop"
+                                  && Segment::from_name(s) == Some(Segment::Constant)))
+                    .map(|s| item(s, KIND_VARIABLE))
+                    .collect()
+            },
+            (1, Some("call")) => {
+                let mut names: Vec<String> = self.workspace(&uri).iter()
+                    .flat_map(|(_, text)| Symbols::collect(text).functions)
+                    .map(|(f, _)| f)
+                    .collect();
+                names.sort();
+                names.dedup();
+                names.iter().map(|f| item(f, KIND_FUNCTION)).collect()
+            },
+            (1, Some("goto" | "if-goto")) => {
+                let symbols = Symbols::collect(&self.documents[&uri]);
+                let function = symbols.function_at(line);
+                symbols.labels.iter().filter(|(f, _, _)| f == function)
+                    .map(|(_, l, _)| item(l, KIND_REFERENCE))
+                    .collect()
+            },
+            _ => Vec::new(),
+        };
+        Json::Array(items)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(message: &str) -> String {
+        format!("Content-Length: {}\r\n\r\n{}", message.len(), message)
+    }
+
+    /// メッセージを順番に送り、サーバーが返したメッセージを返す
+    fn session(messages: &[&str]) -> Vec<Json> {
+        let input: String = messages.iter().map(|m| frame(m)).collect();
+        let mut output = Vec::new();
+        run(input.as_bytes(), &mut output).unwrap();
+
+        let mut replies = Vec::new();
+        let mut reader = output.as_slice();
+        while let Some(reply) = read_message(&mut reader).unwrap() {
+            replies.push(reply.unwrap());
+        }
+        replies
+    }
+
+    const OPEN: &str = concat!(
+        r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":"#,
+        r#"{"uri":"file:///tmp/vmtranslator-lsp/Main.vm","languageId":"vm","version":1,"#,
+        r#""text":"function Main.main 0\npush constant 1\ncall Main.f 1\ngoto END\n"#,
+        r#"label END\nadd\nreturn\nfunction Main.f 0\npush constant 0\nreturn\n"}}}"#,
+    );
+
+    fn request(id: i64, method: &str, line: usize, character: usize) -> String {
+        format!(concat!(
+            r#"{{"jsonrpc":"2.0","id":{},"method":"{}","params":{{"textDocument":"#,
+            r#"{{"uri":"file:///tmp/vmtranslator-lsp/Main.vm"}},"#,
+            r#""position":{{"line":{},"character":{}}}}}}}"#,
+        ), id, method, line, character)
+    }
+
+    #[test]
+    fn test_lsp_initialize_and_diagnostics() {
+        let replies = session(&[
+            r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+            OPEN,
+            r#"{"jsonrpc":"2.0","id":2,"method":"shutdown"}"#,
+            &request(4, "textDocument/hover", 1, 0),
+            r#"{"jsonrpc":"2.0","method":"exit"}"#,
+            r#"{"jsonrpc":"2.0","id":3,"method":"shutdown"}"#,
+        ]);
+        assert_eq!(replies.len(), 4);
+        let capabilities = replies[0].get("result").unwrap().get("capabilities").unwrap();
+        assert_eq!(capabilities.get("hoverProvider"), Some(&Json::Bool(true)));
+
+        // add の行のスタック不足の警告
+        let diagnostics = replies[1].get("params").unwrap().get("diagnostics").unwrap();
+        let d = &diagnostics.as_array().unwrap()[0];
+        assert_eq!(d.get("code").and_then(|c| c.as_str()), Some("L008"));
+        assert_eq!(d.get("range").unwrap().get("start").unwrap().get("line"),
+                   Some(&Json::Number(5)));
+        assert_eq!(replies[2].get("result"), Some(&Json::Null));
+        // shutdownの後のリクエストはエラーになる
+        let error = replies[3].get("error").unwrap();
+        assert_eq!(error.get("code"), Some(&Json::Number(INVALID_REQUEST)));
+    }
+
+    #[test]
+    fn test_lsp_utf16_and_errors() {
+        let open = concat!(
+            r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":"#,
+            r#"{"uri":"file:///tmp/vmtranslator-lsp/Main.vm","languageId":"vm","version":1,"#,
+            r#""text":"goto NOWHERE // 😀
+"}}}"#,
+        );
+        let replies = session(&[open, &request(1, "textDocument/hover", 0, 0)]);
+
+        // 絵文字はUTF-16で2文字
+        let d = &replies[0].get("params").unwrap().get("diagnostics").unwrap()
+            .as_array().unwrap()[0];
+        let end = d.get("range").unwrap().get("end").unwrap();
+        assert_eq!(end.get("character"), Some(&Json::Number(18)));
+
+        // 変換できない場合はエラーを表示する
+        let hover = replies[1].get("result").unwrap().get("contents").unwrap()
+            .get("value").unwrap().as_str().unwrap();
+        assert!(hover.contains("変換できません") && hover.contains("NOWHERE"), "{}", hover);
+
+        // 関数名のないcallの後ろではnullを返す
+        let open = open.replace("goto NOWHERE // \u{1F600}\n", "call");
+        let replies = session(&[&open, &request(1, "textDocument/definition", 0, 5)]);
+        assert_eq!(replies[1].get("result"), Some(&Json::Null));
+
+        let line = Line::parse("push \u{1F600}\u{1F600} x", 1);
+        assert_eq!(token_at(&line, 9), 1);
+        assert_eq!(token_at(&line, 10), 2);
+    }
+
+    #[test]
+    fn test_lsp_definition_hover_completion() {
+        let replies = session(&[
+            OPEN,
+            &request(1, "textDocument/definition", 2, 7),
+            &request(2, "textDocument/definition", 3, 6),
+            &request(3, "textDocument/hover", 1, 0),
+            &request(4, "textDocument/completion", 1, 5),
+            &request(5, "textDocument/completion", 2, 5),
+        ]);
+        let result = |i: usize| replies[i].get("result").unwrap();
+        let line = |j: &Json| j.get("range").unwrap().get("start").unwrap()
+                               .get("line").and_then(|l| l.as_i64());
+
+        assert_eq!(line(result(1)), Some(7));
+        assert_eq!(line(result(2)), Some(4));
+
+        let hover = result(3).get("contents").unwrap().get("value").unwrap();
+        assert!(hover.as_str().unwrap().starts_with("`push constant 1`\n```asm\n@1\nD=A\n"));
+
+        let labels = |r: &Json| -> Vec<String> {
+            r.as_array().unwrap().iter()
+             .map(|i| i.get("label").unwrap().as_str().unwrap().to_string()).collect()
+        };
+        assert!(labels(result(4)).contains(&"constant".to_string()));
+        assert_eq!(labels(result(5)), vec!["Main.f", "Main.main"]);
+    }
+
+    #[test]
+    fn test_lsp_parse_error() {
+        // 読めないメッセージにはエラーを返し、次のメッセージも処理する
+        let mut input = frame("{\"jsonrpc\":").into_bytes();
+        input.extend(b"Content-Length: 2\r\n\r\n\xff\xfe");
+        input.extend(frame(r#"{"jsonrpc":"2.0","id":1,"method":"shutdown"}"#).into_bytes());
+        let mut output = Vec::new();
+        run(input.as_slice(), &mut output).unwrap();
+
+        let mut reader = output.as_slice();
+        let mut replies = Vec::new();
+        while let Some(reply) = read_message(&mut reader).unwrap() {
+            replies.push(reply.unwrap());
+        }
+        assert_eq!(replies.len(), 3);
+        for reply in &replies[..2] {
+            assert_eq!(reply.get("id"), Some(&Json::Null));
+            let error = reply.get("error").unwrap();
+            assert_eq!(error.get("code"), Some(&Json::Number(PARSE_ERROR)));
+        }
+        assert_eq!(replies[2].get("id"), Some(&Json::Number(1)));
+        assert_eq!(replies[2].get("result"), Some(&Json::Null));
+    }
+
+    #[test]
+    fn test_read_message_too_large() {
+        let mut input = "Content-Length: 99999999999\r\n\r\n{}".as_bytes();
+        assert!(read_message(&mut input).unwrap_err().contains("上限"));
+    }
+
+    #[test]
+    fn test_uri() {
+        let path = uri_to_path("file:///tmp/a%20b/Main.vm").unwrap();
+        assert_eq!(path, PathBuf::from("/tmp/a b/Main.vm"));
+        assert_eq!(path_to_uri(&path), "file:///tmp/a%20b/Main.vm");
+    }
+}
@@ -2,20 +2,35 @@
 
 use std::env;
 use std::fs;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::io;
+use std::process;
+use std::thread;
+use std::time::Duration;
 
-mod parser;
-use parser::Parser;
-use parser::CommandType;
-mod code_writer;
-use code_writer::CodeWriter;
+use vmtranslator::{translate, translate_cached, load_inputs, get_f_list, TranslateOptions, OptLevel,
+                   CommentStyle, NamingScheme, IdentifierPolicy, Dialect, Input, Cache, Bootstrap, Output};
+use vmtranslator::watch::Watcher;
+use vmtranslator::format;
+use vmtranslator::lint::{self, Linter};
+use vmtranslator::cfg::Cfg;
+use vmtranslator::lsp;
+use vmtranslator::debugger;
+use vmtranslator::profile;
+use vmtranslator::ir;
+use vmtranslator::bytecode;
+use vmtranslator::program::Program;
 
 fn print_usage() {
     println!("VMコマンドをHackアセンブリコードへ変換する");
     println!();
     println!("Usage:");
     println!("   command vm_path asm_path [options]");
+    println!("   command fmt [--check] vm_path...");
+    println!("   command lint [--enable rule] [--disable rule] vm_path...");
+    println!("   command cfg vm_path [--dot | --json]");
+    println!("   command lsp");
+    println!("   command debug vm_path");
+    println!("   command profile vm_path [--steps n] [--folded path]");
     println!();
     println!("Arguments:");
     println!("    vm_path     vmファイル、もしくはvmファイルのあるディレクトリのパス。");
@@ -23,98 +38,465 @@ fn print_usage() {
     println!("                vmファイルを１つのasmファイルに変換する");
     println!("    asm_path    コンパイルされたasmファイルを書き込むパス");
     println!();
+    println!("Commands:");
+    println!("    fmt         vm_pathのvmファイルを整形して書き直す。--checkがあるときは");
+    println!("                書き直さず、整形されていないファイルがあれば終了コード1で終わる");
+    println!("    lint        vm_pathのvmファイルの間違いの可能性が高いコードを警告する。");
+    println!("                警告があれば終了コード1で終わる。ruleはIDか名前で、");
+    println!("                --enable、--disableは何度でも指定できる。");
+    println!("                --rulesでルールの一覧を表示する");
+    println!("    cfg         vm_pathのvmファイルの関数ごとの制御フローグラフを");
+    println!("                Graphvizで使うDOT形式（デフォルト）かJSONで表示する");
+    println!("    lsp         標準入出力でLanguage Server Protocolのサーバーを動かす");
+    println!("    debug       vm_pathのvmファイルをインタープリターで実行しながらデバッグする。");
+    println!("                helpでデバッガーのコマンドの一覧を表示する");
+    println!("    profile     vm_pathのvmファイルを変換してエミュレーターで実行し、");
+    println!("                関数とVMコマンドごとに実行した命令の数を表示する。");
+    println!("                --stepsで実行する命令の数の上限（デフォルトは1000万）、");
+    println!("                --foldedでflamegraph用のfolded形式を書き込むパスを指定する");
+    println!();
     println!("Options:");
     println!("    -w, --without-sys-init    通常はアセンブリファイルの最初にSys.init関数を");
     println!("                              実行するコードを書くが、このオプションがあるときは");
     println!("                              そのコードを書かない。");
+    println!("    -O, --optimize            覗き穴最適化を行う。");
+    println!("    --annotate level          各コマンドに書くコメントの量。levelは次のどれか。");
+    println!("                              none     コメントを書かない");
+    println!("                              compact  コマンドごとに１行");
+    println!("                              full     [start]/[end]で囲む（デフォルト）");
+    println!("                              verbose  アドレス、ファイル名と行番号、関数名、");
+    println!("                                       元の行を書く");
+    println!("    --no-comments             --annotate noneと同じ。");
+    println!("    --naming scheme           symbolの名前の付け方。schemeは次のどれか。");
+    println!("                              current  symbol-function-fなど（デフォルト）");
+    println!("                              spec     f、f$ret.0など、仕様と同じ名前");
+    println!("    --emit kind               書き込む内容。kindは次のどれか。");
+    println!("                              asm  Hackアセンブリコード（デフォルト）");
+    println!("                              ir   パースしたプログラムとsymbolの名前のJSON");
+    println!("                              vmb  バイトコード。vm_pathに.vmbのファイルを");
+    println!("                                   指定すると、.vmファイルと同じように変換する");
+    println!("    --escape-identifiers      識別子の規則に合わないラベルと関数名をエラーに");
+    println!("                              せず、:XXの形に変換して使う。");
+    println!("    --extensions              拡張命令（mul、div、shl、shr、dup、swap、ge、le、");
+    println!("                              ne、push/pop indirect）を使えるようにする。");
+    println!("    --watch                   vm_pathのvmファイルを監視し、変更があるたびに");
+    println!("                              変換し直す。Ctrl-Cで終了する。");
+    println!("    --cache-dir dir           ファイルごとの変換結果をdirに保存し、内容が");
+    println!("                              変わっていないファイルは保存した結果を使う。");
+    println!("    -j n                      n個のスレッドでファイルを並列に変換する。");
+    println!("    --source-map path         命令のアドレスと元のVMコマンドの対応をpathに書く。");
+    println!("                              pathの拡張子が.jsonの場合はJSON、それ以外は");
+    println!("                              １行に\"start end file line command\"の形で書く。");
+    println!("    --static-report path      static変数に割り当てられるRAMのアドレスと、");
+    println!("                              ファイルごとの変数の数をpathに書く。");
+    println!("    --size-report path        関数、ファイル、VMコマンドの種類ごとの命令数と");
+    println!("                              合計をpathに書く。");
+    println!("    --no-rom-limit            命令がROM（32768個）に入らなくてもエラーにしない。");
+    println!();
+    println!("Bootstrap options:");
+    println!("    --sp n                    スタックのベースアドレス(RAM[0])。デフォルトは256。");
+    println!("    --lcl n, --arg n          LCL(RAM[1])、ARG(RAM[2])、THIS(RAM[3])、");
+    println!("    --this n, --that n        THAT(RAM[4])を初期化する。");
+    println!("    --entry name              Sys.initの代わりに最初に呼ぶ関数。");
+    println!("    --halt                    エントリー関数から戻った後に無限ループで停止する。");
+    println!("    --bootstrap-config path   key = valueの形で上の設定を書いたファイルを読む。");
+    println!("                              keyはsp, lcl, arg, this, that, entry, halt。");
 }
 
+/// プロファイラーで実行する命令の数のデフォルトの上限
+const PROFILE_STEPS: u64 = 10_000_000;
+
 fn print_error(e: &str) {
     println!("Error: {}", e);
     println!();
     print_usage();
 }
 
-/// vmコードを変換してCodeWriterへ保存する。エラーはすべて関数の外に投げ捨てる
-fn vm_to_asm<R, W>(p: &mut Parser<R>, cw: &mut CodeWriter<W>) 
-    -> Result<(), String> where R: Read, 
-                                W: Write {
-    while p.has_more_commands() {
-        p.advance();
-        match p.command_type() {
-            CommandType::PUSH => { 
-                cw.write_push_pop("push", &p.arg1().unwrap(), 
-                                  p.arg2().unwrap())?;
-            },
-            CommandType::POP => {
-                cw.write_push_pop("pop", &p.arg1().unwrap(), 
-                                  p.arg2().unwrap())?;
-            },
-            CommandType::ARITHMETIC => {
-                cw.write_arithmetic(p.arg1().unwrap().as_str())?;
-            },
-            CommandType::LABEL => cw.write_label(&p.arg1().unwrap())?,
-            CommandType::GOTO => cw.write_goto(&p.arg1().unwrap())?,
-            CommandType::IF => cw.write_if_goto(&p.arg1().unwrap())?,
-            CommandType::FUNCTION => {
-                cw.write_function(&p.arg1().unwrap(),
-                                  p.arg2().unwrap() as usize)?
-            },
-            CommandType::RETURN => cw.write_return()?,
-            CommandType::CALL => {
-                cw.write_call(&p.arg1().unwrap(),
-                              p.arg2().unwrap() as usize)?
-            },
-            CommandType::None => return Err(format!("{} は無効なコマンドです",
-                                                    p.arg1().unwrap())),
+/// 変換結果の書き込み先
+struct Destination {
+    asm_path: String,
+    map_path: Option<String>,
+    static_report_path: Option<String>,
+    size_report_path: Option<String>,
+}
+
+impl Destination {
+    /// 変換結果を書き込む
+    fn write(&self, output: &Output) -> Result<(), String> {
+        if fs::write(&self.asm_path, &output.asm).is_err() {
+            return Err(format!("can't create '{}'.", self.asm_path));
         }
+
+        if let Some(path) = &self.map_path {
+            let map = if path.ends_with(".json") {
+                output.source_map.to_json().to_string()
+            } else {
+                output.source_map.to_text()
+            };
+            if fs::write(path, map).is_err() {
+                return Err(format!("can't create '{}'.", path));
+            }
+        }
+
+        if let Some(path) = &self.static_report_path {
+            if fs::write(path, output.statics.to_text()).is_err() {
+                return Err(format!("can't create '{}'.", path));
+            }
+        }
+
+        if let Some(path) = &self.size_report_path {
+            if fs::write(path, output.size.to_text()).is_err() {
+                return Err(format!("can't create '{}'.", path));
+            }
+        }
+        Ok(())
     }
-    Ok(())
 }
 
-/// pathからvmファイルのリストを取得する
-fn get_f_list(vm_path: &str) -> Result<Vec<String>, String> {
-    // ファイル名とFile構造体のリスト
-    let mut f_list: Vec<String> = Vec::new();
-    let metadata = match fs::metadata(&vm_path) {
-        Ok(m) => m,
-        Err(_) => return Err(format!("'{}' is not exist.", vm_path))
+/// 変換して書き込み、結果を表す１行のメッセージを返す。
+/// エラーがある場合はその後にエラーを１行ずつ続ける
+fn translate_to_file(inputs: &[Input], dest: &Destination,
+                     options: &TranslateOptions, cache: &mut Cache) -> String {
+    let output = match translate_cached(inputs, options, cache) {
+        Ok(output) => output,
+        Err(e) => return format!("[error] {} error(s)\n{}", e.len(), e)
     };
 
-    if metadata.is_file() {
-        f_list.push(vm_path.to_string());
-        return Ok(f_list)
+    match dest.write(&output) {
+        Ok(_) => format!("[ok] {} file(s) -> {} ({} instructions)",
+                         inputs.len(), dest.asm_path, output.instruction_count()),
+        Err(e) => format!("[error] {}", e)
+    }
+}
+
+/// vm_pathのファイルを監視し、変更があるたびに変換する
+/// 変更のないファイルはキャッシュにある変換結果を使う
+fn watch(vm_path: &str, dest: &Destination, options: &TranslateOptions,
+         cache: &mut Cache) {
+    let mut watcher = Watcher::new(vm_path);
+    println!("watching '{}'", vm_path);
+
+    loop {
+        if watcher.poll() {
+            match watcher.inputs() {
+                Ok(inputs) => println!("{}", translate_to_file(&inputs, dest,
+                                                              options, cache)),
+                Err(e) => println!("[error] {}", e),
+            }
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// fmtコマンド。vmファイルを整形する
+fn fmt_main(args: &[String]) {
+    let check = args.iter().any(|a| a == "--check");
+    let paths: Vec<&String> = args.iter().filter(|a| *a != "--check").collect();
+    if paths.is_empty() {
+        return print_error("vm_pathがありません");
     }
 
-    for entry in fs::read_dir(vm_path).unwrap() {
-        let path = entry.unwrap().path();
-        if path.is_file() {
-            let filename = path.to_str().unwrap();
-            if &filename[filename.len()-3..filename.len()] == ".vm" {
-                f_list.push(filename.to_string());
+    let mut unformatted = 0;
+    for vm_path in paths {
+        let f_list = match get_f_list(vm_path) {
+            Ok(f_list) => f_list,
+            Err(e) => return print_error(&e)
+        };
+        for path in f_list {
+            let source = match fs::read_to_string(&path) {
+                Ok(s) => s,
+                Err(_) => return print_error(&format!("{}を開けません", path))
+            };
+            let formatted = format::format(&source);
+            if formatted == source {
+                continue;
+            }
+
+            unformatted += 1;
+            if check {
+                println!("{}", path);
+            } else if fs::write(&path, formatted).is_err() {
+                return print_error(&format!("can't create '{}'.", path));
             }
         }
     }
 
-    if f_list.len() == 0 {
-        return Err(format!("There isn't vm files in '{}'.", vm_path));
+    if check && unformatted > 0 {
+        println!("{} file(s) are not formatted", unformatted);
+        process::exit(1);
+    }
+}
+
+/// lintコマンド。警告を表示する
+fn lint_main(args: &[String]) {
+    let mut linter = Linter::new();
+    let mut paths = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let result = match arg.as_str() {
+            "--enable" | "--disable" => match iter.next() {
+                Some(rule) if arg == "--enable" => linter.enable(rule),
+                Some(rule) => linter.disable(rule),
+                None => Err(format!("{}にはruleが必要です", arg)),
+            },
+            "--rules" => {
+                for rule in &lint::RULES {
+                    println!("{} {:<20} {}", rule.id, rule.name, rule.description);
+                }
+                return;
+            },
+            _ => {
+                paths.push(arg);
+                Ok(())
+            },
+        };
+        if let Err(e) = result {
+            return print_error(&e);
+        }
+    }
+    if paths.is_empty() {
+        return print_error("vm_pathがありません");
+    }
+
+    let mut inputs = Vec::new();
+    for vm_path in paths {
+        match load_inputs(vm_path) {
+            Ok(i) => inputs.extend(i),
+            Err(e) => return print_error(&e.to_string())
+        }
+    }
+    let warnings = match linter.lint(&inputs) {
+        Ok(warnings) => warnings,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    for w in &warnings {
+        println!("{}", w);
+    }
+    if !warnings.is_empty() {
+        println!("{} warning(s)", warnings.len());
+        process::exit(1);
+    }
+}
+
+/// cfgコマンド。制御フローグラフを表示する
+fn cfg_main(args: &[String]) {
+    let json = args.iter().any(|a| a == "--json");
+    let vm_path = match args.iter().find(|a| !a.starts_with("--")) {
+        Some(p) => p,
+        None => return print_error("vm_pathがありません")
+    };
+
+    let program = match load_inputs(vm_path).and_then(|i| Program::parse(&i)) {
+        Ok(program) => program,
+        Err(e) => return print_error(&e.to_string())
+    };
+    let cfg = Cfg::build(&program);
+    if json {
+        println!("{}", cfg.to_json());
+    } else {
+        print!("{}", cfg.to_dot());
+    }
+}
+
+fn debug_main(args: &[String]) {
+    let vm_path = match args.first() {
+        Some(p) => p,
+        None => return print_error("vm_pathがありません")
+    };
+
+    let program = match load_inputs(vm_path).and_then(|i| Program::parse(&i)) {
+        Ok(program) => program,
+        Err(e) => return print_error(&e.to_string())
+    };
+    let stdin = io::stdin();
+    if let Err(e) = debugger::run(&program, stdin.lock(), io::stdout()) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
     }
+}
 
-    Ok(f_list)
+fn profile_main(args: &[String]) {
+    let mut vm_path = None;
+    let mut steps = PROFILE_STEPS;
+    let mut folded_path = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--steps" => match iter.next().and_then(|n| n.parse().ok()) {
+                Some(n) => steps = n,
+                None => return print_error("--stepsには数値が必要です"),
+            },
+            "--folded" => match iter.next() {
+                Some(path) => folded_path = Some(path),
+                None => return print_error("--foldedにはパスが必要です"),
+            },
+            _ => vm_path = Some(arg),
+        }
+    }
+    let vm_path = match vm_path {
+        Some(p) => p,
+        None => return print_error("vm_pathがありません")
+    };
+
+    let inputs = match load_inputs(vm_path) {
+        Ok(inputs) => inputs,
+        Err(e) => return print_error(&e.to_string())
+    };
+    // Sys.initから戻ったら止まるようにする。Sys.initがない場合は最初のファイルから実行する
+    let has_init = Program::parse(&inputs).is_ok_and(|p| p.files.iter()
+        .any(|f| f.functions().iter().any(|f| f.name == Some("Sys.init"))));
+    let mut bootstrap = Bootstrap::new();
+    bootstrap.halt = has_init;
+    let options = TranslateOptions::new().bootstrap_code(bootstrap).bootstrap(has_init);
+
+    let result = translate(&inputs, &options).map_err(|e| e.to_string())
+        .and_then(|output| profile::profile(&output, steps));
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    };
+    print!("{}", result.to_text());
+    if let Some(path) = folded_path {
+        if fs::write(path, result.to_folded()).is_err() {
+            println!("Error: can't create '{}'.", path);
+            process::exit(1);
+        }
+    }
 }
 
 fn main() {
+    let all: Vec<String> = env::args().skip(1).collect();
+    match all.first().map(|a| a.as_str()) {
+        Some("fmt") => return fmt_main(&all[1..]),
+        Some("lint") => return lint_main(&all[1..]),
+        Some("cfg") => return cfg_main(&all[1..]),
+        Some("debug") => return debug_main(&all[1..]),
+        Some("profile") => return profile_main(&all[1..]),
+        Some("lsp") => {
+            let stdin = io::stdin();
+            if let Err(e) = lsp::run(stdin.lock(), io::stdout()) {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            }
+            return;
+        },
+        _ => {},
+    }
+
     let mut args = Vec::new();
-    let mut options = Vec::new();
+    let mut options = TranslateOptions::new();
+    let mut watch_mode = false;
+    let mut cache = Cache::new();
+    let mut bootstrap = Bootstrap::new();
+    let mut map_path = None;
+    let mut static_report_path = None;
+    let mut size_report_path = None;
+    let mut emit = String::from("asm");
+    let mut policy = IdentifierPolicy::Strict;
+    let mut dialect = Dialect::Strict;
 
-    for arg in env::args().skip(1) {
+    let mut iter = all.into_iter();
+    while let Some(arg) = iter.next() {
         match arg.as_str() {
-            "-w" | "--without-sys-init" => options.push("--without-sys-init"),
+            "-w" | "--without-sys-init" => bootstrap.entry = None,
+            "--halt" => bootstrap.halt = true,
+            "--sp" | "--lcl" | "--arg" | "--this" | "--that" | "--entry" => {
+                let value = match iter.next() {
+                    Some(v) => v,
+                    None => return print_error(&format!("{}には値が必要です", arg))
+                };
+                if let Err(e) = bootstrap.apply(&arg[2..], &value) {
+                    return print_error(&e);
+                }
+            },
+            "--bootstrap-config" => {
+                let path = match iter.next() {
+                    Some(p) => p,
+                    None => return print_error("--bootstrap-configにはpathが必要です")
+                };
+                if let Err(e) = bootstrap.apply_file(&path) {
+                    return print_error(&e);
+                }
+            },
+            "-O" | "--optimize" => options = options.opt_level(OptLevel::O1),
+            "--watch" => watch_mode = true,
+            "-j" => {
+                let jobs = match iter.next().map(|n| n.parse::<usize>()) {
+                    Some(Ok(n)) if n > 0 => n,
+                    _ => return print_error("-jには1以上の数値が必要です")
+                };
+                options = options.jobs(jobs);
+            },
+            "--source-map" => {
+                map_path = match iter.next() {
+                    Some(p) => Some(p),
+                    None => return print_error("--source-mapにはpathが必要です")
+                };
+            },
+            "--static-report" => {
+                static_report_path = match iter.next() {
+                    Some(p) => Some(p),
+                    None => return print_error("--static-reportにはpathが必要です")
+                };
+            },
+            "--size-report" => {
+                size_report_path = match iter.next() {
+                    Some(p) => Some(p),
+                    None => return print_error("--size-reportにはpathが必要です")
+                };
+            },
+            "--cache-dir" => {
+                let dir = match iter.next() {
+                    Some(d) => d,
+                    None => return print_error("--cache-dirにはdirが必要です")
+                };
+                cache = match Cache::with_dir(&dir) {
+                    Ok(c) => c,
+                    Err(e) => return print_error(&e)
+                };
+            },
+            "--annotate" => {
+                let style = iter.next().and_then(|l| CommentStyle::from_name(&l));
+                match style {
+                    Some(style) => options = options.comment_style(style),
+                    None => return print_error(
+                        "--annotateにはnone, compact, full, verboseのどれかが必要です")
+                }
+            },
+            "--naming" => {
+                let scheme = iter.next().and_then(|n| NamingScheme::from_name(&n));
+                match scheme {
+                    Some(scheme) => options = options.naming_scheme(scheme),
+                    None => return print_error(
+                        "--namingにはcurrent, specのどちらかが必要です")
+                }
+            },
+            "--emit" => match iter.next() {
+                Some(kind) if ["asm", "ir", "vmb"].contains(&kind.as_str()) => emit = kind,
+                _ => return print_error("--emitにはasm, ir, vmbのどれかが必要です")
+            },
+            "--no-rom-limit" => options = options.rom_limit(false),
+            "--escape-identifiers" => policy = IdentifierPolicy::Escape,
+            "--extensions" => dialect = Dialect::Extended,
+            "--no-comments" => {
+                options = options.comment_style(CommentStyle::None)
+            },
             _ => args.push(arg),
         }
     }
 
-    let vm_path = match args.get(0) {
+    options = options.bootstrap_code(bootstrap)
+        .identifier_policy(policy)
+        .dialect(dialect);
+
+    let vm_path = match args.first() {
         Some(f) => f,
         None => return print_error("vm_pathがありません")
     };
@@ -123,35 +505,44 @@ fn main() {
         None => return print_error("asm_pathがありません")
     };
 
-    let f_list = match get_f_list(vm_path) {
-        Ok(f_list) => f_list,
-        Err(e) => return print_error(&e)
-    };
-    let outputfile = match File::create(asm_path) {
-        Ok(f) => f,
-        Err(_) => return print_error(&format!("can't create '{}'.", 
-                                              asm_path))
+    let dest = Destination {
+        asm_path: asm_path.to_string(),
+        map_path,
+        static_report_path,
+        size_report_path,
     };
 
-    let mut code_writer = CodeWriter::new(outputfile);
-    code_writer.write_init();
+    if emit != "asm" {
+        if watch_mode {
+            return print_error("--watchは--emit asmのときだけ使えます");
+        }
+        let result = load_inputs(vm_path).and_then(|i| match emit.as_str() {
+            "ir" => ir::emit(&i, &options).map(|json| json.to_string().into_bytes()),
+            _ => Program::parse_with(&i, policy, dialect).map(|p| bytecode::encode(&p)),
+        });
+        match result {
+            Ok(bytes) => if fs::write(asm_path, bytes).is_err() {
+                print_error(&format!("can't create '{}'.", asm_path))
+            },
+            Err(e) => print_error(&e.to_string()),
+        }
+        return;
+    }
 
-    if options.contains(&"--without-sys-init") == false {
-        let _ = code_writer.write_call("Sys.init", 0);
+    if watch_mode {
+        return watch(vm_path, &dest, &options, &mut cache);
     }
-    
-    for filename in f_list {
-        let file = match File::open(&filename) {
-            Ok(f) => f,
-            Err(_) => return print_error(&format!("{}を開けません", &filename))
-        };
-        
-        let mut parser = Parser::new(file); 
-        let mut fname = filename.split('/').last().unwrap();
-        fname = fname.trim_end_matches(".vm"); // 拡張子を削除
-        code_writer.set_file_name(fname);
-        if let Err(e) = vm_to_asm(&mut parser, &mut code_writer) {
-            return print_error(&e);
-        }
+
+    let inputs = match load_inputs(vm_path) {
+        Ok(inputs) => inputs,
+        Err(e) => return print_error(&e.to_string())
+    };
+    let output = match translate_cached(&inputs, &options, &mut cache) {
+        Ok(output) => output,
+        Err(e) => return print_error(&e.to_string())
+    };
+
+    if let Err(e) = dest.write(&output) {
+        print_error(&e)
     }
 }
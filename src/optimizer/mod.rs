@@ -0,0 +1,92 @@
+//! 変換後のアセンブリコードに対して行う最適化
+
+use crate::hack::instruction;
+
+/// 覗き穴最適化を行う。
+/// pushの直後にpopがある場合、pushの最後で行うSPのインクリメントと
+/// popの最初で行うSPのデクリメントは打ち消し合うので両方を取り除く。
+/// 取り除くのは次の命令列で、間にラベルがある場合は取り除かない
+/// ```text
+/// @SP
+/// M=M+1
+/// @SP
+/// M=M-1
+/// A=M
+/// ```
+///
+/// 行ごとに分けたアセンブリコードを受け取り、
+/// 取り除く行は`true`、残す行は`false`になるリストを返す
+pub fn peephole(lines: &[&str]) -> Vec<bool> {
+    // 命令がある行のインデックスのリスト
+    let code: Vec<usize> = (0..lines.len())
+        .filter(|i| instruction(lines[*i]).is_some())
+        .collect();
+    let mut removed = vec![false; lines.len()];
+
+    let pattern = ["@SP", "M=M+1", "@SP", "M=M-1", "A=M"];
+    let mut i = 0;
+    while i + pattern.len() <= code.len() {
+        let matched = pattern.iter().enumerate().all(|(j, p)| {
+            instruction(lines[code[i + j]]) == Some(*p)
+        });
+
+        if matched {
+            // Aにスタックの一番上のアドレスを入れるために最初の@SPとA=Mは残す
+            for j in 1..pattern.len() - 1 {
+                removed[code[i + j]] = true;
+            }
+            i += pattern.len();
+        } else {
+            i += 1;
+        }
+    }
+
+    removed
+}
+
+
+#[cfg(test)]
+mod test {
+    /// 最適化した結果のアセンブリコードを返す
+    fn peephole(asm: &str) -> String {
+        let lines: Vec<&str> = asm.split_inclusive('\n').collect();
+        let removed = super::peephole(&lines);
+        lines.iter().zip(removed).filter(|(_, r)| !r).map(|(l, _)| *l).collect()
+    }
+
+    #[test]
+    fn test_peephole() {
+        let asm = concat!(
+            "@SP \n",
+            "M=M+1 \n",
+            "// [end] push constant 1 \n",
+            "// [start] pop local 0 \n",
+            "@SP \n",
+            "M=M-1 \n",
+            "A=M \n",
+            "D=M \n",
+        );
+        let expected = concat!(
+            "@SP \n",
+            "// [end] push constant 1 \n",
+            "// [start] pop local 0 \n",
+            "A=M \n",
+            "D=M \n",
+        );
+        assert_eq!(peephole(asm), expected);
+    }
+
+    #[test]
+    fn test_peephole_label() {
+        // ラベルを挟む場合はジャンプしてくる可能性があるので取り除かない
+        let asm = concat!(
+            "@SP \n",
+            "M=M+1 \n",
+            "(LOOP) \n",
+            "@SP \n",
+            "M=M-1 \n",
+            "A=M \n",
+        );
+        assert_eq!(peephole(asm), asm);
+    }
+}
@@ -0,0 +1,127 @@
+//! 変換の設定
+
+use crate::code_writer::{CommentStyle, NamingScheme};
+use crate::bootstrap::Bootstrap;
+use crate::identifier::IdentifierPolicy;
+use crate::program::Dialect;
+
+/// 最適化のレベル
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptLevel {
+    /// 最適化を行わない
+    O0,
+    /// 覗き穴最適化を行う
+    O1,
+}
+
+/// `translate`関数に渡す設定。
+/// ```
+/// use vmtranslator::{TranslateOptions, OptLevel, CommentStyle};
+///
+/// let options = TranslateOptions::new()
+///     .bootstrap(false)
+///     .opt_level(OptLevel::O1)
+///     .comment_style(CommentStyle::None);
+/// ```
+#[derive(Debug, Clone)]
+pub struct TranslateOptions {
+    pub(crate) bootstrap: Bootstrap,
+    pub(crate) opt_level: OptLevel,
+    pub(crate) comment_style: CommentStyle,
+    pub(crate) naming_scheme: NamingScheme,
+    pub(crate) identifier_policy: IdentifierPolicy,
+    pub(crate) dialect: Dialect,
+    pub(crate) rom_limit: bool,
+    pub(crate) jobs: usize,
+}
+
+impl TranslateOptions {
+    /// デフォルトの設定。Sys.initを呼ぶブートストラップコードを書き、
+    /// 最適化はせず、コメントはすべて書く
+    pub fn new() -> TranslateOptions {
+        TranslateOptions {
+            bootstrap: Bootstrap::new(),
+            opt_level: OptLevel::O0,
+            comment_style: CommentStyle::Full,
+            naming_scheme: NamingScheme::Current,
+            identifier_policy: IdentifierPolicy::Strict,
+            dialect: Dialect::Strict,
+            rom_limit: true,
+            jobs: 1,
+        }
+    }
+
+    /// `false`の場合はSys.initを呼ぶコードを書かない
+    pub fn bootstrap(mut self, bootstrap: bool) -> TranslateOptions {
+        self.bootstrap.entry = if bootstrap {
+            Some("Sys.init".to_string())
+        } else {
+            None
+        };
+        self
+    }
+
+    /// ブートストラップコードの設定をすべて指定する
+    pub fn bootstrap_code(mut self, bootstrap: Bootstrap) -> TranslateOptions {
+        self.bootstrap = bootstrap;
+        self
+    }
+
+    pub fn opt_level(mut self, level: OptLevel) -> TranslateOptions {
+        self.opt_level = level;
+        self
+    }
+
+    pub fn comment_style(mut self, style: CommentStyle) -> TranslateOptions {
+        self.comment_style = style;
+        self
+    }
+
+    /// symbolの名前の付け方。`NamingScheme::Spec`にすると公式のツールと
+    /// 同じ名前になる
+    pub fn naming_scheme(mut self, scheme: NamingScheme) -> TranslateOptions {
+        self.naming_scheme = scheme;
+        self
+    }
+
+    /// 識別子の規則に合わないラベルと関数名の扱い。デフォルトはエラーにする
+    pub fn identifier_policy(mut self, policy: IdentifierPolicy) -> TranslateOptions {
+        self.identifier_policy = policy;
+        self
+    }
+
+    /// 受け付けるコマンドの範囲。デフォルトは仕様のコマンドだけで、
+    /// `Dialect::Extended`にするとmulなどの拡張命令も変換する
+    pub fn dialect(mut self, dialect: Dialect) -> TranslateOptions {
+        self.dialect = dialect;
+        self
+    }
+
+    /// `false`の場合は命令がROMに入らなくてもエラーにしない。
+    /// ベンチマークや、大きすぎるプログラムのコードの大きさを調べるときに使う
+    pub fn rom_limit(mut self, rom_limit: bool) -> TranslateOptions {
+        self.rom_limit = rom_limit;
+        self
+    }
+
+    /// ファイルを変換するスレッドの数。デフォルトは1。
+    /// `CommentStyle::Verbose`の場合はコメントに書くアドレスを順番に
+    /// 決める必要があるので、この設定に関係なく１つずつ変換する
+    pub fn jobs(mut self, jobs: usize) -> TranslateOptions {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    /// ファイルごとの変換結果に影響する設定を表す文字列。
+    /// キャッシュのキーに使う
+    pub(crate) fn fingerprint(&self) -> String {
+        format!("{:?} {:?} {:?} {:?} {:?}", self.opt_level, self.comment_style,
+                self.naming_scheme, self.identifier_policy, self.dialect)
+    }
+}
+
+impl Default for TranslateOptions {
+    fn default() -> TranslateOptions {
+        TranslateOptions::new()
+    }
+}
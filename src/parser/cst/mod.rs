@@ -0,0 +1,213 @@
+//! vmファイルの具象構文木（CST）。
+//! コメント、空行、空白、改行をすべて残すので、`to_string()`で元のファイルと
+//! 同じ文字列に戻せる。整形やリファクタリング、コメントからのドキュメント
+//! 作成などのツールはこのモジュールを使う。
+//! `Parser`もこのモジュールで行を分解し、コマンドの部分だけを使う
+//!
+//! 各コマンドには、その前にある空行とコメントだけの行が付く。
+//! ```
+//! use vmtranslator::parser::cst::SyntaxTree;
+//!
+//! let source = "// main\nfunction Main.main 0  // entry\n\n  return\n";
+//! let tree = SyntaxTree::parse(source);
+//! assert_eq!(tree.to_string(), source);
+//!
+//! let first = &tree.items()[0];
+//! assert_eq!(first.trivia[0].comment.as_deref(), Some("// main"));
+//! assert_eq!(first.command.code(), "function Main.main 0");
+//! assert_eq!(tree.items()[1].trivia.len(), 1); // 空行
+//! ```
+
+use std::fmt;
+
+/// 単語とその前の空白
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    /// 単語の前の空白。最初の単語の場合は字下げ
+    pub leading: String,
+    pub text: String,
+}
+
+/// ファイルの１行
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    /// 行番号。1から始まる
+    pub number: usize,
+    pub tokens: Vec<Token>,
+    /// 最後の単語の後（単語がない場合は行頭から）コメントもしくは改行までの空白
+    pub trailing: String,
+    /// `//`から行末（改行の前）までのコメント
+    pub comment: Option<String>,
+    /// `\n`、`\r\n`、もしくはファイルの最後の行で改行がない場合は空の文字列
+    pub newline: String,
+}
+
+impl Line {
+    /// １行を分解する。textは改行を含んでいてもよい
+    pub fn parse(text: &str, number: usize) -> Line {
+        let (body, newline) = if let Some(body) = text.strip_suffix("\r\n") {
+            (body, "\r\n")
+        } else if let Some(body) = text.strip_suffix('\n') {
+            (body, "\n")
+        } else {
+            (text, "")
+        };
+        let (code, comment) = match body.find("//") {
+            Some(i) => (&body[..i], Some(body[i..].to_string())),
+            None => (body, None),
+        };
+
+        let mut tokens = Vec::new();
+        let mut rest = code;
+        loop {
+            let start = rest.len() - rest.trim_start().len();
+            if start == rest.len() {
+                break;
+            }
+            let end = rest[start..].find(char::is_whitespace)
+                                   .map_or(rest.len(), |i| start + i);
+            tokens.push(Token {
+                leading: rest[..start].to_string(),
+                text: rest[start..end].to_string(),
+            });
+            rest = &rest[end..];
+        }
+
+        Line {
+            number,
+            tokens,
+            trailing: rest.to_string(),
+            comment,
+            newline: newline.to_string(),
+        }
+    }
+
+    /// コマンドがある行かどうか
+    pub fn is_command(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// 空行（空白だけの行を含む）かどうか
+    pub fn is_blank(&self) -> bool {
+        self.tokens.is_empty() && self.comment.is_none()
+    }
+
+    /// 単語を空白１つでつなげたコマンドの文字列
+    pub fn code(&self) -> String {
+        let words: Vec<&str> = self.tokens.iter().map(|t| t.text.as_str()).collect();
+        words.join(" ")
+    }
+
+    /// 行の字下げ
+    pub fn indent(&self) -> &str {
+        match self.tokens.first() {
+            Some(t) => &t.leading,
+            None => &self.trailing,
+        }
+    }
+}
+
+impl fmt::Display for Line {
+    /// 元の行をそのまま書く
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for t in &self.tokens {
+            write!(f, "{}{}", t.leading, t.text)?;
+        }
+        write!(f, "{}", self.trailing)?;
+        if let Some(comment) = &self.comment {
+            write!(f, "{}", comment)?;
+        }
+        write!(f, "{}", self.newline)
+    }
+}
+
+/// コマンドの行と、その前にある空行とコメントだけの行
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    pub trivia: Vec<Line>,
+    pub command: Line,
+}
+
+/// ファイル全体
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SyntaxTree {
+    items: Vec<Item>,
+    /// 最後のコマンドの後にある空行とコメントだけの行
+    trailing: Vec<Line>,
+}
+
+impl SyntaxTree {
+    pub fn parse(source: &str) -> SyntaxTree {
+        let mut items = Vec::new();
+        let mut trivia = Vec::new();
+        for (i, text) in source.split_inclusive('\n').enumerate() {
+            let line = Line::parse(text, i + 1);
+            if line.is_command() {
+                items.push(Item { trivia: std::mem::take(&mut trivia), command: line });
+            } else {
+                trivia.push(line);
+            }
+        }
+        SyntaxTree { items, trailing: trivia }
+    }
+
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+
+    pub fn trailing(&self) -> &[Line] {
+        &self.trailing
+    }
+
+    /// すべての行をファイルの順番で返す
+    pub fn lines(&self) -> impl Iterator<Item = &Line> {
+        self.items.iter()
+            .flat_map(|item| item.trivia.iter().chain(Some(&item.command)))
+            .chain(&self.trailing)
+    }
+}
+
+impl fmt::Display for SyntaxTree {
+    /// 元のファイルをそのまま書く
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for line in self.lines() {
+            write!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_line_parse() {
+        let line = Line::parse("  push\tconstant  1 // one \r\n", 3);
+        assert_eq!(line.number, 3);
+        assert_eq!(line.code(), "push constant 1");
+        assert_eq!(line.indent(), "  ");
+        assert_eq!(line.tokens[1].leading, "\t");
+        assert_eq!(line.trailing, " ");
+        assert_eq!(line.comment.as_deref(), Some("// one "));
+        assert_eq!(line.newline, "\r\n");
+
+        assert!(Line::parse("   \n", 1).is_blank());
+        assert!(!Line::parse("// a", 1).is_command());
+    }
+
+    #[test]
+    fn test_syntax_tree_round_trip() {
+        let source = concat!(
+            "\n", "// Main.vm\r\n", "push   constant 1//one\n", "\t\n",
+            "function\tMain.main 0\n", "  label LOOP  \n", "// end",
+        );
+        let tree = SyntaxTree::parse(source);
+        assert_eq!(tree.to_string(), source);
+        assert_eq!(tree.items().len(), 3);
+        assert_eq!(tree.items()[0].trivia.len(), 2);
+        assert_eq!(tree.items()[2].command.number, 6);
+        assert_eq!(tree.trailing()[0].comment.as_deref(), Some("// end"));
+    }
+}
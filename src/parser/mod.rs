@@ -4,6 +4,7 @@
 use std::str::FromStr;
 use std::io::Read;
 
+pub mod cst;
 mod vmlines;
 use vmlines::Vmlines;
 
@@ -11,17 +12,22 @@ use vmlines::Vmlines;
 /// ひとつの.vmファイルに対してパースを行うとともに、入力コードへのアクセスを
 /// カプセル化する。つまり、このモジュールはVMコマンドを読み、それをパースし、
 /// その要素に対してアクセスする便利なメソッドを提供する。さらに、空白文字と
-/// コメントを取り除く
+/// コメントを取り除く。
+/// 各行は`cst::Line`に分解し、コマンドの単語を空白１つでつなげたものを
+/// コマンドの文字列とする
 pub struct Parser<R> {
     vm_lines: Vmlines<R>,
     command: Option<String>, // 現在のコマンド
     next: Option<String>, // 次のコマンド
+    line: usize, // 現在のコマンドの行番号
+    next_line: usize, // 次のコマンドの行番号
 }
 
 /// 現VMコマンドの種類を表す。
 /// 算術コマンドはすべて`CommandType::ARITHMETIC`として表される。
 /// どれにも該当しない場合は`CommandType::None`として表される。
 #[derive(Debug, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum CommandType {
     ARITHMETIC,
     PUSH,
@@ -40,20 +46,20 @@ impl<R: Read> Parser<R> {
     pub fn new(stream: R) -> Parser<R> {
         let mut vm_lines = Vmlines::new(stream);
         let next = vm_lines.next();
+        let next_line = vm_lines.line();
 
         Parser {
             vm_lines,
             command: None,
             next,
+            line: 0,
+            next_line,
         }
     }
 
     // 入力において、さらにコマンドが存在するか？
     pub fn has_more_commands(&self) -> bool {
-        match self.next {
-            Some(_) => true,
-            None => false
-        }
+        self.next.is_some()
     }
 
     /// 入力から次のコマンドを読み、それを現コマンドとする。
@@ -61,10 +67,23 @@ impl<R: Read> Parser<R> {
     /// 最初は現コマンドは空である。
     pub fn advance(&mut self) {
         self.command = self.next.take();
+        self.line = self.next_line;
         self.next = self.vm_lines.next();
+        self.next_line = self.vm_lines.line();
+    }
+
+    /// 現コマンドの文字列を返す。現コマンドが空の場合は`None`を返す
+    pub fn command(&self) -> Option<&str> {
+        self.command.as_deref()
     }
 
-    /// 現VMコマンドの種類を返す。算術コマンドはすべて
+    /// 現コマンドが書かれていた行の行番号を返す。行番号は1から始まる。
+    /// 現コマンドが空の場合は0を返す
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// 現VMコマンドの種類を返す。算術コマンドは拡張命令も含めてすべて
     /// `CommandType::ARITHMETIC`が返される。
     pub fn command_type(&self) -> CommandType {
         let command = match &self.command {
@@ -76,6 +95,9 @@ impl<R: Read> Parser<R> {
         match word {
             "add" | "sub" | "neg" | "eq" | "gt" 
             | "lt" | "and" | "or" | "not" => CommandType::ARITHMETIC,
+            // 拡張命令。使えるかどうかは変換するときに調べる
+            "mul" | "div" | "shl" | "shr" | "dup" | "swap"
+            | "ge" | "le" | "ne" => CommandType::ARITHMETIC,
             "push" => CommandType::PUSH,
             "pop" => CommandType::POP,
             "label" => CommandType::LABEL,
@@ -98,11 +120,9 @@ impl<R: Read> Parser<R> {
         };
 
         let words: Vec<&str> = command.split(' ')
-                                   .filter(|w| *w != "").collect();
+                                   .filter(|w| !w.is_empty()).collect();
 
-        if words.len() == 3 {
-            return Some(words[1].to_string());
-        } else if words.len() == 2 {
+        if words.len() == 3 || words.len() == 2 {
             return Some(words[1].to_string());
         } else if words.len() == 1 {
             return Some(words[0].to_string());
@@ -121,7 +141,7 @@ impl<R: Read> Parser<R> {
         };
 
         let words: Vec<&str> = command.split(' ')
-                                   .filter(|w| *w != "").collect();
+                                   .filter(|w| !w.is_empty()).collect();
 
         if words.len() == 3 {
             match isize::from_str(words[2]) {
@@ -135,6 +155,7 @@ impl<R: Read> Parser<R> {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod test {
     use super::Parser;
     use super::CommandType;
@@ -224,4 +245,14 @@ mod test {
         parser.advance();
         assert_eq!(parser.arg2(), None);
     }
+
+    #[test]
+    fn test_parser_line() {
+        let mut parser = Parser::new("// A\npush constant 1\n\nadd\n".as_bytes());
+        assert_eq!(parser.line(), 0);
+        parser.advance();
+        assert_eq!(parser.line(), 2);
+        parser.advance();
+        assert_eq!(parser.line(), 4);
+    }
 }
\ No newline at end of file
@@ -2,15 +2,19 @@ use std::io::Read;
 use std::io::BufRead;
 use std::io::BufReader;
 
+use super::cst::Line;
+
 /// 不要な行やコメントを削除したデータを提供する
 pub struct Vmlines<R> {
-    vm: BufReader<R>
+    vm: BufReader<R>,
+    line: usize, // 最後に読んだ行の行番号（1から始まる）
 }
 
 impl<R: Read> Vmlines<R> {
     pub fn new(stream: R) -> Vmlines<R> {
         Vmlines {
-            vm: BufReader::new(stream)
+            vm: BufReader::new(stream),
+            line: 0,
         }
     }
 
@@ -24,23 +28,19 @@ impl<R: Read> Vmlines<R> {
             if self.vm.read_line(&mut vmline).unwrap() == 0 {
                 return None;
             }
+            self.line += 1;
 
-            let mut line = vmline.as_str();
-            let comment: Vec<_> = line.match_indices("//").collect();
-            if comment.len() != 0 {
-              line = &line[..comment[0].0];
-            }
-
-            // 両端の空白や改行を削除
-            line = line.trim();
-
-            if line.len() == 0 {
-                continue;
+            let line = Line::parse(&vmline, self.line);
+            if line.is_command() {
+                return Some(line.code());
             }
-
-            return Some(line.to_string())
         }
     }
+
+    /// 最後に`next()`が返した行の行番号を返す。行番号は1から始まる
+    pub fn line(&self) -> usize {
+        self.line
+    }
 }
 
 #[cfg(test)]
@@ -68,4 +68,21 @@ mod test {
         assert_eq!(lines.next(), Some("VM2".to_string()));
         assert_eq!(lines.next(), None);
     }
+
+    #[test]
+    fn test_vmlines_line() {
+        let mut lines = Vmlines::new("// A\n\npush constant 1\nadd // B\n".as_bytes());
+        assert_eq!(lines.next(), Some("push constant 1".to_string()));
+        assert_eq!(lines.line(), 3);
+        assert_eq!(lines.next(), Some("add".to_string()));
+        assert_eq!(lines.line(), 4);
+    }
+
+    #[test]
+    fn test_vmlines_whitespace() {
+        let mut lines = Vmlines::new("  push\tconstant   1\r\nlabel\tLOOP".as_bytes());
+        assert_eq!(lines.next(), Some("push constant 1".to_string()));
+        assert_eq!(lines.next(), Some("label LOOP".to_string()));
+        assert_eq!(lines.next(), None);
+    }
 }
@@ -0,0 +1,292 @@
+//! 命令数のプロファイラー。
+//! 変換したプログラムをエミュレーターで実行し、実行したHackの命令を
+//! ソースマップでVMコマンドと関数に割り当てて数える。
+//!
+//! 呼び出しの履歴は、callコマンド（ブートストラップを含む）からジャンプして
+//! functionコマンドに入ったときに積み、returnコマンドから出たときに降ろす。
+//! flatは関数自身の命令数、inclusiveは関数から呼び出した関数の命令数も含めた数。
+//!
+//! folded形式はflamegraphなどのツールで使う形式で、１行に呼び出しの履歴と
+//! 命令数を書く
+//! ```text
+//! bootstrap;Sys.init;Main.main 1234
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::Output;
+use crate::hack;
+use crate::emulator::Emulator;
+
+/// テキストのレポートに書くVMコマンドの数
+const TOP_COMMANDS: usize = 20;
+
+/// 関数ごとの結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub flat: u64,
+    pub inclusive: u64,
+    /// 呼び出された回数
+    pub calls: u64,
+}
+
+/// VMコマンドごとの結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandProfile {
+    /// ファイル名。ブートストラップコードの場合は`None`
+    pub file: Option<String>,
+    pub line: usize,
+    pub command: String,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    /// 実行した命令の数
+    pub steps: u64,
+    /// `false`の場合は命令数の上限で打ち切った
+    pub halted: bool,
+    /// flatの多い順
+    pub functions: Vec<FunctionProfile>,
+    /// 命令数の多い順。一度も実行しなかったコマンドは含まない
+    pub commands: Vec<CommandProfile>,
+    /// 呼び出しの履歴と命令数。履歴の順
+    pub folded: Vec<(Vec<String>, u64)>,
+}
+
+/// ソースマップのエントリーのうち、プロファイルに使う情報
+struct EntryInfo {
+    /// エントリーのある関数の番号
+    scope: usize,
+    /// callコマンドかブートストラップコードの場合は`true`
+    call: bool,
+    ret: bool,
+}
+
+/// outputのプログラムを最大max_steps個の命令まで実行して数える
+pub fn profile(output: &Output, max_steps: u64) -> Result<Profile, String> {
+    let rom = hack::assemble(&output.asm)?;
+    let entries = output.source_map.entries();
+
+    // 関数名とその番号
+    let mut names: Vec<String> = Vec::new();
+    let mut ids: HashMap<String, usize> = HashMap::new();
+    let mut intern = |name: &str| -> usize {
+        *ids.entry(name.to_string()).or_insert_with(|| {
+            names.push(name.to_string());
+            names.len() - 1
+        })
+    };
+
+    let mut infos = Vec::new();
+    let mut owner = vec![None; rom.len()];
+    // 関数の最初の命令のアドレスと関数
+    let mut entrances = HashMap::new();
+    for (i, (e, scope)) in entries.iter().zip(output.source_map.scopes()).enumerate() {
+        let scope = intern(scope);
+        let command = e.command.split_whitespace().next();
+        if command == Some("function") {
+            // localのない関数のfunctionコマンドは命令を生成しないので、
+            // 関数の入口はエントリーの開始アドレスで調べる
+            entrances.insert(e.start, scope);
+        }
+        infos.push(EntryInfo {
+            scope,
+            call: e.file.is_none() || command == Some("call"),
+            ret: command == Some("return"),
+        });
+        let end = e.end.min(rom.len());
+        for o in owner.iter_mut().take(end).skip(e.start) {
+            *o = Some(i);
+        }
+    }
+
+    // 無条件ジャンプ（`0;JMP`）の命令。callコマンドはこれで関数へ移る
+    let jumps: Vec<bool> = rom.iter().map(|w| w & 0x8000 != 0 && w & 0b111 == 0b111).collect();
+    let mut emulator = Emulator::new(rom);
+    let mut counts = vec![0u64; entries.len()];
+    let mut calls = vec![0u64; names.len()];
+    let mut stacks: HashMap<Vec<usize>, u64> = HashMap::new();
+    let mut stack: Vec<usize> = Vec::new();
+    // 直前に実行した命令のアドレスとエントリー
+    let mut previous: Option<(usize, usize)> = None;
+    // 今の呼び出しの履歴で実行した命令の数
+    let mut run = 0;
+    let mut steps = 0;
+
+    while steps < max_steps && !emulator.is_halted() {
+        let pc = emulator.pc;
+        if let Some(e) = owner[pc] {
+            if previous.is_none_or(|(_, p)| p != e) {
+                let jumped = previous.is_some_and(|(address, _)| jumps[address]);
+                let entrance = entrances.get(&pc).copied()
+                    .filter(|_| jumped && previous.is_some_and(|(_, p)| infos[p].call));
+                let pop = entrance.is_none() && previous.is_some_and(|(_, p)| infos[p].ret) && stack.len() > 1;
+                if entrance.is_some() || pop || stack.is_empty() {
+                    if run > 0 {
+                        *stacks.entry(stack.clone()).or_insert(0) += run;
+                        run = 0;
+                    }
+                    if pop {
+                        stack.pop();
+                    }
+                    if let Some(f) = entrance {
+                        stack.push(f);
+                        calls[f] += 1;
+                    }
+                    if stack.is_empty() {
+                        stack.push(infos[e].scope);
+                    }
+                }
+            }
+            counts[e] += 1;
+            previous = Some((pc, e));
+        }
+        run += 1;
+        emulator.step();
+        steps += 1;
+    }
+    if run > 0 {
+        *stacks.entry(stack).or_insert(0) += run;
+    }
+
+    let mut flat = vec![0u64; names.len()];
+    let mut inclusive = vec![0u64; names.len()];
+    for (stack, count) in &stacks {
+        if let Some(leaf) = stack.last() {
+            flat[*leaf] += count;
+        }
+        // 再帰呼び出しで同じ関数が何度も現れても一度だけ数える
+        let mut seen = stack.clone();
+        seen.sort();
+        seen.dedup();
+        for f in seen {
+            inclusive[f] += count;
+        }
+    }
+
+    let mut functions: Vec<FunctionProfile> = names.iter().enumerate()
+        .filter(|(i, _)| inclusive[*i] > 0 || calls[*i] > 0)
+        .map(|(i, name)| FunctionProfile {
+            name: name.clone(),
+            flat: flat[i],
+            inclusive: inclusive[i],
+            calls: calls[i],
+        })
+        .collect();
+    functions.sort_by(|a, b| b.flat.cmp(&a.flat).then(b.inclusive.cmp(&a.inclusive)));
+
+    let mut commands: Vec<CommandProfile> = entries.iter().zip(&counts)
+        .filter(|(_, c)| **c > 0)
+        .map(|(e, c)| CommandProfile {
+            file: e.file.clone(),
+            line: e.line,
+            command: e.command.clone(),
+            count: *c,
+        })
+        .collect();
+    commands.sort_by_key(|c| std::cmp::Reverse(c.count));
+
+    let mut folded: Vec<(Vec<String>, u64)> = stacks.into_iter()
+        .map(|(stack, count)| (stack.iter().map(|f| names[*f].clone()).collect(), count))
+        .collect();
+    folded.sort();
+
+    Ok(Profile { steps, halted: emulator.is_halted(), functions, commands, folded })
+}
+
+impl Profile {
+    /// 関数ごとの命令数と、命令数の多いVMコマンドの表
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(text, "{}個の命令を実行しました{}", self.steps,
+                         if self.halted { "" } else { "（上限で打ち切りました）" });
+        let _ = writeln!(text);
+        let _ = writeln!(text, "{:>10} {:>6} {:>10} {:>8}  function",
+                         "flat", "flat%", "inclusive", "calls");
+        let total = self.steps.max(1) as f64;
+        for f in &self.functions {
+            let _ = writeln!(text, "{:>10} {:>5.1}% {:>10} {:>8}  {}", f.flat,
+                             f.flat as f64 * 100.0 / total, f.inclusive, f.calls, f.name);
+        }
+        let _ = writeln!(text);
+        let _ = writeln!(text, "{:>10}  command", "count");
+        for c in self.commands.iter().take(TOP_COMMANDS) {
+            let _ = writeln!(text, "{:>10}  {}:{} {}", c.count,
+                             c.file.as_deref().unwrap_or("-"), c.line, c.command);
+        }
+        text
+    }
+
+    /// flamegraphなどで使うfolded形式
+    pub fn to_folded(&self) -> String {
+        let mut text = String::new();
+        for (stack, count) in &self.folded {
+            let _ = writeln!(text, "{} {}", stack.join(";"), count);
+        }
+        text
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{translate, Input, TranslateOptions};
+
+    #[test]
+    fn test_profile() {
+        let inputs = [
+            Input::new("Sys", concat!(
+                "function Sys.init 0\n",
+                "push constant 3\n",
+                "call Main.twice 1\n",
+                "push constant 4\n",
+                "call Main.twice 1\n",
+                "add\n",
+                "pop static 0\n",
+                "label END\n",
+                "goto END\n",
+            )),
+            Input::new("Main", concat!(
+                "function Main.twice 0\n",
+                "push argument 0\n",
+                "call Main.add 1\n",
+                "return\n",
+                "function Main.add 0\n",
+                "push argument 0\n",
+                "push argument 0\n",
+                "add\n",
+                "return\n",
+            )),
+        ];
+        let output = translate(&inputs, &TranslateOptions::new()).unwrap();
+        let result = profile(&output, 100000).unwrap();
+        assert!(result.halted);
+
+        let function = |name: &str| result.functions.iter().find(|f| f.name == name).unwrap();
+        assert_eq!(function("Sys.init").calls, 1);
+        assert_eq!(function("Main.twice").calls, 2);
+        assert_eq!(function("Main.add").calls, 2);
+        assert_eq!(function("Sys.init").inclusive + function("bootstrap").flat, result.steps);
+        assert_eq!(function("Main.twice").inclusive,
+                   function("Main.twice").flat + function("Main.add").flat);
+
+        let folded = result.to_folded();
+        assert!(folded.contains("bootstrap;Sys.init;Main.twice;Main.add "));
+        let total: u64 = folded.lines()
+            .map(|l| l.rsplit(' ').next().unwrap().parse::<u64>().unwrap()).sum();
+        assert_eq!(total, result.steps);
+
+        // 2回呼ばれるので、Main.addのaddコマンドの命令は2回ずつ実行される
+        let add = result.commands.iter()
+            .find(|c| c.file.as_deref() == Some("Main") && c.line == 8).unwrap();
+        let entry = output.source_map.entries().iter()
+            .find(|e| e.file.as_deref() == Some("Main") && e.line == 8).unwrap();
+        assert_eq!(add.count, 2 * (entry.end - entry.start) as u64);
+
+        assert!(!profile(&output, 10).unwrap().halted);
+    }
+}
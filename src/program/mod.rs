@@ -0,0 +1,512 @@
+//! パースしたVMプログラムを表すデータ構造。
+//! Parserが返す文字列を種類ごとに分けた形にするので、
+//! 変換以外の解析（ラベルの検査など）はこのモジュールのデータを使う
+
+use std::fmt;
+
+use crate::{Parser, CommandType, Input, Diagnostic, Diagnostics};
+use crate::identifier::{self, IdentifierPolicy};
+
+/// `push constant`で扱える値の最大値
+const MAX_CONSTANT: usize = 32767;
+
+/// 受け付けるVMコマンドの範囲
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dialect {
+    /// nand2tetrisの仕様のコマンドだけを受け付ける
+    Strict,
+    /// 仕様のコマンドに加えて拡張命令（mul、div、shl、shr、dup、swap、
+    /// ge、le、ne、indirectセグメント）を受け付ける
+    Extended,
+}
+
+/// メモリセグメント
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Segment {
+    Constant,
+    Local,
+    Argument,
+    This,
+    That,
+    Temp,
+    Pointer,
+    Static,
+    /// 拡張命令。スタックの値をアドレスとして使う
+    Indirect,
+}
+
+impl Segment {
+    pub fn from_name(name: &str) -> Option<Segment> {
+        match name {
+            "constant" => Some(Segment::Constant),
+            "local" => Some(Segment::Local),
+            "argument" => Some(Segment::Argument),
+            "this" => Some(Segment::This),
+            "that" => Some(Segment::That),
+            "temp" => Some(Segment::Temp),
+            "pointer" => Some(Segment::Pointer),
+            "static" => Some(Segment::Static),
+            "indirect" => Some(Segment::Indirect),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Segment::Constant => "constant",
+            Segment::Local => "local",
+            Segment::Argument => "argument",
+            Segment::This => "this",
+            Segment::That => "that",
+            Segment::Temp => "temp",
+            Segment::Pointer => "pointer",
+            Segment::Static => "static",
+            Segment::Indirect => "indirect",
+        }
+    }
+
+    /// 拡張命令のセグメントかどうか
+    pub fn is_extension(&self) -> bool {
+        *self == Segment::Indirect
+    }
+
+    /// indexの最大値。制限がない場合は`None`
+    fn max_index(&self) -> Option<usize> {
+        match self {
+            Segment::Constant => Some(MAX_CONSTANT),
+            Segment::Temp => Some(7),
+            Segment::Pointer => Some(1),
+            _ => None,
+        }
+    }
+}
+
+/// 算術・論理コマンド
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Op {
+    Add,
+    Sub,
+    Neg,
+    Eq,
+    Gt,
+    Lt,
+    And,
+    Or,
+    Not,
+    // ここから拡張命令
+    Mul,
+    Div,
+    Shl,
+    Shr,
+    Dup,
+    Swap,
+    Ge,
+    Le,
+    Ne,
+}
+
+impl Op {
+    pub fn from_name(name: &str) -> Option<Op> {
+        match name {
+            "add" => Some(Op::Add),
+            "sub" => Some(Op::Sub),
+            "neg" => Some(Op::Neg),
+            "eq" => Some(Op::Eq),
+            "gt" => Some(Op::Gt),
+            "lt" => Some(Op::Lt),
+            "and" => Some(Op::And),
+            "or" => Some(Op::Or),
+            "not" => Some(Op::Not),
+            "mul" => Some(Op::Mul),
+            "div" => Some(Op::Div),
+            "shl" => Some(Op::Shl),
+            "shr" => Some(Op::Shr),
+            "dup" => Some(Op::Dup),
+            "swap" => Some(Op::Swap),
+            "ge" => Some(Op::Ge),
+            "le" => Some(Op::Le),
+            "ne" => Some(Op::Ne),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::Add => "add",
+            Op::Sub => "sub",
+            Op::Neg => "neg",
+            Op::Eq => "eq",
+            Op::Gt => "gt",
+            Op::Lt => "lt",
+            Op::And => "and",
+            Op::Or => "or",
+            Op::Not => "not",
+            Op::Mul => "mul",
+            Op::Div => "div",
+            Op::Shl => "shl",
+            Op::Shr => "shr",
+            Op::Dup => "dup",
+            Op::Swap => "swap",
+            Op::Ge => "ge",
+            Op::Le => "le",
+            Op::Ne => "ne",
+        }
+    }
+
+    /// 拡張命令かどうか
+    pub fn is_extension(&self) -> bool {
+        !matches!(self, Op::Add | Op::Sub | Op::Neg | Op::Eq | Op::Gt | Op::Lt
+                  | Op::And | Op::Or | Op::Not)
+    }
+}
+
+/// ひとつのVMコマンド
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Arithmetic(Op),
+    Push(Segment, u16),
+    Pop(Segment, u16),
+    Label(String),
+    Goto(String),
+    IfGoto(String),
+    Function(String, u16),
+    Call(String, u16),
+    Return,
+}
+
+impl Command {
+    /// 拡張命令を使っているかどうか
+    pub fn is_extension(&self) -> bool {
+        match self {
+            Command::Arithmetic(op) => op.is_extension(),
+            Command::Push(s, _) | Command::Pop(s, _) => s.is_extension(),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    /// VMコードの形で書く
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::Arithmetic(op) => write!(f, "{}", op.name()),
+            Command::Push(s, i) => write!(f, "push {} {}", s.name(), i),
+            Command::Pop(s, i) => write!(f, "pop {} {}", s.name(), i),
+            Command::Label(l) => write!(f, "label {}", l),
+            Command::Goto(l) => write!(f, "goto {}", l),
+            Command::IfGoto(l) => write!(f, "if-goto {}", l),
+            Command::Function(name, n) => write!(f, "function {} {}", name, n),
+            Command::Call(name, n) => write!(f, "call {} {}", name, n),
+            Command::Return => write!(f, "return"),
+        }
+    }
+}
+
+/// 行番号付きのコマンド
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub line: usize,
+    pub command: Command,
+}
+
+/// ひとつのvmファイル
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmFile {
+    pub name: String,
+    pub statements: Vec<Statement>,
+}
+
+/// ファイル内のひとつの関数
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function<'a> {
+    /// 関数名。最初のfunctionコマンドより前の部分は`None`
+    pub name: Option<&'a str>,
+    /// localの数
+    pub locals: u16,
+    /// functionコマンドの行番号。最初のfunctionコマンドより前の部分は0
+    pub line: usize,
+    /// functionコマンドの次から、次のfunctionコマンドの前までのコマンド
+    pub body: &'a [Statement],
+}
+
+/// 変換するすべてのvmファイル
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub files: Vec<VmFile>,
+}
+
+/// 現コマンドの第２引数を0以上max以下の数値として返す
+fn arg2<R: std::io::Read>(p: &Parser<R>, max: usize) -> Result<u16, String> {
+    let command = p.command().unwrap_or("");
+    match p.arg2() {
+        Some(n) if n >= 0 && n as usize <= max => Ok(n as u16),
+        Some(_) => Err(format!("{} の数値は0から{}までです", command, max)),
+        None => Err(format!("{} の引数が正しくありません", command)),
+    }
+}
+
+/// 現コマンドの第１引数をラベルもしくは関数名として返す。
+/// policyが`IdentifierPolicy::Strict`の場合は識別子の規則を検査する
+fn identifier<R: std::io::Read>(p: &Parser<R>, policy: IdentifierPolicy)
+    -> Result<String, String>
+{
+    let name = p.arg1().unwrap_or_default();
+    if policy == IdentifierPolicy::Strict {
+        if let Err(e) = identifier::validate(&name) {
+            return Err(format!("{}: {}", p.command().unwrap_or(""), e));
+        }
+    }
+    Ok(name)
+}
+
+/// Parserの現コマンドをCommandにする。
+/// dialectが`Dialect::Strict`の場合は拡張命令をエラーにする
+fn parse_command<R: std::io::Read>(p: &Parser<R>, policy: IdentifierPolicy,
+                                   dialect: Dialect) -> Result<Command, String>
+{
+    let arg1 = p.arg1().unwrap_or_default();
+    let command = match p.command_type() {
+        CommandType::ARITHMETIC => match Op::from_name(&arg1) {
+            Some(op) => Command::Arithmetic(op),
+            None => return Err(format!("{} は無効なコマンドです", arg1)),
+        },
+        CommandType::PUSH | CommandType::POP => {
+            let segment = match Segment::from_name(&arg1) {
+                Some(s) => s,
+                None => return Err(format!("{} は無効なセグメントです", arg1)),
+            };
+            let index = arg2(p, segment.max_index().unwrap_or(MAX_CONSTANT))?;
+            if p.command_type() == CommandType::PUSH {
+                Command::Push(segment, index)
+            } else if segment == Segment::Constant {
+                return Err("pop constant は無効なコマンドです".to_string());
+            } else {
+                Command::Pop(segment, index)
+            }
+        },
+        CommandType::LABEL => Command::Label(identifier(p, policy)?),
+        CommandType::GOTO => Command::Goto(identifier(p, policy)?),
+        CommandType::IF => Command::IfGoto(identifier(p, policy)?),
+        CommandType::FUNCTION => {
+            Command::Function(identifier(p, policy)?, arg2(p, MAX_CONSTANT)?)
+        },
+        CommandType::CALL => {
+            Command::Call(identifier(p, policy)?, arg2(p, MAX_CONSTANT)?)
+        },
+        CommandType::RETURN => Command::Return,
+        CommandType::None => return Err(format!("{} は無効なコマンドです", arg1)),
+    };
+    if dialect == Dialect::Strict && command.is_extension() {
+        return Err(extension_error(&command.to_string()));
+    }
+    Ok(command)
+}
+
+/// 拡張命令を`Dialect::Strict`で使ったときのエラーメッセージ
+pub(crate) fn extension_error(command: &str) -> String {
+    format!("{} は拡張命令です。使うには拡張命令を有効にしてください", command)
+}
+
+impl VmFile {
+    /// vmファイルをパースする。エラーがあった場合はすべてのエラーを集めて返す
+    pub fn parse(input: &Input) -> Result<VmFile, Diagnostics> {
+        VmFile::parse_with(input, IdentifierPolicy::Strict, Dialect::Strict)
+    }
+
+    /// `parse`と同じだが、ラベルと関数名の扱いをpolicyで、
+    /// 受け付けるコマンドの範囲をdialectで指定する
+    pub fn parse_with(input: &Input, policy: IdentifierPolicy, dialect: Dialect)
+        -> Result<VmFile, Diagnostics>
+    {
+        let mut statements = Vec::new();
+        let mut diagnostics = Diagnostics::new();
+
+        let mut parser = Parser::new(input.source.as_bytes());
+        while parser.has_more_commands() {
+            parser.advance();
+            match parse_command(&parser, policy, dialect) {
+                Ok(command) => statements.push(Statement {
+                    line: parser.line(),
+                    command,
+                }),
+                Err(e) => diagnostics.push(Diagnostic::at(&input.name,
+                                                          parser.line(), &e)),
+            }
+        }
+
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+        Ok(VmFile { name: input.name.clone(), statements })
+    }
+}
+
+impl VmFile {
+    /// VMコードのテキストに戻す。各コマンドは元の行番号の行に書き、
+    /// 間は空行にするので、エラーやソースマップの行番号は元のファイルと同じになる
+    pub fn to_input(&self) -> Input {
+        let mut source = String::new();
+        let mut line = 1;
+        for s in &self.statements {
+            while line < s.line {
+                source.push('\n');
+                line += 1;
+            }
+            source += &s.command.to_string();
+            source.push('\n');
+            line += 1;
+        }
+        Input::new(&self.name, &source)
+    }
+
+    /// ファイルを関数ごとに分ける。最初のfunctionコマンドより前に
+    /// コマンドがある場合は、その部分を名前のない関数として最初に返す
+    pub fn functions(&self) -> Vec<Function<'_>> {
+        let mut functions = Vec::new();
+        let mut current = Function { name: None, locals: 0, line: 0, body: &[] };
+        let mut start = 0;
+        for (i, s) in self.statements.iter().enumerate() {
+            if let Command::Function(name, locals) = &s.command {
+                current.body = &self.statements[start..i];
+                if current.name.is_some() || !current.body.is_empty() {
+                    functions.push(current);
+                }
+                current = Function { name: Some(name), locals: *locals,
+                                     line: s.line, body: &[] };
+                start = i + 1;
+            }
+        }
+        current.body = &self.statements[start..];
+        if current.name.is_some() || !current.body.is_empty() {
+            functions.push(current);
+        }
+        functions
+    }
+}
+
+impl Program {
+    /// すべてのvmファイルをパースする
+    pub fn parse(inputs: &[Input]) -> Result<Program, Diagnostics> {
+        Program::parse_with(inputs, IdentifierPolicy::Strict, Dialect::Strict)
+    }
+
+    /// `parse`と同じだが、ラベルと関数名の扱いをpolicyで、
+    /// 受け付けるコマンドの範囲をdialectで指定する
+    pub fn parse_with(inputs: &[Input], policy: IdentifierPolicy, dialect: Dialect)
+        -> Result<Program, Diagnostics>
+    {
+        let mut files = Vec::new();
+        let mut diagnostics = Diagnostics::new();
+        for input in inputs {
+            match VmFile::parse_with(input, policy, dialect) {
+                Ok(file) => files.push(file),
+                Err(e) => diagnostics.extend(e),
+            }
+        }
+
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+        Ok(Program { files })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_vm_file_parse() {
+        let input = Input::new("Main", concat!(
+            "function Main.main 1\n",
+            "  push   constant 7 // seven\n",
+            "pop local 0\n",
+            "label LOOP\n",
+            "if-goto LOOP\n",
+            "call Math.abs 1\n",
+            "add\n",
+            "return\n",
+        ));
+        let file = VmFile::parse(&input).unwrap();
+
+        assert_eq!(file.statements[1], Statement {
+            line: 2,
+            command: Command::Push(Segment::Constant, 7),
+        });
+        let text: Vec<String> = file.statements.iter()
+                                    .map(|s| s.command.to_string()).collect();
+        assert_eq!(text, vec!["function Main.main 1", "push constant 7",
+                              "pop local 0", "label LOOP", "if-goto LOOP",
+                              "call Math.abs 1", "add", "return"]);
+    }
+
+    #[test]
+    fn test_vm_file_functions() {
+        let input = Input::new("Main", concat!(
+            "push constant 1\n",
+            "function A 2\n",
+            "return\n",
+            "function B 0\n",
+        ));
+        let file = VmFile::parse(&input).unwrap();
+        let functions = file.functions();
+        assert_eq!(functions.len(), 3);
+        assert_eq!((functions[0].name, functions[0].body.len()), (None, 1));
+        assert_eq!((functions[1].name, functions[1].locals, functions[1].line),
+                   (Some("A"), 2, 2));
+        assert_eq!(functions[1].body[0].command, Command::Return);
+        assert!(functions[2].body.is_empty());
+    }
+
+    #[test]
+    fn test_vm_file_parse_errors() {
+        let input = Input::new("Main", concat!(
+            "push constant 40000\n",
+            "pop constant 0\n",
+            "push temp 8\n",
+            "push nowhere 0\n",
+            "push local\n",
+            "foo\n",
+        ));
+        let d = VmFile::parse(&input).unwrap_err();
+        let lines: Vec<usize> = d.iter().map(|d| d.line.unwrap()).collect();
+        assert_eq!(lines, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_vm_file_parse_identifiers() {
+        let input = Input::new("Main", concat!(
+            "label (LOOP)\n",
+            "goto 1st\n",
+            "function Main.main 0\n",
+            "call Math@abs 1\n",
+        ));
+        let d = VmFile::parse(&input).unwrap_err();
+        let messages: Vec<(usize, String)> = d.iter()
+            .map(|d| (d.line.unwrap(), d.message.clone())).collect();
+        assert_eq!(messages, vec![
+            (1, "label (LOOP): (LOOP) の1文字目 '(' は識別子に使えない文字です".to_string()),
+            (2, "goto 1st: 1st は数字で始まっています".to_string()),
+            (4, "call Math@abs 1: Math@abs の5文字目 '@' は識別子に使えない文字です".to_string()),
+        ]);
+
+        let file = VmFile::parse_with(&input, IdentifierPolicy::Escape, Dialect::Strict)
+            .unwrap();
+        assert_eq!(file.statements[0].command, Command::Label("(LOOP)".to_string()));
+    }
+
+    #[test]
+    fn test_vm_file_parse_dialect() {
+        let input = Input::new("Main", "swap\npush indirect 1\npop indirect 0\nge\n");
+        let d = VmFile::parse(&input).unwrap_err();
+        let messages: Vec<String> = d.iter().map(|d| d.message.clone()).collect();
+        assert_eq!(messages[0], "swap は拡張命令です。使うには拡張命令を有効にしてください");
+        assert_eq!(messages.len(), 4);
+
+        let file = VmFile::parse_with(&input, IdentifierPolicy::Strict, Dialect::Extended)
+            .unwrap();
+        assert_eq!(file.statements[1].command, Command::Push(Segment::Indirect, 1));
+        assert!(file.statements.iter().all(|s| s.command.is_extension()));
+    }
+}
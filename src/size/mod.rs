@@ -0,0 +1,213 @@
+//! 生成したコードの大きさ。
+//! ソースマップから、関数、ファイル、VMコマンドの種類ごとのHackの命令数を数える。
+//! Hackの命令はROMに置かれ、ROMに置ける命令は32768個までになる
+//!
+//! テキスト形式のレポートでは、それぞれ命令数の多い順に１行にひとつずつ
+//! 命令数と名前を書き、最後に合計を書く
+//! ```text
+//! # functions
+//! 1234 Main.main
+//! # files
+//! 1500 Main
+//! # commands
+//! 800 call
+//! # total 1634/32768
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::Diagnostic;
+use crate::hack;
+use crate::source_map::SourceMap;
+
+/// ROMに置ける命令の数
+pub const ROM_SIZE: usize = 32768;
+
+/// ROMに入らないときのエラーに書く関数の数
+const LARGEST: usize = 5;
+
+/// 名前ごとの命令数を命令数の多い順に並べたもの
+pub type Sizes = Vec<(String, usize)>;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SizeReport {
+    /// 関数ごとの命令数。ブートストラップコードは`bootstrap`
+    pub functions: Sizes,
+    /// ファイルごとの命令数。ブートストラップコードは`-`
+    pub files: Sizes,
+    /// VMコマンドの種類（`push local`、`call`、`eq`など）ごとの命令数
+    pub commands: Sizes,
+    pub total: usize,
+}
+
+/// VMコマンドの種類。pushとpopはセグメントも含める
+fn kind(command: &str) -> String {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    match words.as_slice() {
+        [op @ ("push" | "pop"), segment, ..] => format!("{} {}", op, segment),
+        [first, ..] => first.to_string(),
+        [] => String::new(),
+    }
+}
+
+/// 名前ごとの命令数を数える。名前は最初に現れた順に並べておく
+#[derive(Default)]
+struct Counter {
+    sizes: Sizes,
+    /// 名前からsizesの位置への表
+    index: HashMap<String, usize>,
+}
+
+impl Counter {
+    /// nameの命令数にsizeを足す
+    fn add(&mut self, name: &str, size: usize) {
+        match self.index.get(name) {
+            Some(i) => self.sizes[*i].1 += size,
+            None => {
+                self.index.insert(name.to_string(), self.sizes.len());
+                self.sizes.push((name.to_string(), size));
+            },
+        }
+    }
+
+    /// 命令数の多い順に並べる。同じ場合は先に現れた順
+    fn sorted(self) -> Sizes {
+        let mut sizes = self.sizes;
+        sizes.retain(|(_, n)| *n > 0);
+        sizes.sort_by_key(|(_, n)| std::cmp::Reverse(*n));
+        sizes
+    }
+}
+
+impl SizeReport {
+    pub fn from_source_map(map: &SourceMap) -> SizeReport {
+        let mut functions = Counter::default();
+        let mut files = Counter::default();
+        let mut commands = Counter::default();
+        let mut total = 0;
+        for (e, scope) in map.entries().iter().zip(map.scopes()) {
+            let size = e.end - e.start;
+            functions.add(scope, size);
+            files.add(e.file.as_deref().unwrap_or("-"), size);
+            commands.add(&kind(&e.command), size);
+            total += size;
+        }
+
+        SizeReport {
+            functions: functions.sorted(),
+            files: files.sorted(),
+            commands: commands.sorted(),
+            total,
+        }
+    }
+
+    /// 命令がROMに入らない場合は、命令の多い関数を書いたエラーを返す
+    pub fn check(&self) -> Result<(), Diagnostic> {
+        if self.total <= ROM_SIZE {
+            return Ok(());
+        }
+
+        let largest: Vec<String> = self.functions.iter().take(LARGEST)
+            .map(|(name, n)| format!("{} {}", name, n))
+            .collect();
+        Err(Diagnostic::new(&format!(
+            "命令が{}個あり、ROMに置ける{}個を超えています。命令の多い関数: {}",
+            self.total, ROM_SIZE, largest.join(", "))))
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (title, sizes) in [("functions", &self.functions), ("files", &self.files),
+                               ("commands", &self.commands)] {
+            let _ = writeln!(text, "# {}", title);
+            for (name, n) in sizes {
+                let _ = writeln!(text, "{} {}", n, name);
+            }
+        }
+        let _ = writeln!(text, "# total {}/{}", self.total, ROM_SIZE);
+        text
+    }
+}
+
+/// アドレスが`@value`命令で扱える32767を超えるラベルがある場合はエラーを返す
+pub fn check_labels(asm: &str) -> Result<(), Diagnostic> {
+    match hack::labels(asm).into_iter().find(|(_, a)| *a >= ROM_SIZE) {
+        Some((label, address)) => Err(Diagnostic::new(&format!(
+            "ラベル {} のアドレス{}が{}を超えています",
+            label, address, ROM_SIZE - 1))),
+        None => Ok(()),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{translate, Input, TranslateOptions};
+
+    #[test]
+    fn test_size_report() {
+        let inputs = [
+            Input::new("Main", concat!(
+                "function Main.main 1\n",
+                "push local 0\n",
+                "push local 0\n",
+                "eq\n",
+                "call Main.f 1\n",
+                "return\n",
+                "function Main.f 0\n",
+                "push constant 0\n",
+                "return\n",
+            )),
+        ];
+        let output = translate(&inputs, &TranslateOptions::new().bootstrap(false)).unwrap();
+        let report = SizeReport::from_source_map(&output.source_map);
+
+        assert_eq!(report.total, output.instruction_count());
+        // Sys.initを呼ばなくてもSPを設定するブートストラップコードはある
+        assert_eq!(report.files, vec![("Main".to_string(), report.total - 4),
+                                      ("-".to_string(), 4)]);
+        assert_eq!(report.functions.iter().map(|(_, n)| n).sum::<usize>(), report.total);
+        assert_eq!(report.functions[0].0, "Main.main");
+        assert!(report.commands.iter().any(|(k, _)| k == "push local"));
+        assert!(report.commands.iter().all(|(k, _)| k != "push"));
+        assert!(report.commands.windows(2).all(|w| w[0].1 >= w[1].1));
+        assert!(report.check().is_ok());
+
+        let text = report.to_text();
+        assert!(text.starts_with("# functions\n"));
+        assert!(text.ends_with(&format!("# total {}/32768\n", report.total)));
+    }
+
+    #[test]
+    fn test_counter() {
+        let mut counter = Counter::default();
+        counter.add("b", 1);
+        counter.add("a", 2);
+        counter.add("c", 0);
+        counter.add("b", 1);
+        assert_eq!(counter.sorted(), vec![("b".to_string(), 2), ("a".to_string(), 2)]);
+    }
+
+    #[test]
+    fn test_rom_overflow() {
+        // eqは1つで20命令以上になる
+        let big: String = (0..1700).map(|_| "push constant 0\npush constant 0\neq\n").collect();
+        let inputs = [
+            Input::new("Main", &format!(
+                "function Main.small 0\npush constant 0\nreturn\nfunction Main.big 0\n{}",
+                big)),
+        ];
+        let e = translate(&inputs, &TranslateOptions::new()).unwrap_err().to_string();
+        assert!(e.contains("ROMに置ける32768個を超えています"), "{}", e);
+        assert!(e.contains("命令の多い関数: Main.big "), "{}", e);
+        let output = translate(&inputs, &TranslateOptions::new().rom_limit(false)).unwrap();
+        assert!(output.size.total > ROM_SIZE);
+
+        let asm: String = (0..ROM_SIZE).map(|_| "D=0 \n").collect();
+        assert!(check_labels(&asm).is_ok());
+        let e = check_labels(&(asm + "(END) \n")).unwrap_err();
+        assert_eq!(e.message, "ラベル END のアドレス32768が32767を超えています");
+    }
+}
@@ -0,0 +1,148 @@
+//! 生成したHackの命令のアドレス（ROMの番地）から、元のVMコマンドの
+//! ファイル名、行番号、コマンドを調べるためのソースマップ
+//!
+//! テキスト形式では１行にひとつのVMコマンドを次の形で書く。
+//! アドレスはstart以上end未満で、ブートストラップコードのファイル名は`-`になる
+//! ```text
+//! start end file line command
+//! ```
+
+use std::fmt::Write;
+
+use crate::json::Json;
+
+/// ひとつのVMコマンドから生成された命令の範囲
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapEntry {
+    /// 最初の命令のアドレス
+    pub start: usize,
+    /// 最後の命令の次のアドレス。labelコマンドのように命令を生成しない
+    /// コマンドの場合はstartと同じになる
+    pub end: usize,
+    /// ファイル名。ブートストラップコードの場合は`None`
+    pub file: Option<String>,
+    pub line: usize,
+    pub command: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SourceMap {
+    entries: Vec<MapEntry>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap { entries: Vec::new() }
+    }
+
+    /// エントリーを追加する。エントリーはアドレス順に追加する
+    pub fn push(&mut self, entry: MapEntry) {
+        self.entries.push(entry);
+    }
+
+    pub fn entries(&self) -> &[MapEntry] {
+        &self.entries
+    }
+
+    /// addressの命令を生成したVMコマンドを返す
+    pub fn lookup(&self, address: usize) -> Option<&MapEntry> {
+        // endがaddressより大きい最初のエントリー
+        let i = self.entries.partition_point(|e| e.end <= address);
+        self.entries.get(i).filter(|e| e.start <= address)
+    }
+
+    /// 各エントリーのある関数の名前。最初のfunctionコマンドより前のコマンドは
+    /// ファイル名、ブートストラップコードは`bootstrap`になる
+    pub fn scopes(&self) -> Vec<&str> {
+        let mut scopes = Vec::with_capacity(self.entries.len());
+        let mut scope = None;
+        let mut file = None;
+        for e in &self.entries {
+            if e.file.as_deref() != file {
+                file = e.file.as_deref();
+                scope = None;
+            }
+            let mut words = e.command.split_whitespace();
+            if words.next() == Some("function") {
+                scope = words.next().or(scope);
+            }
+            scopes.push(*scope.get_or_insert(file.unwrap_or("bootstrap")));
+        }
+        scopes
+    }
+
+    pub fn to_json(&self) -> Json {
+        let mappings = self.entries.iter().map(|e| Json::object(vec![
+            ("start", Json::Number(e.start as i64)),
+            ("end", Json::Number(e.end as i64)),
+            ("file", match &e.file {
+                Some(f) => Json::string(f),
+                None => Json::Null,
+            }),
+            ("line", Json::Number(e.line as i64)),
+            ("command", Json::string(&e.command)),
+        ])).collect();
+
+        Json::object(vec![
+            ("version", Json::Number(1)),
+            ("mappings", Json::Array(mappings)),
+        ])
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for e in &self.entries {
+            let _ = writeln!(text, "{} {} {} {} {}", e.start, e.end,
+                             e.file.as_deref().unwrap_or("-"), e.line, e.command);
+        }
+        text
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(start: usize, end: usize, command: &str) -> MapEntry {
+        MapEntry {
+            start,
+            end,
+            file: Some("Main".to_string()),
+            line: 1,
+            command: command.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_source_map_lookup() {
+        let mut map = SourceMap::new();
+        map.push(entry(0, 7, "push constant 1"));
+        map.push(entry(7, 7, "label A"));
+        map.push(entry(7, 17, "add"));
+
+        assert_eq!(map.lookup(0).unwrap().command, "push constant 1");
+        assert_eq!(map.lookup(6).unwrap().command, "push constant 1");
+        assert_eq!(map.lookup(7).unwrap().command, "add");
+        assert_eq!(map.lookup(17), None);
+    }
+
+    #[test]
+    fn test_source_map_scopes() {
+        let mut map = SourceMap::new();
+        map.push(MapEntry { file: None, line: 0, ..entry(0, 4, "bootstrap") });
+        map.push(entry(4, 11, "push constant 1"));
+        map.push(entry(11, 11, "function Main.f 0"));
+        map.push(entry(11, 20, "return"));
+        map.push(MapEntry { file: Some("Sys".to_string()), ..entry(20, 21, "goto A") });
+        assert_eq!(map.scopes(), vec!["bootstrap", "Main", "Main.f", "Main.f", "Sys"]);
+    }
+
+    #[test]
+    fn test_source_map_text() {
+        let mut map = SourceMap::new();
+        map.push(MapEntry { file: None, line: 0, ..entry(0, 4, "bootstrap") });
+        map.push(entry(4, 11, "push constant 1"));
+        assert_eq!(map.to_text(), "0 4 - 0 bootstrap\n4 11 Main 1 push constant 1\n");
+    }
+}
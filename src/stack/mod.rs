@@ -0,0 +1,180 @@
+//! スタックの高さの検査。
+//! 各VMコマンドがスタックの値をいくつ使い、いくつ積むかは決まっているので、
+//! 関数の最初の高さを0として各コマンドの前の高さをデータフロー解析で求め、
+//! 次のものを報告する
+//! * ラベルに複数の経路から到達するときに高さが一致しない
+//! * スタックにある値より多くの値を使う（関数のスタックが空のときの`add`など）
+//! * returnのときにスタックにある値が１つではない
+
+use std::collections::HashMap;
+
+use crate::{Diagnostic, Diagnostics};
+use crate::program::{VmFile, Function, Command, Op, Segment};
+
+/// コマンドが使う値の数と積む値の数
+pub fn effect(command: &Command) -> (usize, usize) {
+    match command {
+        Command::Arithmetic(Op::Neg) | Command::Arithmetic(Op::Not) => (1, 1),
+        Command::Arithmetic(Op::Dup) => (1, 2),
+        Command::Arithmetic(Op::Swap) => (2, 2),
+        Command::Arithmetic(_) => (2, 1),
+        Command::Push(Segment::Indirect, _) => (1, 1),
+        Command::Pop(Segment::Indirect, _) => (2, 0),
+        Command::Push(_, _) => (0, 1),
+        Command::Pop(_, _) | Command::IfGoto(_) => (1, 0),
+        Command::Call(_, n) => (*n as usize, 1),
+        Command::Return => (1, 0),
+        Command::Label(_) | Command::Goto(_) | Command::Function(_, _) => (0, 0),
+    }
+}
+
+/// 関数の解析結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    /// 各コマンドの前のスタックの高さ。到達しないコマンドは`None`
+    pub heights: Vec<Option<usize>>,
+    pub diagnostics: Diagnostics,
+}
+
+impl Analysis {
+    /// ラベルの行番号とそのラベルでのスタックの高さ
+    pub fn labels<'a>(&self, function: &'a Function) -> Vec<(&'a str, usize, Option<usize>)> {
+        function.body.iter().zip(&self.heights).filter_map(|(s, h)| match &s.command {
+            Command::Label(l) => Some((l.as_str(), s.line, *h)),
+            _ => None,
+        }).collect()
+    }
+}
+
+/// ひとつの関数を解析する
+pub fn analyze(file: &str, function: &Function) -> Analysis {
+    let body = function.body;
+    let labels: HashMap<&str, usize> = body.iter().enumerate()
+        .filter_map(|(i, s)| match &s.command {
+            Command::Label(l) => Some((l.as_str(), i)),
+            _ => None,
+        })
+        .collect();
+
+    let mut heights: Vec<Option<usize>> = vec![None; body.len()];
+    let mut diagnostics = Diagnostics::new();
+    let mut mismatched = vec![false; body.len()];
+    let mut work = Vec::new();
+    if !body.is_empty() {
+        heights[0] = Some(0);
+        work.push(0);
+    }
+
+    while let Some(i) = work.pop() {
+        let s = &body[i];
+        let height = heights[i].unwrap();
+        let (used, pushed) = effect(&s.command);
+        if height < used {
+            diagnostics.push(Diagnostic::at(file, s.line, &format!(
+                "{} はスタックの値を{}個使いますが、{}個しかありません",
+                s.command, used, height)));
+        }
+        if s.command == Command::Return && height != 1 {
+            diagnostics.push(Diagnostic::at(file, s.line, &format!(
+                "return のときスタックに値が{}個あります。1個でなければなりません",
+                height)));
+        }
+        let next = height.saturating_sub(used) + pushed;
+
+        let mut successors = Vec::new();
+        match &s.command {
+            Command::Return => {},
+            Command::Goto(l) => successors.extend(labels.get(l.as_str())),
+            Command::IfGoto(l) => {
+                successors.push(i + 1);
+                successors.extend(labels.get(l.as_str()));
+            },
+            _ => successors.push(i + 1),
+        }
+
+        for j in successors.into_iter().filter(|j| *j < body.len()) {
+            match heights[j] {
+                None => {
+                    heights[j] = Some(next);
+                    work.push(j);
+                },
+                Some(h) if h != next && !mismatched[j] => {
+                    mismatched[j] = true;
+                    diagnostics.push(Diagnostic::at(file, body[j].line, &format!(
+                        "{} に到達するときのスタックの高さが一致しません（{}と{}）",
+                        body[j].command, h, next)));
+                },
+                Some(_) => {},
+            }
+        }
+    }
+
+    Analysis { heights, diagnostics }
+}
+
+/// ファイル内のすべての関数を検査する。
+/// 最初のfunctionコマンドより前のコマンドは検査しない
+pub fn check_stack(file: &VmFile) -> Diagnostics {
+    let mut diagnostics = Diagnostics::new();
+    for function in file.functions().iter().filter(|f| f.name.is_some()) {
+        diagnostics.extend(analyze(&file.name, function).diagnostics);
+    }
+    diagnostics
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Input;
+
+    fn check(source: &str) -> Vec<(usize, String)> {
+        let file = VmFile::parse(&Input::new("Main", source)).unwrap();
+        check_stack(&file).iter()
+            .map(|d| (d.line.unwrap(), d.message.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_check_stack_ok() {
+        let source = concat!(
+            "function Main.max 0\n",
+            "push argument 0\n",
+            "push argument 1\n",
+            "gt\n",
+            "if-goto A\n",
+            "push argument 1\n",
+            "goto END\n",
+            "label A\n",
+            "push argument 0\n",
+            "label END\n",
+            "call Math.abs 1\n",
+            "return\n",
+        );
+        assert!(check(source).is_empty());
+
+        let file = VmFile::parse(&Input::new("Main", source)).unwrap();
+        let functions = file.functions();
+        let analysis = analyze("Main", &functions[0]);
+        assert_eq!(analysis.labels(&functions[0]),
+                   vec![("A", 8, Some(0)), ("END", 10, Some(1))]);
+    }
+
+    #[test]
+    fn test_check_stack_errors() {
+        let d = check(concat!(
+            "function Main.main 0\n",
+            "add\n",
+            "push constant 1\n",
+            "if-goto L\n",
+            "push constant 2\n",
+            "label L\n",
+            "push constant 3\n",
+            "return\n",
+        ));
+        assert_eq!(d.len(), 3);
+        assert_eq!(d[0], (2, "add はスタックの値を2個使いますが、0個しかありません".to_string()));
+        assert!(d.iter().any(|(line, m)| *line == 6 && m.contains("一致しません")));
+        assert!(d.iter().any(|(line, m)| *line == 8 && m.contains("値が")));
+    }
+}
@@ -0,0 +1,151 @@
+//! staticセグメントの割り当て。
+//! staticセグメントは`@ファイル名.index`のsymbolで表し、RAMの割り当ては
+//! アセンブラに任せている。アセンブラは変数を最初に使われた順にRAM[16]から
+//! 割り当てるので、RAM[256]からのスタックと重ならないように使える変数は
+//! 240個（RAM[16]〜RAM[255]）までになる
+//!
+//! テキスト形式のレポートでは１行にひとつの変数を次の形で書き、
+//! 最後にファイルごとの個数と合計を書く
+//! ```text
+//! address symbol
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::hack;
+use crate::Diagnostic;
+
+/// 最初の変数のアドレス
+pub const BASE_ADDRESS: usize = 16;
+/// 使える変数の数
+pub const MAX_STATICS: usize = 240;
+
+/// ひとつの変数
+#[derive(Debug, Clone, PartialEq)]
+pub struct StaticEntry {
+    /// アセンブラが割り当てるRAMのアドレス
+    pub address: usize,
+    pub symbol: String,
+    /// symbolが`ファイル名.index`の形の場合はファイル名
+    pub file: Option<String>,
+}
+
+/// プログラム全体の変数の割り当て
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct StaticMap {
+    entries: Vec<StaticEntry>,
+}
+
+/// symbolが`ファイル名.index`の形の場合はファイル名を返す
+fn file_of(symbol: &str) -> Option<&str> {
+    let (file, index) = symbol.rsplit_once('.')?;
+    if !file.is_empty() && !index.is_empty()
+        && index.chars().all(|c| c.is_ascii_digit()) {
+        Some(file)
+    } else {
+        None
+    }
+}
+
+impl StaticMap {
+    /// リンクしたアセンブリコードから変数の割り当てを調べる
+    pub fn from_asm(asm: &str) -> StaticMap {
+        let entries = hack::variables(asm).into_iter().enumerate()
+            .map(|(i, symbol)| StaticEntry {
+                address: BASE_ADDRESS + i,
+                symbol: symbol.to_string(),
+                file: file_of(symbol).map(|f| f.to_string()),
+            })
+            .collect();
+        StaticMap { entries }
+    }
+
+    pub fn entries(&self) -> &[StaticEntry] {
+        &self.entries
+    }
+
+    /// 変数の数
+    pub fn total(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// ファイルごとの変数の数。ファイルは最初に変数が割り当てられた順に並べる。
+    /// `ファイル名.index`の形でないsymbolは`None`にまとめる
+    pub fn per_file(&self) -> Vec<(Option<&str>, usize)> {
+        let mut counts: Vec<(Option<&str>, usize)> = Vec::new();
+        // ファイルからcountsの位置への表
+        let mut index: HashMap<Option<&str>, usize> = HashMap::new();
+        for e in &self.entries {
+            let file = e.file.as_deref();
+            match index.get(&file) {
+                Some(i) => counts[*i].1 += 1,
+                None => {
+                    index.insert(file, counts.len());
+                    counts.push((file, 1));
+                },
+            }
+        }
+        counts
+    }
+
+    /// 変数がRAM[255]を超える場合はエラーを返す
+    pub fn check(&self) -> Result<(), Diagnostic> {
+        if self.total() <= MAX_STATICS {
+            return Ok(());
+        }
+
+        let files: Vec<String> = self.per_file().iter()
+            .map(|(f, n)| format!("{} {}", f.unwrap_or("-"), n))
+            .collect();
+        Err(Diagnostic::new(&format!(
+            "static変数が{}個あり、スタックの領域と重なります。\
+             使えるのはRAM[{}]からの{}個までです（{}）",
+            self.total(), BASE_ADDRESS, MAX_STATICS, files.join(", "))))
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for e in &self.entries {
+            let _ = writeln!(text, "{} {}", e.address, e.symbol);
+        }
+        for (file, n) in self.per_file() {
+            let _ = writeln!(text, "# {} {}", file.unwrap_or("-"), n);
+        }
+        let _ = writeln!(text, "# total {}/{}", self.total(), MAX_STATICS);
+        text
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_static_map() {
+        let map = StaticMap::from_asm(concat!(
+            "@Main.3 \n", "@Foo.0 \n", "@Main.0 \n", "@Main.3 \n", "@Foo.bar \n",
+        ));
+        assert_eq!(map.total(), 4);
+        assert_eq!(map.entries()[2], StaticEntry {
+            address: 18,
+            symbol: "Main.0".to_string(),
+            file: Some("Main".to_string()),
+        });
+        assert_eq!(map.per_file(), vec![(Some("Main"), 2), (Some("Foo"), 1), (None, 1)]);
+        assert!(map.check().is_ok());
+        assert_eq!(map.to_text(), concat!(
+            "16 Main.3\n", "17 Foo.0\n", "18 Main.0\n", "19 Foo.bar\n",
+            "# Main 2\n", "# Foo 1\n", "# - 1\n", "# total 4/240\n",
+        ));
+    }
+
+    #[test]
+    fn test_static_map_check() {
+        let asm: String = (0..241).map(|i| format!("@Main.{} \n", i)).collect();
+        let e = StaticMap::from_asm(&asm).check().unwrap_err();
+        assert!(e.message.contains("241個"));
+        assert!(e.message.contains("Main 241"));
+    }
+}
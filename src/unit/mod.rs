@@ -0,0 +1,362 @@
+//! ファイルごとの変換（翻訳単位）と、その結果をつなげる処理（リンク）。
+//! 翻訳単位のsymbolはファイル名で区別されるので、各ファイルは他のファイルと
+//! 関係なく変換でき、その結果をキャッシュできる
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use crate::{CodeWriter, Parser, Input, TranslateOptions, OptLevel, Output,
+            CommentStyle, Diagnostic, Diagnostics, vm_command_to_asm};
+use crate::optimizer;
+use crate::hack;
+use crate::source_map::{SourceMap, MapEntry};
+use crate::statics::StaticMap;
+use crate::size::SizeReport;
+use crate::program::VmFile;
+use crate::labels;
+use crate::identifier;
+
+/// ひとつのvmファイルを変換した結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fragment {
+    pub name: String,
+    pub asm: String,
+    /// 各VMコマンドが生成した命令の情報。asmの中と同じ順番に並ぶ
+    pub spans: Vec<Span>,
+}
+
+/// ひとつのVMコマンドを変換した結果の情報
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub command: String,
+    /// 生成した命令の数
+    pub size: usize,
+}
+
+/// 変換中のVMコマンドと、そのアセンブリコードが始まる位置（バイト数）
+struct Chunk {
+    line: usize,
+    command: String,
+    start: usize,
+}
+
+/// `CommentStyle::Verbose`のコメント`// @{address} ...`のアドレスを書き換える
+fn relocate_comment(line: &str, address: usize) -> String {
+    let rest = line["// @".len()..].trim_start_matches(|c: char| c.is_ascii_digit());
+    format!("// @{}{}", address, rest)
+}
+
+/// 最適化のレベルに合わせてアセンブリコードを仕上げ、
+/// 各VMコマンドが生成した命令の数を数える。
+/// baseはこのコードの最初の命令のアドレス
+fn finish(asm: Vec<u8>, chunks: Vec<Chunk>, options: &TranslateOptions,
+          base: usize) -> (String, Vec<Span>)
+{
+    let asm = String::from_utf8(asm).unwrap();
+
+    // 各行をどのVMコマンドが生成したか
+    let mut lines = Vec::new();
+    let mut owners = Vec::new();
+    let mut owner = None;
+    let mut offset = 0;
+    for line in asm.split_inclusive('\n') {
+        let next = owner.map_or(0, |o| o + 1);
+        if next < chunks.len() && chunks[next].start <= offset {
+            owner = Some(next);
+        }
+        lines.push(line);
+        owners.push(owner);
+        offset += line.len();
+    }
+
+    let removed = match options.opt_level {
+        OptLevel::O0 => vec![false; lines.len()],
+        OptLevel::O1 => optimizer::peephole(&lines),
+    };
+
+    // 最適化で命令が減るとアドレスがずれるので、コメントのアドレスは
+    // 最適化の後の命令の数から計算し直す
+    let verbose = options.comment_style == CommentStyle::Verbose;
+    let mut address = base;
+
+    let mut result = String::with_capacity(asm.len());
+    let mut sizes = vec![0; chunks.len()];
+    for (i, line) in lines.iter().enumerate() {
+        if removed[i] {
+            continue;
+        }
+        if verbose && line.starts_with("// @") {
+            result += &relocate_comment(line, address);
+        } else {
+            result += line;
+        }
+        if hack::is_instruction(line) {
+            address += 1;
+            if let Some(o) = owners[i] {
+                sizes[o] += 1;
+            }
+        }
+    }
+
+    let spans = chunks.into_iter().zip(sizes)
+        .map(|(c, size)| Span { line: c.line, command: c.command, size })
+        .collect();
+
+    (result, spans)
+}
+
+/// 変換単位の名前を検査する。名前はstaticセグメントのシンボル名
+/// `名前.index`に使うので、アセンブラで使える識別子でなければならず、
+/// 別のファイルと同じ名前だとstatic変数を共有してしまう
+pub fn check_names(inputs: &[Input]) -> Diagnostics {
+    let mut diagnostics = Diagnostics::new();
+    let mut names: HashMap<&str, usize> = HashMap::new();
+    for (i, input) in inputs.iter().enumerate() {
+        if let Err(e) = identifier::validate(&input.name) {
+            diagnostics.push(Diagnostic::new(&format!(
+                "ファイル名 {} はstatic変数の名前に使えません: {}", input.name, e)));
+            continue;
+        }
+
+        match names.get(input.name.as_str()) {
+            Some(first) => diagnostics.push(Diagnostic::new(&format!(
+                "{}番目と{}番目のファイルの名前がどちらも {} です。\
+                 static変数の名前が重なるので、別の名前にしてください",
+                first + 1, i + 1, input.name))),
+            None => {
+                names.insert(&input.name, i);
+            },
+        }
+    }
+    diagnostics
+}
+
+/// ブートストラップコードを返す
+pub fn bootstrap(options: &TranslateOptions) -> Result<Fragment, Diagnostics> {
+    let mut cw = CodeWriter::new(Vec::new());
+    cw.set_comment_style(options.comment_style);
+    cw.set_naming_scheme(options.naming_scheme);
+    cw.set_identifier_policy(options.identifier_policy);
+    cw.set_dialect(options.dialect);
+    cw.write_bootstrap(&options.bootstrap).map_err(|e| Diagnostic::new(&e))?;
+
+    let chunk = Chunk { line: 0, command: "bootstrap".to_string(), start: 0 };
+    let (asm, spans) = finish(cw.into_inner(), vec![chunk], options, 0);
+    Ok(Fragment { name: String::new(), asm, spans })
+}
+
+/// ひとつのvmファイルを変換する。
+/// 変換する前にパースとラベルの検査を行い、
+/// エラーがあった場合はファイル内のすべてのエラーを集めて返す
+pub fn translate_unit(input: &Input, options: &TranslateOptions)
+    -> Result<Fragment, Diagnostics>
+{
+    translate_unit_at(input, options, 0)
+}
+
+/// `translate_unit`と同じだが、最初の命令のアドレスをbaseとして変換する。
+/// アドレスは`CommentStyle::Verbose`のコメントにだけ影響する
+pub fn translate_unit_at(input: &Input, options: &TranslateOptions, base: usize)
+    -> Result<Fragment, Diagnostics>
+{
+    let file = VmFile::parse_with(input, options.identifier_policy, options.dialect)?;
+    let diagnostics = labels::check_labels(&file);
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+
+    let mut cw = CodeWriter::new(Vec::new());
+    cw.set_comment_style(options.comment_style);
+    cw.set_naming_scheme(options.naming_scheme);
+    cw.set_identifier_policy(options.identifier_policy);
+    cw.set_dialect(options.dialect);
+    cw.set_file_name(&input.name);
+    cw.set_address(base);
+
+    let mut diagnostics = Diagnostics::new();
+    let mut chunks = Vec::new();
+    let source_lines: Vec<&str> = input.source.lines().collect();
+    let mut parser = Parser::new(input.source.as_bytes());
+    while parser.has_more_commands() {
+        parser.advance();
+        if let Some(source) = source_lines.get(parser.line().wrapping_sub(1)) {
+            cw.set_source_line(parser.line(), source);
+        }
+        chunks.push(Chunk {
+            line: parser.line(),
+            command: parser.command().unwrap_or("").to_string(),
+            start: cw.get_ref().len(),
+        });
+        if let Err(e) = vm_command_to_asm(&parser, &mut cw) {
+            diagnostics.push(Diagnostic::at(&input.name, parser.line(), &e));
+        }
+    }
+
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+
+    let (asm, spans) = finish(cw.into_inner(), chunks, options, base);
+    Ok(Fragment { name: input.name.clone(), asm, spans })
+}
+
+/// 複数のvmファイルをjobs個のスレッドで変換する。
+/// 結果はinputsと同じ順番で返す
+pub fn translate_units(inputs: &[&Input], options: &TranslateOptions,
+                       jobs: usize) -> Vec<Result<Fragment, Diagnostics>>
+{
+    let jobs = jobs.max(1).min(inputs.len());
+    if jobs <= 1 {
+        return inputs.iter().map(|i| translate_unit(i, options)).collect();
+    }
+
+    // 各スレッドは次に変換するファイルの番号をnextから取り出す
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<(usize, Result<Fragment, Diagnostics>)> =
+        thread::scope(|s| {
+            let workers: Vec<_> = (0..jobs).map(|_| s.spawn(|| {
+                let mut done = Vec::new();
+                loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    if i >= inputs.len() {
+                        return done;
+                    }
+                    done.push((i, translate_unit(inputs[i], options)));
+                }
+            })).collect();
+
+            workers.into_iter().flat_map(|w| w.join().unwrap()).collect()
+        });
+
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, r)| r).collect()
+}
+
+/// ブートストラップコードと各ファイルの変換結果をつなげる。
+/// 同時に命令のアドレスを数えてソースマップを作る
+pub fn link(bootstrap: &Fragment, fragments: &[Fragment]) -> Output {
+    let len = fragments.iter().map(|f| f.asm.len()).sum::<usize>();
+    let mut asm = String::with_capacity(bootstrap.asm.len() + len);
+    let mut source_map = SourceMap::new();
+    let mut address = 0;
+
+    for fragment in Some(bootstrap).into_iter().chain(fragments) {
+        asm += &fragment.asm;
+
+        let file = if fragment.name.is_empty() {
+            None
+        } else {
+            Some(fragment.name.clone())
+        };
+        for span in &fragment.spans {
+            source_map.push(MapEntry {
+                start: address,
+                end: address + span.size,
+                file: file.clone(),
+                line: span.line,
+                command: span.command.clone(),
+            });
+            address += span.size;
+        }
+    }
+
+    let statics = StaticMap::from_asm(&asm);
+    let size = SizeReport::from_source_map(&source_map);
+    Output { asm, source_map, statics, size }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_translate_unit_independent() {
+        // 同じファイルはどの順番で変換しても同じ結果になる
+        let options = TranslateOptions::new();
+        let a = Input::new("A", "push constant 1\npush constant 1\neq\n");
+        let b = Input::new("B", "push constant 1\npush constant 1\neq\n");
+
+        let fa = translate_unit(&a, &options).unwrap();
+        let fb = translate_unit(&b, &options).unwrap();
+        assert_eq!(translate_unit(&a, &options).unwrap(), fa);
+        assert!(fa.asm.contains("symbol-ifd-A-0"));
+        assert!(fb.asm.contains("symbol-ifd-B-0"));
+    }
+
+    #[test]
+    fn test_translate_units_order() {
+        let options = TranslateOptions::new();
+        let inputs: Vec<Input> = (0..20)
+            .map(|i| Input::new(&format!("F{}", i), "push constant 1\nnot\n"))
+            .collect();
+        let refs: Vec<&Input> = inputs.iter().collect();
+
+        let serial = translate_units(&refs, &options, 1);
+        let parallel = translate_units(&refs, &options, 4);
+        assert_eq!(serial, parallel);
+        assert_eq!(parallel[7].as_ref().unwrap().name, "F7");
+    }
+
+    #[test]
+    fn test_translate_unit_spans() {
+        let input = Input::new("Main", "push constant 1\n\nlabel A\npop static 0\n");
+        for level in [OptLevel::O0, OptLevel::O1].iter() {
+            let options = TranslateOptions::new().opt_level(*level);
+            let fragment = translate_unit(&input, &options).unwrap();
+
+            let lines: Vec<usize> = fragment.spans.iter().map(|s| s.line).collect();
+            assert_eq!(lines, vec![1, 3, 4]);
+            assert_eq!(fragment.spans[1].size, 0);
+            let size: usize = fragment.spans.iter().map(|s| s.size).sum();
+            assert_eq!(size, hack::count_instructions(&fragment.asm));
+        }
+    }
+
+    #[test]
+    fn test_translate_unit_verbose() {
+        let input = Input::new("Main", "push constant 1\npop static 0 // x\n");
+        let options = TranslateOptions::new().comment_style(CommentStyle::Verbose)
+                                             .opt_level(OptLevel::O1);
+        let fragment = translate_unit_at(&input, &options, 10).unwrap();
+        let comments: Vec<&str> = fragment.asm.lines()
+                                          .filter(|l| l.starts_with("//"))
+                                          .collect();
+
+        // 最適化で取り除かれた命令の分だけアドレスがずれる
+        let first = fragment.spans[0].size;
+        assert_eq!(comments, vec![
+            "// @10 Main:1 (-) push constant 1".to_string(),
+            format!("// @{} Main:2 (-) pop static 0 // x", 10 + first),
+        ]);
+    }
+
+    #[test]
+    fn test_check_names() {
+        let inputs = vec![Input::new("a.b", ""), Input::new("Main", ""),
+                          Input::new("1st", ""), Input::new("Main", "")];
+        let d: Vec<String> = check_names(&inputs).iter()
+                                 .map(|d| d.message.clone()).collect();
+        assert_eq!(d.len(), 2);
+        assert!(d[0].starts_with("ファイル名 1st はstatic変数の名前に使えません"));
+        assert!(d[1].starts_with("2番目と4番目のファイルの名前がどちらも Main です"));
+    }
+
+    #[test]
+    fn test_link_source_map() {
+        let options = TranslateOptions::new();
+        let boot = bootstrap(&options).unwrap();
+        let main = translate_unit(&Input::new("Main", "push constant 1\nadd\n"),
+                                  &options).unwrap();
+        let output = link(&boot, &[main]);
+        let entries = output.source_map.entries();
+
+        assert_eq!(entries[0].file, None);
+        assert_eq!(entries[1].start, entries[0].end);
+        assert_eq!(entries[1].file.as_deref(), Some("Main"));
+        assert_eq!(entries[2].command, "add");
+        assert_eq!(entries[2].end, output.instruction_count());
+    }
+}
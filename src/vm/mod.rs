@@ -0,0 +1,548 @@
+//! VMコードのインタープリター。
+//! 変換せずにVMコマンドをそのまま実行し、メモリの配置はHackへ変換した場合と
+//! 同じにする（SPはRAM[0]、LCLはRAM[1]…、tempはRAM[5]から、staticはRAM[16]から）。
+//! デバッガーのためにコマンドごとに実行でき、関数の呼び出しを記録する
+//!
+//! ブートストラップはHackへ変換した場合と同じで、SPを256にしてSys.initを呼ぶ。
+//! Sys.initがない場合は、最初のコマンドからSPを256にして実行する
+
+use std::collections::HashMap;
+
+use crate::program::{Program, Command, Segment, Op};
+
+/// RAMの大きさ
+const RAM_SIZE: usize = 32768;
+/// スタックのベースアドレス
+const STACK_BASE: i16 = 256;
+/// static変数のベースアドレス
+const STATIC_BASE: usize = 16;
+
+const SP: usize = 0;
+const LCL: usize = 1;
+const ARG: usize = 2;
+const THIS: usize = 3;
+const THAT: usize = 4;
+
+/// 実行するひとつのコマンドと、その場所
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub command: Command,
+}
+
+/// 呼び出し中の関数
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub function: String,
+    /// 引数の数
+    pub args: u16,
+    /// localの数。functionコマンドを実行するまでは0
+    pub locals: u16,
+    /// callコマンドの番号。ブートストラップから呼ばれた場合は`None`
+    pub call_site: Option<usize>,
+}
+
+/// 実行の状態
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Running,
+    /// 最後のコマンドを実行したか、自分自身へのgotoで止まった
+    Halted,
+}
+
+pub struct Vm {
+    code: Vec<Location>,
+    /// 関数名とfunctionコマンドの番号
+    functions: HashMap<String, usize>,
+    /// (関数名, ラベル)とlabelコマンドの番号
+    labels: HashMap<(String, String), usize>,
+    /// 各コマンドの関数名。最初のfunctionコマンドより前はファイル名
+    scopes: Vec<String>,
+    /// (ファイル名, index)とstatic変数のアドレス
+    statics: HashMap<(String, u16), usize>,
+    pub ram: Vec<i16>,
+    pub pc: usize,
+    pub frames: Vec<Frame>,
+    state: State,
+}
+
+impl Vm {
+    /// プログラムを読み込み、ブートストラップを実行した状態にする
+    pub fn new(program: &Program) -> Vm {
+        let mut code = Vec::new();
+        let mut functions = HashMap::new();
+        let mut labels = HashMap::new();
+        let mut scopes = Vec::new();
+        let mut statics = HashMap::new();
+
+        for file in &program.files {
+            let mut scope = file.name.clone();
+            for s in &file.statements {
+                match &s.command {
+                    Command::Function(f, _) => {
+                        scope = f.clone();
+                        functions.insert(f.clone(), code.len());
+                    },
+                    Command::Label(l) => {
+                        labels.insert((scope.clone(), l.clone()), code.len());
+                    },
+                    Command::Push(Segment::Static, i) | Command::Pop(Segment::Static, i) => {
+                        // アセンブラと同じように、最初に使われた順に割り当てる
+                        let n = statics.len();
+                        statics.entry((file.name.clone(), *i)).or_insert(STATIC_BASE + n);
+                    },
+                    _ => {},
+                }
+                scopes.push(scope.clone());
+                code.push(Location {
+                    file: file.name.clone(),
+                    line: s.line,
+                    command: s.command.clone(),
+                });
+            }
+        }
+
+        let mut vm = Vm {
+            code,
+            functions,
+            labels,
+            scopes,
+            statics,
+            ram: vec![0; RAM_SIZE],
+            pc: 0,
+            frames: Vec::new(),
+            state: State::Running,
+        };
+        vm.ram[SP] = STACK_BASE;
+        if vm.functions.contains_key("Sys.init") {
+            let _ = vm.call("Sys.init", 0, None);
+        }
+        if vm.code.is_empty() {
+            vm.state = State::Halted;
+        }
+        vm
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// 次に実行するコマンド。停止している場合は`None`
+    pub fn current(&self) -> Option<&Location> {
+        match self.state {
+            State::Running => self.code.get(self.pc),
+            State::Halted => None,
+        }
+    }
+
+    pub fn code(&self) -> &[Location] {
+        &self.code
+    }
+
+    /// 関数のfunctionコマンドの番号
+    pub fn function(&self, name: &str) -> Option<usize> {
+        self.functions.get(name).copied()
+    }
+
+    /// ファイルのline行目以降で最初のコマンドの番号
+    pub fn find_line(&self, file: &str, line: usize) -> Option<usize> {
+        self.code.iter().enumerate()
+            .filter(|(_, l)| l.file == file && l.line >= line)
+            .min_by_key(|(_, l)| l.line)
+            .map(|(i, _)| i)
+    }
+
+    /// static変数のアドレス。使われていない場合は`None`
+    pub fn static_address(&self, file: &str, index: u16) -> Option<usize> {
+        self.statics.get(&(file.to_string(), index)).copied()
+    }
+
+    /// ファイルのstatic変数のindexとアドレス。indexの順に並べる
+    pub fn statics(&self, file: &str) -> Vec<(u16, usize)> {
+        let mut statics: Vec<(u16, usize)> = self.statics.iter()
+            .filter(|((f, _), _)| f == file)
+            .map(|((_, i), a)| (*i, *a))
+            .collect();
+        statics.sort();
+        statics
+    }
+
+    fn address(&self, register: usize) -> usize {
+        self.ram[register] as u16 as usize
+    }
+
+    /// 計算したアドレスがRAMの範囲にあるか調べる
+    fn checked(address: Option<usize>) -> Result<usize, String> {
+        match address {
+            Some(a) if a < RAM_SIZE => Ok(a),
+            _ => Err("RAMの範囲外のアドレスを使いました".to_string()),
+        }
+    }
+
+    fn push(&mut self, value: i16) -> Result<(), String> {
+        let sp = self.address(SP);
+        if sp >= RAM_SIZE {
+            return Err("スタックがあふれました".to_string());
+        }
+        self.ram[sp] = value;
+        self.ram[SP] = self.ram[SP].wrapping_add(1);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i16, String> {
+        if self.ram[SP] <= 0 {
+            return Err("スタックが空です".to_string());
+        }
+        self.ram[SP] -= 1;
+        Ok(self.ram[self.address(SP)])
+    }
+
+    /// セグメントのアドレス。constantとindirectの場合は`None`
+    fn segment_address(&self, segment: Segment, index: u16) -> Option<usize> {
+        let index = index as usize;
+        let address = match segment {
+            Segment::Constant | Segment::Indirect => return None,
+            Segment::Local => self.address(LCL) + index,
+            Segment::Argument => self.address(ARG) + index,
+            Segment::This => self.address(THIS) + index,
+            Segment::That => self.address(THAT) + index,
+            Segment::Temp => 5 + index,
+            Segment::Pointer => THIS + index,
+            Segment::Static => {
+                let file = &self.code.get(self.pc)?.file;
+                return self.static_address(file, index as u16);
+            },
+        };
+        Some(address % RAM_SIZE)
+    }
+
+    fn call(&mut self, function: &str, args: u16, call_site: Option<usize>)
+        -> Result<(), String>
+    {
+        let target = match self.functions.get(function) {
+            Some(t) => *t,
+            None => return Err(format!("関数 {} は定義されていません", function)),
+        };
+        // Hackへ変換した場合と同じ配置にするためにRAMにも書くが、
+        // 16ビットに収まらないことがあるので、戻り先はFrameのcall_siteを使う
+        let return_address = match call_site {
+            Some(pc) => (pc + 1) as i16,
+            None => -1,
+        };
+        self.push(return_address)?;
+        for register in [LCL, ARG, THIS, THAT] {
+            self.push(self.ram[register])?;
+        }
+        self.ram[ARG] = self.ram[SP] - 5 - args as i16;
+        self.ram[LCL] = self.ram[SP];
+        self.frames.push(Frame {
+            function: function.to_string(),
+            args,
+            locals: 0,
+            call_site,
+        });
+        self.pc = target;
+        Ok(())
+    }
+
+    /// labelコマンドの番号
+    fn label(&self, label: &str) -> Result<usize, String> {
+        let key = (self.scopes[self.pc].clone(), label.to_string());
+        match self.labels.get(&key) {
+            Some(target) => Ok(*target),
+            None => Err(format!("label {} は定義されていません", label)),
+        }
+    }
+
+    /// コマンドをひとつ実行する
+    pub fn step(&mut self) -> Result<State, String> {
+        if self.state == State::Halted {
+            return Ok(State::Halted);
+        }
+        let command = self.code[self.pc].command.clone();
+        let mut next = self.pc + 1;
+
+        match command {
+            Command::Arithmetic(op) => {
+                let y = self.pop()?;
+                let value = match op {
+                    Op::Neg => y.wrapping_neg(),
+                    Op::Not => !y,
+                    Op::Dup => {
+                        self.push(y)?;
+                        y
+                    },
+                    _ => {
+                        let x = self.pop()?;
+                        match op {
+                            Op::Add => x.wrapping_add(y),
+                            Op::Sub => x.wrapping_sub(y),
+                            Op::And => x & y,
+                            Op::Or => x | y,
+                            Op::Eq => -((x == y) as i16),
+                            Op::Gt => -((x > y) as i16),
+                            Op::Lt => -((x < y) as i16),
+                            Op::Ge => -((x >= y) as i16),
+                            Op::Le => -((x <= y) as i16),
+                            Op::Ne => -((x != y) as i16),
+                            Op::Mul => x.wrapping_mul(y),
+                            Op::Div if y == 0 => 0,
+                            Op::Div => x.wrapping_div(y),
+                            Op::Shl if y <= 0 => x,
+                            Op::Shl => x.checked_shl(y as u32).unwrap_or(0),
+                            Op::Shr if y <= 0 => x,
+                            Op::Shr => x >> y.min(15),
+                            Op::Swap => {
+                                self.push(y)?;
+                                x
+                            },
+                            Op::Neg | Op::Not | Op::Dup => unreachable!(),
+                        }
+                    },
+                };
+                self.push(value)?;
+            },
+            Command::Push(Segment::Indirect, index) => {
+                let address = self.pop()?.wrapping_add(index as i16);
+                let value = self.ram[address as u16 as usize % RAM_SIZE];
+                self.push(value)?;
+            },
+            Command::Pop(Segment::Indirect, index) => {
+                let value = self.pop()?;
+                let address = self.pop()?.wrapping_add(index as i16);
+                self.ram[address as u16 as usize % RAM_SIZE] = value;
+            },
+            Command::Push(segment, index) => {
+                let value = match self.segment_address(segment, index) {
+                    Some(a) => self.ram[a],
+                    None => index as i16,
+                };
+                self.push(value)?;
+            },
+            Command::Pop(segment, index) => {
+                let value = self.pop()?;
+                if let Some(a) = self.segment_address(segment, index) {
+                    self.ram[a] = value;
+                }
+            },
+            Command::Label(_) => {},
+            Command::Goto(label) => {
+                next = self.label(&label)?;
+                if next + 1 == self.pc || next == self.pc {
+                    // 自分自身へのループ（Sys.haltなど）
+                    self.state = State::Halted;
+                    self.pc = next;
+                }
+            },
+            Command::IfGoto(label) => {
+                if self.pop()? != 0 {
+                    next = self.label(&label)?;
+                }
+            },
+            Command::Function(_, locals) => {
+                for _ in 0..locals {
+                    self.push(0)?;
+                }
+                if let Some(frame) = self.frames.last_mut() {
+                    frame.locals = locals;
+                }
+            },
+            Command::Call(function, args) => {
+                self.call(&function, args, Some(self.pc))?;
+                next = self.pc;
+            },
+            Command::Return => {
+                let frame = self.address(LCL);
+                let arg = Vm::checked(Some(self.address(ARG)))?;
+                let value = self.pop()?;
+                self.ram[arg] = value;
+                self.ram[SP] = self.ram[ARG].wrapping_add(1);
+                for (i, register) in [THAT, THIS, ARG, LCL].iter().enumerate() {
+                    let address = Vm::checked(frame.checked_sub(i + 1))?;
+                    self.ram[*register] = self.ram[address];
+                }
+                match self.frames.pop().and_then(|f| f.call_site) {
+                    Some(pc) => next = pc + 1,
+                    None => {
+                        // ブートストラップに戻ったら停止する
+                        self.state = State::Halted;
+                        return Ok(self.state);
+                    },
+                }
+            },
+        }
+
+        if self.state == State::Running {
+            self.pc = next;
+        }
+        if self.pc >= self.code.len() {
+            self.state = State::Halted;
+        }
+        Ok(self.state)
+    }
+
+    /// 今の関数のセグメントの値。constantは空。
+    /// countはRAMの大きさまでにする
+    pub fn segment(&self, segment: Segment, count: usize) -> Vec<i16> {
+        (0..count.min(RAM_SIZE) as u16).filter_map(|i| self.segment_address(segment, i))
+                         .map(|a| self.ram[a])
+                         .collect()
+    }
+
+    /// 今の関数のスタックの値。関数の外の場合はRAM[256]から
+    pub fn stack(&self) -> &[i16] {
+        let base = match self.frames.last() {
+            Some(f) => self.address(LCL) + f.locals as usize,
+            None => STACK_BASE as usize,
+        };
+        let sp = self.address(SP).min(RAM_SIZE);
+        if base <= sp { &self.ram[base..sp] } else { &[] }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Input;
+
+    fn run(sources: &[(&str, &str)]) -> Vm {
+        let inputs: Vec<Input> = sources.iter().map(|(n, s)| Input::new(n, s)).collect();
+        let mut vm = Vm::new(&Program::parse(&inputs).unwrap());
+        for _ in 0..10000 {
+            if vm.step().unwrap() == State::Halted {
+                break;
+            }
+        }
+        vm
+    }
+
+    #[test]
+    fn test_vm_arithmetic() {
+        let vm = run(&[("Main", concat!(
+            "push constant 7\n", "push constant 8\n", "add\n",
+            "push constant 3\n", "sub\n", "push constant 12\n", "eq\n",
+            "push constant 5\n", "neg\n",
+        ))]);
+        assert_eq!(vm.ram[SP], 258);
+        assert_eq!(&vm.ram[256..258], &[-1, -5]);
+    }
+
+    #[test]
+    fn test_vm_call() {
+        let vm = run(&[
+            ("Sys", concat!(
+                "function Sys.init 0\n",
+                "push constant 3\n",
+                "call Main.fact 1\n",
+                "pop static 0\n",
+                "label END\n",
+                "goto END\n",
+            )),
+            ("Main", concat!(
+                "function Main.fact 1\n",
+                "push argument 0\n",
+                "pop local 0\n",
+                "push local 0\n",
+                "push constant 1\n",
+                "gt\n",
+                "if-goto REC\n",
+                "push constant 1\n",
+                "return\n",
+                "label REC\n",
+                "push local 0\n",
+                "push constant 1\n",
+                "sub\n",
+                "call Main.fact 1\n",
+                "push local 0\n",
+                "call Main.mul 2\n",
+                "return\n",
+                "function Main.mul 0\n",
+                "push argument 0\n",
+                "push argument 1\n",
+                "pop temp 0\n",
+                "pop temp 1\n",
+                "push constant 0\n",
+                "label LOOP\n",
+                "push temp 0\n",
+                "push constant 0\n",
+                "eq\n",
+                "if-goto DONE\n",
+                "push temp 1\n",
+                "add\n",
+                "push temp 0\n",
+                "push constant 1\n",
+                "sub\n",
+                "pop temp 0\n",
+                "goto LOOP\n",
+                "label DONE\n",
+                "return\n",
+            )),
+        ]);
+        assert_eq!(vm.state(), State::Halted);
+        assert_eq!(vm.ram[vm.static_address("Sys", 0).unwrap()], 6);
+        assert_eq!(vm.frames.len(), 1);
+        assert_eq!(vm.frames[0].function, "Sys.init");
+    }
+
+    #[test]
+    fn test_vm_return_far() {
+        // callコマンドの番号が32767を超えても呼び出し元へ戻る
+        let padding = "push constant 0\npop temp 1\n".repeat(17000);
+        let inputs = [Input::new("Sys", &format!(concat!(
+            "function Sys.init 0\n{}",
+            "call Sys.seven 0\n",
+            "pop temp 0\n",
+            "label END\n",
+            "goto END\n",
+            "function Sys.seven 0\n",
+            "push constant 7\n",
+            "return\n",
+        ), padding))];
+        let mut vm = Vm::new(&Program::parse(&inputs).unwrap());
+        while vm.step().unwrap() == State::Running {}
+        assert_eq!(vm.ram[5], 7);
+        assert_eq!(vm.frames.len(), 1);
+    }
+
+    #[test]
+    fn test_vm_if_goto_loop() {
+        // if-gotoのループは自分自身へ戻っても条件が成り立つ間は続ける
+        let vm = run(&[("Main", concat!(
+            "push constant 5\n",
+            "pop temp 0\n",
+            "label L\n",
+            "push temp 0\n",
+            "push constant 1\n",
+            "sub\n",
+            "pop temp 0\n",
+            "push temp 0\n",
+            "if-goto L\n",
+            "label STOP\n",
+            "goto STOP\n",
+        ))]);
+        assert_eq!(vm.state(), State::Halted);
+        assert_eq!(vm.ram[5], 0);
+        assert_eq!(vm.ram[SP], 256);
+    }
+
+    #[test]
+    fn test_vm_return_out_of_range() {
+        // ARGが負の値の場合はパニックせずにエラーにする
+        let inputs = [Input::new("Main", concat!(
+            "push constant 1\n",
+            "neg\n",
+            "pop local 2\n",
+            "push constant 1\n",
+            "return\n",
+        ))];
+        let mut vm = Vm::new(&Program::parse(&inputs).unwrap());
+        let mut result = Ok(State::Running);
+        while result == Ok(State::Running) {
+            result = vm.step();
+        }
+        assert!(result.is_err());
+        assert_eq!(vm.stack(), &[1]);
+    }
+}
@@ -0,0 +1,149 @@
+//! vmファイルの変更を監視するモジュール。
+//! ファイルの更新時刻とサイズを定期的に調べる（ポーリング）ことで変更を検出する。
+//! .vmbファイルは`load_inputs`と同じようにバイトコードとして読み込む
+
+use std::fs;
+use std::time::SystemTime;
+
+use crate::{get_f_list, load_file, Diagnostic, Input};
+
+/// 監視しているひとつのファイル
+struct Entry {
+    path: String,
+    modified: Option<SystemTime>,
+    len: u64,
+    /// .vmbファイルには複数のファイルが含まれる
+    inputs: Result<Vec<Input>, Diagnostic>,
+}
+
+/// vm_pathにあるvmファイルを監視する。
+/// 変更があったファイルだけを読み直す
+pub struct Watcher {
+    vm_path: String,
+    entries: Vec<Entry>,
+    error: Option<String>, // get_f_listが返したエラー
+}
+
+/// ファイルの更新時刻とサイズを返す
+fn stamp(path: &str) -> (Option<SystemTime>, u64) {
+    match fs::metadata(path) {
+        Ok(m) => (m.modified().ok(), m.len()),
+        Err(_) => (None, 0),
+    }
+}
+
+impl Watcher {
+    /// 最初の`poll()`ですべてのファイルを読み込む
+    pub fn new(vm_path: &str) -> Watcher {
+        Watcher {
+            vm_path: vm_path.to_string(),
+            entries: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// ファイルの変更を調べ、変更があったファイルを読み直す。
+    /// ファイルの追加、削除、変更があった場合は`true`を返す。
+    /// 最初の呼び出しでは必ず`true`を返す
+    pub fn poll(&mut self) -> bool {
+        let first = self.entries.is_empty() && self.error.is_none();
+
+        let f_list = match get_f_list(&self.vm_path) {
+            Ok(f_list) => f_list,
+            Err(e) => {
+                let changed = self.error.as_ref() != Some(&e);
+                self.entries.clear();
+                self.error = Some(e);
+                return changed;
+            }
+        };
+        let mut changed = first || self.error.take().is_some()
+                          || f_list.len() != self.entries.len();
+
+        let mut entries = Vec::new();
+        for path in f_list {
+            let (modified, len) = stamp(&path);
+            let old = self.entries.iter()
+                          .position(|e| e.path == path)
+                          .map(|i| self.entries.swap_remove(i));
+
+            match old {
+                Some(e) if e.modified == modified && e.len == len => {
+                    entries.push(e);
+                },
+                _ => {
+                    changed = true;
+                    let inputs = load_file(&path);
+                    entries.push(Entry { path, modified, len, inputs });
+                }
+            }
+        }
+        self.entries = entries;
+
+        changed
+    }
+
+    /// 読み込んだファイルのリストを返す。
+    /// 読み込めなかったファイルやvmファイルが見つからない場合はエラーを返す
+    pub fn inputs(&self) -> Result<Vec<Input>, Diagnostic> {
+        if let Some(e) = &self.error {
+            return Err(Diagnostic::new(e));
+        }
+
+        let mut inputs = Vec::new();
+        for e in &self.entries {
+            inputs.extend(e.inputs.clone()?);
+        }
+        Ok(inputs)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::Watcher;
+    use crate::{bytecode, Input};
+    use crate::program::Program;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_watcher_poll() {
+        let dir = env::temp_dir().join(format!("vmtranslator-watch-{}",
+                                               std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("Main.vm");
+        fs::write(&path, "push constant 1\n").unwrap();
+
+        let mut watcher = Watcher::new(dir.to_str().unwrap());
+        assert!(watcher.poll());
+        assert!(!watcher.poll());
+        assert_eq!(watcher.inputs().unwrap()[0].source, "push constant 1\n");
+
+        // サイズを変えて更新時刻の精度に依存しないようにする
+        fs::write(&path, "push constant 10\n").unwrap();
+        assert!(watcher.poll());
+        assert_eq!(watcher.inputs().unwrap()[0].source, "push constant 10\n");
+
+        fs::write(dir.join("Sub.vm"), "add\n").unwrap();
+        assert!(watcher.poll());
+        assert_eq!(watcher.inputs().unwrap().len(), 2);
+
+        // バイトコードは含まれるファイルに戻して読み込む
+        let program = Program::parse(&[Input::new("A", "add\n"), Input::new("B", "sub\n")])
+            .unwrap();
+        let vmb = dir.join("Lib.vmb");
+        fs::write(&vmb, bytecode::encode(&program)).unwrap();
+        let mut vmb_watcher = Watcher::new(vmb.to_str().unwrap());
+        assert!(vmb_watcher.poll());
+        let names: Vec<String> = vmb_watcher.inputs().unwrap().into_iter()
+            .map(|i| i.name).collect();
+        assert_eq!(names, vec!["A", "B"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(watcher.poll());
+        assert!(watcher.inputs().is_err());
+        assert!(!watcher.poll());
+    }
+}